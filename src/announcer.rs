@@ -0,0 +1,309 @@
+use std::sync::{atomic::Ordering, Arc};
+use std::time::Duration;
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+use tokio::task::{self, JoinHandle, JoinSet};
+use tokio::time::{self, Instant};
+
+use crate::manager::TransferStats;
+use crate::tracker::{AnnounceRequest, Event, Tracker, TrackerError, TrackerPeer, TrackerResponse};
+
+/// wait before retrying a tier none of whose trackers responded, doubled after every failure
+const RETRY_INTERVAL: Duration = Duration::from_secs(15);
+/// upper bound for the retry backoff
+const MAX_RETRY_INTERVAL: Duration = Duration::from_secs(30 * 60);
+/// a stopped announce shouldn't keep the client from shutting down
+const STOPPED_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A tier of the announce-list, see BEP 12
+#[derive(Debug)]
+struct Tier {
+    /// the tracker which responded last is kept at the front
+    trackers: Vec<Tracker>,
+    /// event which still has to be delivered to the tier
+    pending_event: Option<Event>,
+    next_announce: Instant,
+    /// number of announces in a row which failed
+    failures: u32,
+}
+
+impl Tier {
+    fn new(trackers: Vec<Tracker>) -> Self {
+        Self {
+            trackers,
+            pending_event: Some(Event::Started),
+            next_announce: Instant::now(),
+            failures: 0,
+        }
+    }
+
+    /// Exponential backoff based on the number of failed announces
+    fn retry_interval(&self) -> Duration {
+        let backoff = RETRY_INTERVAL * 2u32.saturating_pow(self.failures.min(16));
+        backoff.min(MAX_RETRY_INTERVAL)
+    }
+
+    /// Try the trackers in order until one of them responds and promote it to the front of the tier
+    async fn announce(
+        &mut self,
+        request: &AnnounceRequest,
+    ) -> Result<TrackerResponse, TrackerError> {
+        let mut last_error = None;
+        for index in 0..self.trackers.len() {
+            match self.trackers[index].announce(request).await {
+                Ok(res) => {
+                    let tracker = self.trackers.remove(index);
+                    self.trackers.insert(0, tracker);
+                    return Ok(res);
+                }
+                Err(e) => {
+                    eprintln!(
+                        "Announce to {} failed: {}",
+                        self.trackers[index].announce_url, e
+                    );
+                    last_error = Some(e);
+                }
+            }
+        }
+        Err(last_error.unwrap_or_else(|| TrackerError::Connection("Tier has no trackers".into())))
+    }
+}
+
+/// Periodically announces to every tier of trackers and passes the returned peers on
+pub struct Announcer {
+    tiers: Vec<Tier>,
+    info_hash: Vec<u8>,
+    peer_id: Vec<u8>,
+    port: u16,
+    stats: Arc<TransferStats>,
+    send_peers: UnboundedSender<Vec<TrackerPeer>>,
+    /// `Completed` and `Stopped` events which have to be reported to the trackers
+    receive_events: UnboundedReceiver<Event>,
+}
+
+impl Announcer {
+    /// `tiers` should already be shuffled
+    pub fn new(
+        tiers: Vec<Vec<Tracker>>,
+        info_hash: Vec<u8>,
+        peer_id: Vec<u8>,
+        port: u16,
+        stats: Arc<TransferStats>,
+        send_peers: UnboundedSender<Vec<TrackerPeer>>,
+        receive_events: UnboundedReceiver<Event>,
+    ) -> Self {
+        Self {
+            tiers: tiers.into_iter().map(Tier::new).collect(),
+            info_hash,
+            peer_id,
+            port,
+            stats,
+            send_peers,
+            receive_events,
+        }
+    }
+
+    fn announce_request(&self, event: Option<Event>) -> AnnounceRequest {
+        AnnounceRequest {
+            info_hash: self.info_hash.clone(),
+            peer_id: self.peer_id.clone(),
+            port: self.port,
+            uploaded: self.stats.uploaded.load(Ordering::Relaxed),
+            downloaded: self.stats.downloaded.load(Ordering::Relaxed),
+            left: self.stats.left.load(Ordering::Relaxed),
+            event,
+        }
+    }
+
+    /// Announce to every tier which is due. The tiers are announced to at the same time,
+    /// so that an unresponsive tracker doesn't hold up the others.
+    async fn announce_to_tiers(&mut self) {
+        let mut tiers: Vec<Option<Tier>> = self.tiers.drain(..).map(Some).collect();
+        let mut announces = JoinSet::new();
+        for (index, slot) in tiers.iter_mut().enumerate() {
+            if slot.as_ref().unwrap().next_announce > Instant::now() {
+                continue;
+            }
+            let mut tier = slot.take().unwrap();
+            let request = self.announce_request(tier.pending_event);
+            announces.spawn(async move {
+                let res = tier.announce(&request).await;
+                (index, tier, res)
+            });
+        }
+        while let Some(announced) = announces.join_next().await {
+            let (index, mut tier, res) = match announced {
+                Ok(announced) => announced,
+                Err(e) => {
+                    eprintln!("Announce task failed: {}", e);
+                    continue;
+                }
+            };
+            match res {
+                Ok(res) => {
+                    tier.pending_event = None;
+                    tier.failures = 0;
+                    if let Some(warning) = &res.warning_message {
+                        eprintln!("Tracker warning: {}", warning);
+                    }
+                    // never announce more often than the tracker allows
+                    let interval = res.interval.max(res.min_interval.unwrap_or(0));
+                    tier.next_announce =
+                        Instant::now() + Duration::from_secs(interval.max(1) as u64);
+                    // peers from all the tiers end up in the same connection pipeline
+                    if self.send_peers.send(res.peers).is_err() {
+                        eprintln!("Receiver Dropped");
+                    }
+                }
+                Err(_) => {
+                    tier.next_announce = Instant::now() + tier.retry_interval();
+                    tier.failures += 1;
+                }
+            }
+            tiers[index] = Some(tier);
+        }
+        self.tiers = tiers.into_iter().flatten().collect();
+    }
+
+    pub fn listen_for_events(mut self) -> JoinHandle<()> {
+        task::spawn(async move {
+            loop {
+                self.announce_to_tiers().await;
+
+                let next_announce = self
+                    .tiers
+                    .iter()
+                    .map(|tier| tier.next_announce)
+                    .min()
+                    .unwrap_or_else(|| Instant::now() + RETRY_INTERVAL);
+                tokio::select! {
+                    _ = time::sleep_until(next_announce) => {}
+                    event = self.receive_events.recv() => match event {
+                        Some(Event::Stopped) | None => break,
+                        Some(event) => {
+                            for tier in self.tiers.iter_mut() {
+                                tier.pending_event = Some(event);
+                                tier.next_announce = Instant::now();
+                            }
+                        }
+                    },
+                }
+            }
+
+            // only the tiers which know about us have to be told that we are leaving
+            let request = self.announce_request(Some(Event::Stopped));
+            let mut announces = JoinSet::new();
+            for mut tier in self.tiers.drain(..) {
+                if tier.pending_event == Some(Event::Started) {
+                    continue;
+                }
+                let request = request.clone();
+                announces.spawn(async move {
+                    match time::timeout(STOPPED_TIMEOUT, tier.announce(&request)).await {
+                        Ok(Err(e)) => eprintln!("Stopped announce failed: {}", e),
+                        Err(_) => eprintln!("Stopped announce timed out"),
+                        Ok(Ok(_)) => {}
+                    }
+                });
+            }
+            while announces.join_next().await.is_some() {}
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Result;
+    use std::sync::atomic::AtomicU64;
+    use tokio::net::UdpSocket;
+    use tokio::sync::mpsc;
+
+    /// Answer `count` connect/announce requests and return the announced events and `left` values
+    async fn fake_tracker(server: UdpSocket, count: usize) -> Result<Vec<(u32, u64)>> {
+        let mut announces = vec![];
+        let mut buf = [0; 2048];
+        while announces.len() < count {
+            let (_, addr) = server.recv_from(&mut buf).await?;
+            let action = u32::from_be_bytes([buf[8], buf[9], buf[10], buf[11]]);
+            let mut response = action.to_be_bytes().to_vec();
+            response.extend_from_slice(&buf[12..16]);
+            if action == 0 {
+                response.extend_from_slice(&1u64.to_be_bytes());
+            } else {
+                let left = u64::from_be_bytes([
+                    buf[64], buf[65], buf[66], buf[67], buf[68], buf[69], buf[70], buf[71],
+                ]);
+                let event = u32::from_be_bytes([buf[80], buf[81], buf[82], buf[83]]);
+                announces.push((event, left));
+                // interval of 30 minutes, no leechers, seeders or peers
+                response.extend_from_slice(&[0, 0, 7, 8, 0, 0, 0, 0, 0, 0, 0, 0]);
+            }
+            server.send_to(&response, addr).await?;
+        }
+        Ok(announces)
+    }
+
+    #[tokio::test]
+    async fn test_lifecycle_events() -> Result<()> {
+        let server = UdpSocket::bind("127.0.0.1:0").await?;
+        let tracker = Tracker::new(format!("udp://{}/announce", server.local_addr()?));
+        let server_task = tokio::spawn(fake_tracker(server, 3));
+
+        let stats = Arc::new(TransferStats {
+            uploaded: AtomicU64::new(0),
+            downloaded: AtomicU64::new(0),
+            left: AtomicU64::new(100),
+        });
+        let (send_peers, mut receive_peers) = mpsc::unbounded_channel();
+        let (send_events, receive_events) = mpsc::unbounded_channel();
+        let announcer = Announcer::new(
+            vec![vec![tracker]],
+            vec![1; 20],
+            vec![2; 20],
+            6881,
+            stats.clone(),
+            send_peers,
+            receive_events,
+        );
+        let handle = announcer.listen_for_events();
+
+        // wait for the started announce before completing the download
+        receive_peers.recv().await;
+        stats.left.store(0, Ordering::Relaxed);
+        send_events.send(Event::Completed)?;
+        receive_peers.recv().await;
+        send_events.send(Event::Stopped)?;
+        handle.await?;
+
+        assert_eq!(server_task.await??, vec![(2, 100), (1, 0), (3, 0)]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_tier_fallback() -> Result<()> {
+        let server = UdpSocket::bind("127.0.0.1:0").await?;
+        let working = format!("udp://{}/announce", server.local_addr()?);
+        let server_task = tokio::spawn(fake_tracker(server, 2));
+
+        let mut tier = Tier::new(vec![
+            Tracker::new("wss://tracker.example/announce".to_string()),
+            Tracker::new(working.clone()),
+        ]);
+        let request = AnnounceRequest {
+            info_hash: vec![1; 20],
+            peer_id: vec![2; 20],
+            port: 6881,
+            uploaded: 0,
+            downloaded: 0,
+            left: 100,
+            event: Some(Event::Started),
+        };
+        tier.announce(&request).await?;
+        // the working tracker is promoted and tried first from now on
+        assert_eq!(tier.trackers[0].announce_url, working);
+        tier.announce(&request).await?;
+
+        server_task.await??;
+        Ok(())
+    }
+}
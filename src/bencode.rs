@@ -0,0 +1,97 @@
+use std::ops::Range;
+
+use crate::Result;
+
+/// Return the index just past the end of the bencoded value starting at `start`
+pub fn value_end(buf: &[u8], start: usize) -> Result<usize> {
+    // number of lists/dicts which are still open
+    let mut depth = 0usize;
+    let mut pos = start;
+    loop {
+        match buf.get(pos) {
+            // integer: i<number>e
+            Some(b'i') => {
+                let end = find(buf, pos + 1, b'e')?;
+                pos = end + 1;
+            }
+            // list or dictionary, the values are handled by the following iterations
+            Some(b'l') | Some(b'd') => {
+                depth += 1;
+                pos += 1;
+                continue;
+            }
+            Some(b'e') if depth > 0 => {
+                depth -= 1;
+                pos += 1;
+            }
+            // byte string: <length>:<contents>
+            Some(b'0'..=b'9') => {
+                let colon = find(buf, pos, b':')?;
+                let length = std::str::from_utf8(&buf[pos..colon])?.parse::<usize>()?;
+                pos = colon
+                    .checked_add(1 + length)
+                    .filter(|end| *end <= buf.len())
+                    .ok_or("Bencoded string is truncated")?;
+            }
+            Some(_) => Err("Invalid bencode")?,
+            None => Err("Bencoded value is truncated")?,
+        }
+        if depth == 0 {
+            return Ok(pos);
+        }
+    }
+}
+
+/// Byte span of the value stored under `key` in the dictionary starting at the beginning of `buf`
+pub fn dict_value_span(buf: &[u8], key: &[u8]) -> Result<Option<Range<usize>>> {
+    if buf.first() != Some(&b'd') {
+        Err("Bencoded value is not a dictionary")?;
+    }
+    let mut pos = 1;
+    while buf.get(pos) != Some(&b'e') {
+        if !buf.get(pos).is_some_and(u8::is_ascii_digit) {
+            Err("Dictionary key is not a string")?;
+        }
+        let key_end = value_end(buf, pos)?;
+        let colon = find(buf, pos, b':')?;
+        let end = value_end(buf, key_end)?;
+        if &buf[colon + 1..key_end] == key {
+            return Ok(Some(key_end..end));
+        }
+        pos = end;
+    }
+    Ok(None)
+}
+
+fn find(buf: &[u8], from: usize, byte: u8) -> Result<usize> {
+    let offset = buf
+        .get(from..)
+        .and_then(|rest| rest.iter().position(|b| *b == byte))
+        .ok_or("Bencoded value is truncated")?;
+    Ok(from + offset)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_value_end() -> Result<()> {
+        assert_eq!(value_end(b"i42e", 0)?, 4);
+        assert_eq!(value_end(b"4:spamxx", 0)?, 6);
+        assert_eq!(value_end(b"l4:spami3ee", 0)?, 11);
+        assert_eq!(value_end(b"d3:keyld1:ai1eeee", 0)?, 17);
+        assert!(value_end(b"d3:key", 0).is_err());
+        assert!(value_end(b"9:spam", 0).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_dict_value_span() -> Result<()> {
+        let buf = b"d1:ai1e4:infod4:name1:xe1:zlee";
+        let span = dict_value_span(buf, b"info")?.unwrap();
+        assert_eq!(&buf[span], b"d4:name1:xe");
+        assert_eq!(dict_value_span(buf, b"missing")?, None);
+        Ok(())
+    }
+}
@@ -0,0 +1,241 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+use tokio::task::JoinHandle;
+use tokio::time;
+
+use crate::disk::HavePieces;
+use crate::peer::ChokeState;
+use crate::utils;
+
+/// how often the peers are ranked again
+const CHOKE_INTERVAL: Duration = Duration::from_secs(10);
+/// the optimistic unchoke moves on every third round, i.e. every 30 seconds
+const OPTIMISTIC_ROUNDS: u32 = 3;
+/// peers unchoked for their rate, the optimistic unchoke comes on top
+const UPLOAD_SLOTS: usize = 4;
+
+/// Counters of a connection the choker ranks it by
+#[derive(Debug, Default)]
+pub struct PeerStats {
+    /// bytes of blocks received from the peer
+    pub downloaded: AtomicU64,
+    /// bytes of blocks sent to the peer
+    pub uploaded: AtomicU64,
+    pub interested: AtomicBool,
+}
+
+#[derive(Debug)]
+pub enum ChokerCommand {
+    Register {
+        addr: (String, u16),
+        stats: Arc<PeerStats>,
+        /// receives the choke state whenever it changes
+        transmitter: UnboundedSender<ChokeState>,
+    },
+    Unregister {
+        addr: (String, u16),
+    },
+}
+
+#[derive(Debug)]
+struct ChokerPeer {
+    stats: Arc<PeerStats>,
+    transmitter: UnboundedSender<ChokeState>,
+    unchoked: bool,
+    /// counters at the previous round
+    downloaded: u64,
+    uploaded: u64,
+    /// bytes transferred during the previous round
+    rate: u64,
+}
+
+/// Decides which peers may download from us, tit-for-tat with an optimistic unchoke.
+/// While leeching peers are ranked by how fast they send to us, while seeding by how fast they take from us.
+#[derive(Debug)]
+pub struct Choker {
+    receive_commands: UnboundedReceiver<ChokerCommand>,
+    peers: HashMap<(String, u16), ChokerPeer>,
+    have: Arc<HavePieces>,
+    optimistic: Option<(String, u16)>,
+    round: u32,
+}
+
+impl Choker {
+    pub fn new(receive_commands: UnboundedReceiver<ChokerCommand>, have: Arc<HavePieces>) -> Self {
+        Self {
+            receive_commands,
+            peers: HashMap::new(),
+            have,
+            optimistic: None,
+            round: 0,
+        }
+    }
+
+    pub fn listen(mut self) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut interval = time::interval(CHOKE_INTERVAL);
+            loop {
+                tokio::select! {
+                    cmd = self.receive_commands.recv() => match cmd {
+                        Some(cmd) => self.handle_command(cmd),
+                        None => break,
+                    },
+                    _ = interval.tick() => self.choke_round(),
+                }
+            }
+        })
+    }
+
+    fn handle_command(&mut self, cmd: ChokerCommand) {
+        match cmd {
+            ChokerCommand::Register {
+                addr,
+                stats,
+                transmitter,
+            } => {
+                let peer = ChokerPeer {
+                    downloaded: stats.downloaded.load(Ordering::Relaxed),
+                    uploaded: stats.uploaded.load(Ordering::Relaxed),
+                    stats,
+                    transmitter,
+                    unchoked: false,
+                    rate: 0,
+                };
+                self.peers.insert(addr, peer);
+            }
+            ChokerCommand::Unregister { addr } => {
+                self.peers.remove(&addr);
+                if self.optimistic.as_ref() == Some(&addr) {
+                    self.optimistic = None;
+                }
+            }
+        }
+    }
+
+    /// Unchoke the interested peers with the best rates plus the optimistic unchoke, choke everyone else
+    fn choke_round(&mut self) {
+        let seeding = self.have.is_complete();
+        for peer in self.peers.values_mut() {
+            let downloaded = peer.stats.downloaded.load(Ordering::Relaxed);
+            let uploaded = peer.stats.uploaded.load(Ordering::Relaxed);
+            peer.rate = if seeding {
+                uploaded - peer.uploaded
+            } else {
+                downloaded - peer.downloaded
+            };
+            peer.downloaded = downloaded;
+            peer.uploaded = uploaded;
+        }
+
+        let mut interested: Vec<(&(String, u16), &ChokerPeer)> = self
+            .peers
+            .iter()
+            .filter(|(_, peer)| peer.stats.interested.load(Ordering::Relaxed))
+            .collect();
+        interested.sort_by_key(|(_, peer)| std::cmp::Reverse(peer.rate));
+        let mut unchoke: HashSet<(String, u16)> = interested
+            .iter()
+            .take(UPLOAD_SLOTS)
+            .map(|(addr, _)| (*addr).clone())
+            .collect();
+
+        // give a peer which isn't unchoked for its rate a chance to show a better one
+        let optimistic_left = self
+            .optimistic
+            .as_ref()
+            .is_none_or(|addr| !interested.iter().any(|(other, _)| *other == addr));
+        if self.round.is_multiple_of(OPTIMISTIC_ROUNDS) || optimistic_left {
+            let candidates: Vec<&(String, u16)> = interested
+                .iter()
+                .map(|(addr, _)| *addr)
+                .filter(|addr| !unchoke.contains(*addr))
+                .collect();
+            self.optimistic = match candidates.len() {
+                0 => None,
+                len => {
+                    let index = utils::random_u32().unwrap_or(0) as usize % len;
+                    Some(candidates[index].clone())
+                }
+            };
+        }
+        if let Some(addr) = &self.optimistic {
+            unchoke.insert(addr.clone());
+        }
+        self.round += 1;
+
+        for (addr, peer) in self.peers.iter_mut() {
+            let unchoked = unchoke.contains(addr);
+            if unchoked == peer.unchoked {
+                continue;
+            }
+            peer.unchoked = unchoked;
+            let state = if unchoked {
+                ChokeState::Unchoked
+            } else {
+                ChokeState::Choked
+            };
+            // the peer may have disconnected before unregistering
+            peer.transmitter.send(state).unwrap_or(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::mpsc;
+
+    fn register(
+        choker: &mut Choker,
+        port: u16,
+        interested: bool,
+    ) -> (Arc<PeerStats>, UnboundedReceiver<ChokeState>) {
+        let stats = Arc::new(PeerStats::default());
+        stats.interested.store(interested, Ordering::Relaxed);
+        let (transmitter, receive_states) = mpsc::unbounded_channel();
+        choker.handle_command(ChokerCommand::Register {
+            addr: ("10.0.0.1".to_string(), port),
+            stats: stats.clone(),
+            transmitter,
+        });
+        (stats, receive_states)
+    }
+
+    #[test]
+    fn test_choke_round() {
+        let (_tx, rx) = mpsc::unbounded_channel();
+        let have = Arc::new(HavePieces::new(1));
+        let mut choker = Choker::new(rx, have.clone());
+        let mut peers: Vec<_> = (0..7)
+            .map(|port| register(&mut choker, port, port != 6))
+            .collect();
+        // the peers which sent us the most get the regular slots
+        for (port, (stats, _)) in peers.iter().enumerate() {
+            stats
+                .downloaded
+                .store(port as u64 * 1000, Ordering::Relaxed);
+        }
+        choker.choke_round();
+
+        let mut unchoked = vec![];
+        for (port, (_, receive_states)) in peers.iter_mut().enumerate() {
+            if let Ok(state) = receive_states.try_recv() {
+                assert_eq!(state, ChokeState::Unchoked);
+                unchoked.push(port);
+            }
+        }
+        // four regular slots and one optimistic unchoke among the slower interested peers
+        assert_eq!(unchoked.len(), 5);
+        assert!([2, 3, 4, 5].iter().all(|port| unchoked.contains(port)));
+        assert!(!unchoked.contains(&6));
+
+        // once we are seeding, the peers which take the most from us are preferred
+        have.insert(0);
+        peers[0].0.uploaded.store(10_000, Ordering::Relaxed);
+        choker.choke_round();
+        assert!(choker.peers[&("10.0.0.1".to_string(), 0)].unchoked);
+    }
+}
@@ -0,0 +1,564 @@
+use ring::digest;
+use serde_bencode::{de, ser};
+use serde_bytes::ByteBuf;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::{self, ToSocketAddrs, UdpSocket};
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+use tokio::time::{self, Instant};
+
+use crate::{utils, Result};
+
+mod krpc;
+mod routing;
+
+use krpc::{Args, Message, Response};
+pub use routing::NodeId;
+use routing::{RoutingTable, K};
+
+/// reserved bit which tells the peer that we run a DHT node, see BEP 5
+pub const DHT_BIT: (usize, u8) = (7, 0x01);
+/// well known nodes to join the DHT through
+pub const BOOTSTRAP_NODES: [&str; 3] = [
+    "router.bittorrent.com:6881",
+    "dht.transmissionbt.com:6881",
+    "router.utorrent.com:6881",
+];
+/// number of queries a lookup has in flight at a time
+const ALPHA: usize = 3;
+const QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+/// a token stays valid for up to two rotations
+const TOKEN_ROTATION: Duration = Duration::from_secs(5 * 60);
+/// announced peers are forgotten unless they announce again
+const PEER_TTL: Duration = Duration::from_secs(30 * 60);
+/// peers returned in a single get_peers response
+const MAX_VALUES: usize = 50;
+
+/// Whether the reserved bytes of a handshake have the DHT bit set
+pub fn supports_dht(reserved_bytes: &[u8]) -> bool {
+    let (byte, bit) = DHT_BIT;
+    reserved_bytes[byte] & bit != 0
+}
+
+pub fn random_id() -> Result<NodeId> {
+    krpc::node_id(&utils::generate_peer_id()?)
+}
+
+/// Resolve `host:port` strings to the IPv4 addresses of the nodes, unresolvable hosts are skipped
+pub async fn resolve(hosts: &[String]) -> Vec<SocketAddr> {
+    let mut addrs = vec![];
+    for host in hosts {
+        if let Ok(resolved) = net::lookup_host(host.as_str()).await {
+            addrs.extend(resolved.filter(|addr| addr.is_ipv4()));
+        }
+    }
+    addrs
+}
+
+/// Secrets the tokens handed out in get_peers responses are derived from
+#[derive(Debug)]
+struct Tokens {
+    /// current and previous secret
+    secrets: [Vec<u8>; 2],
+    rotated: Instant,
+}
+
+impl Tokens {
+    fn new() -> Result<Self> {
+        Ok(Self {
+            secrets: [utils::generate_peer_id()?, utils::generate_peer_id()?],
+            rotated: Instant::now(),
+        })
+    }
+
+    fn rotate(&mut self) -> Result<()> {
+        if self.rotated.elapsed() >= TOKEN_ROTATION {
+            self.secrets.swap(0, 1);
+            self.secrets[0] = utils::generate_peer_id()?;
+            self.rotated = Instant::now();
+        }
+        Ok(())
+    }
+
+    fn token(secret: &[u8], ip: IpAddr) -> Vec<u8> {
+        let mut input = secret.to_vec();
+        match ip {
+            IpAddr::V4(ip) => input.extend_from_slice(&ip.octets()),
+            IpAddr::V6(ip) => input.extend_from_slice(&ip.octets()),
+        }
+        digest::digest(&digest::SHA1_FOR_LEGACY_USE_ONLY, &input).as_ref()[..8].to_vec()
+    }
+
+    fn generate(&self, ip: IpAddr) -> Vec<u8> {
+        Tokens::token(&self.secrets[0], ip)
+    }
+
+    fn validate(&self, token: &[u8], ip: IpAddr) -> bool {
+        self.secrets
+            .iter()
+            .any(|secret| Tokens::token(secret, ip) == token)
+    }
+}
+
+#[derive(Debug)]
+struct State {
+    table: RoutingTable,
+    tokens: Tokens,
+    /// peers which announced themselves for an info hash
+    peers: HashMap<NodeId, Vec<(SocketAddr, Instant)>>,
+}
+
+/// queried node and where its response goes
+type PendingQuery = (SocketAddr, oneshot::Sender<Message>);
+
+/// Result of an iterative lookup
+struct Lookup {
+    peers: Vec<SocketAddr>,
+    /// closest nodes which responded, along with the token they handed out
+    closest: Vec<(NodeId, SocketAddr, Option<Vec<u8>>)>,
+}
+
+/// On disk cache of the routing table, so that restarts don't depend on the bootstrap nodes
+#[derive(Debug, Deserialize, Serialize)]
+struct NodeCache {
+    id: ByteBuf,
+    /// compact node info
+    nodes: ByteBuf,
+}
+
+/// A node of the mainline DHT, see BEP 5
+#[derive(Debug, Clone)]
+pub struct Dht {
+    id: NodeId,
+    socket: Arc<UdpSocket>,
+    state: Arc<Mutex<State>>,
+    /// queries waiting for a response, by transaction id
+    pending: Arc<Mutex<HashMap<Vec<u8>, PendingQuery>>>,
+}
+
+impl Dht {
+    pub async fn bind<A: ToSocketAddrs>(addr: A, id: NodeId) -> Result<Dht> {
+        let socket = UdpSocket::bind(addr).await?;
+        Ok(Dht {
+            id,
+            socket: Arc::new(socket),
+            state: Arc::new(Mutex::new(State {
+                table: RoutingTable::new(id),
+                tokens: Tokens::new()?,
+                peers: HashMap::new(),
+            })),
+            pending: Arc::new(Mutex::new(HashMap::new())),
+        })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.socket.local_addr()?)
+    }
+
+    /// Answer the queries of other nodes and hand the responses to the waiting queries
+    pub fn listen(&self) -> JoinHandle<()> {
+        let dht = self.clone();
+        tokio::spawn(async move {
+            let mut buf = [0; 2048];
+            loop {
+                let (len, addr) = match dht.socket.recv_from(&mut buf).await {
+                    Ok(res) => res,
+                    Err(e) => {
+                        eprintln!("DHT socket error: {}", e);
+                        continue;
+                    }
+                };
+                // anything which isn't a KRPC message is ignored
+                let msg = match de::from_bytes::<Message>(&buf[..len]) {
+                    Ok(msg) => msg,
+                    Err(_) => continue,
+                };
+                if let Err(e) = dht.handle_message(msg, addr).await {
+                    eprintln!("DHT message from {} failed: {}", addr, e);
+                }
+            }
+        })
+    }
+
+    async fn handle_message(&self, msg: Message, addr: SocketAddr) -> Result<()> {
+        match msg.y.as_str() {
+            "q" => {
+                let response = self.handle_query(&msg, addr)?;
+                self.socket
+                    .send_to(&ser::to_bytes(&response)?, addr)
+                    .await?;
+            }
+            "r" | "e" => {
+                let mut pending = self.pending.lock().unwrap();
+                // responses have to come from the node which was queried
+                if matches!(pending.get(msg.t.as_ref()), Some((queried, _)) if *queried == addr) {
+                    if let Some((_, transmitter)) = pending.remove(msg.t.as_ref()) {
+                        transmitter.send(msg).unwrap_or(());
+                    }
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn handle_query(&self, msg: &Message, addr: SocketAddr) -> Result<Message> {
+        let protocol_error = |message: &str| Ok(Message::error(msg.t.clone(), 203, message));
+        let args = match &msg.a {
+            Some(args) => args,
+            None => return protocol_error("Missing arguments"),
+        };
+        let id = match krpc::node_id(&args.id) {
+            Ok(id) => id,
+            Err(_) => return protocol_error("Invalid node id"),
+        };
+
+        let mut state = self.state.lock().unwrap();
+        state.table.insert(id, addr);
+        let mut response = Response {
+            id: ByteBuf::from(self.id.to_vec()),
+            ..Default::default()
+        };
+        match msg.q.as_deref() {
+            Some("ping") => {}
+            Some("find_node") => {
+                let target = match args.target.as_deref().map(|bytes| krpc::node_id(bytes)) {
+                    Some(Ok(target)) => target,
+                    _ => return protocol_error("Invalid target"),
+                };
+                response.nodes = Some(ByteBuf::from(closest_nodes(&state.table, &target)));
+            }
+            Some("get_peers") => {
+                let info_hash = match args.info_hash.as_deref().map(|bytes| krpc::node_id(bytes)) {
+                    Some(Ok(info_hash)) => info_hash,
+                    _ => return protocol_error("Invalid info hash"),
+                };
+                state.tokens.rotate()?;
+                response.token = Some(ByteBuf::from(state.tokens.generate(addr.ip())));
+
+                let mut values = vec![];
+                if let Some(peers) = state.peers.get_mut(&info_hash) {
+                    peers.retain(|(_, announced)| announced.elapsed() < PEER_TTL);
+                    values = peers
+                        .iter()
+                        .rev()
+                        .take(MAX_VALUES)
+                        .filter_map(|(peer, _)| krpc::encode_peer(peer))
+                        .map(ByteBuf::from)
+                        .collect();
+                }
+                if values.is_empty() {
+                    response.nodes = Some(ByteBuf::from(closest_nodes(&state.table, &info_hash)));
+                } else {
+                    response.values = Some(values);
+                }
+            }
+            Some("announce_peer") => {
+                let info_hash = match args.info_hash.as_deref().map(|bytes| krpc::node_id(bytes)) {
+                    Some(Ok(info_hash)) => info_hash,
+                    _ => return protocol_error("Invalid info hash"),
+                };
+                let token = args.token.clone().unwrap_or_default();
+                if !state.tokens.validate(&token, addr.ip()) {
+                    return protocol_error("Bad token");
+                }
+                let port = match (args.implied_port, args.port) {
+                    (Some(1), _) => addr.port(),
+                    (_, Some(port)) => port,
+                    _ => return protocol_error("Missing port"),
+                };
+                let peer = SocketAddr::new(addr.ip(), port);
+                let peers = state.peers.entry(info_hash).or_default();
+                peers.retain(|(announced, _)| *announced != peer);
+                peers.push((peer, Instant::now()));
+            }
+            _ => return Ok(Message::error(msg.t.clone(), 204, "Method Unknown")),
+        }
+        Ok(Message::response(msg.t.clone(), response))
+    }
+
+    async fn query(&self, addr: SocketAddr, method: &str, mut args: Args) -> Result<Response> {
+        args.id = ByteBuf::from(self.id.to_vec());
+        let t = utils::random_u32()?.to_be_bytes().to_vec();
+        let (tx, rx) = oneshot::channel();
+        self.pending.lock().unwrap().insert(t.clone(), (addr, tx));
+
+        let query = ser::to_bytes(&Message::query(t.clone(), method, args))?;
+        let response = match self.socket.send_to(&query, addr).await {
+            Ok(_) => time::timeout(QUERY_TIMEOUT, rx).await,
+            Err(e) => {
+                self.pending.lock().unwrap().remove(&t);
+                return Err(e.into());
+            }
+        };
+        self.pending.lock().unwrap().remove(&t);
+
+        let msg = match response {
+            Ok(Ok(msg)) => msg,
+            _ => {
+                self.state.lock().unwrap().table.failed(&addr);
+                Err("DHT query timed out")?
+            }
+        };
+        match (msg.r, msg.e) {
+            (Some(response), _) => {
+                let id = krpc::node_id(&response.id)?;
+                self.state.lock().unwrap().table.insert(id, addr);
+                Ok(response)
+            }
+            (_, Some((code, message))) => Err(format!("DHT error {}: {}", code, message))?,
+            _ => Err("Invalid DHT response")?,
+        }
+    }
+
+    pub async fn ping(&self, addr: SocketAddr) -> Result<NodeId> {
+        let response = self.query(addr, "ping", Args::default()).await?;
+        krpc::node_id(&response.id)
+    }
+
+    /// Join the DHT through the given nodes and fill the routing table with the nodes closest to us
+    pub async fn bootstrap(&self, addrs: &[SocketAddr]) {
+        let handles: Vec<JoinHandle<Result<NodeId>>> = addrs
+            .iter()
+            .map(|&addr| {
+                let dht = self.clone();
+                tokio::spawn(async move { dht.ping(addr).await })
+            })
+            .collect();
+        for handle in handles {
+            handle.await.ok();
+        }
+        self.lookup(self.id, false).await;
+    }
+
+    /// Add a node we learned about from a `Port` message, it is only kept if it responds
+    pub fn add_node(&self, addr: SocketAddr) {
+        let dht = self.clone();
+        tokio::spawn(async move { dht.ping(addr).await.ok() });
+    }
+
+    /// Iterative lookup of the nodes closest to `target`.
+    /// With `get_peers` the target is an info hash and the peers returned on the way are collected.
+    async fn lookup(&self, target: NodeId, get_peers: bool) -> Lookup {
+        let mut candidates: Vec<(NodeId, SocketAddr)> = self
+            .state
+            .lock()
+            .unwrap()
+            .table
+            .closest(&target, K)
+            .into_iter()
+            .map(|node| (node.id, node.addr))
+            .collect();
+        let mut queried = HashSet::new();
+        let mut peers = HashSet::new();
+        let mut closest = vec![];
+
+        loop {
+            candidates.retain(|(id, _)| *id != self.id);
+            candidates.sort_by_key(|(id, _)| routing::distance(id, &target));
+            candidates.dedup_by_key(|(id, _)| *id);
+            // the lookup is done once the closest nodes have all been queried
+            let next: Vec<(NodeId, SocketAddr)> = candidates
+                .iter()
+                .take(K)
+                .filter(|(_, addr)| !queried.contains(addr))
+                .take(ALPHA)
+                .cloned()
+                .collect();
+            if next.is_empty() {
+                break;
+            }
+
+            let handles: Vec<_> = next
+                .into_iter()
+                .map(|(_, addr)| {
+                    queried.insert(addr);
+                    let dht = self.clone();
+                    tokio::spawn(async move {
+                        let args = if get_peers {
+                            Args {
+                                info_hash: Some(ByteBuf::from(target.to_vec())),
+                                ..Default::default()
+                            }
+                        } else {
+                            Args {
+                                target: Some(ByteBuf::from(target.to_vec())),
+                                ..Default::default()
+                            }
+                        };
+                        let method = if get_peers { "get_peers" } else { "find_node" };
+                        (addr, dht.query(addr, method, args).await)
+                    })
+                })
+                .collect();
+
+            for handle in handles {
+                let (addr, res) = match handle.await {
+                    Ok(res) => res,
+                    Err(_) => continue,
+                };
+                let response = match res {
+                    Ok(response) => response,
+                    Err(_) => {
+                        candidates.retain(|(_, candidate)| *candidate != addr);
+                        continue;
+                    }
+                };
+                if let Some(nodes) = &response.nodes {
+                    candidates.extend(krpc::decode_nodes(nodes));
+                }
+                if let Some(values) = &response.values {
+                    peers.extend(values.iter().filter_map(|value| krpc::decode_peer(value)));
+                }
+                if let Ok(id) = krpc::node_id(&response.id) {
+                    closest.push((id, addr, response.token.map(|token| token.into_vec())));
+                }
+            }
+        }
+
+        closest.sort_by_key(|(id, _, _)| routing::distance(id, &target));
+        closest.truncate(K);
+        Lookup {
+            peers: peers.into_iter().collect(),
+            closest,
+        }
+    }
+
+    pub async fn get_peers(&self, info_hash: NodeId) -> Vec<SocketAddr> {
+        self.lookup(info_hash, true).await.peers
+    }
+
+    /// Find the peers of the torrent and tell the closest nodes that we accept connections on `port`
+    pub async fn announce(&self, info_hash: NodeId, port: u16) -> Vec<SocketAddr> {
+        let lookup = self.lookup(info_hash, true).await;
+        let handles: Vec<_> = lookup
+            .closest
+            .into_iter()
+            .filter_map(|(_, addr, token)| Some((addr, token?)))
+            .map(|(addr, token)| {
+                let dht = self.clone();
+                tokio::spawn(async move {
+                    let args = Args {
+                        info_hash: Some(ByteBuf::from(info_hash.to_vec())),
+                        port: Some(port),
+                        token: Some(ByteBuf::from(token)),
+                        ..Default::default()
+                    };
+                    dht.query(addr, "announce_peer", args).await
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.await.ok();
+        }
+        lookup.peers
+    }
+
+    /// Save our id and the nodes of the routing table
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let nodes: Vec<(NodeId, SocketAddr)> = self
+            .state
+            .lock()
+            .unwrap()
+            .table
+            .nodes()
+            .into_iter()
+            .map(|node| (node.id, node.addr))
+            .collect();
+        let cache = NodeCache {
+            id: ByteBuf::from(self.id.to_vec()),
+            nodes: ByteBuf::from(krpc::encode_nodes(&nodes)),
+        };
+        fs::write(path, ser::to_bytes(&cache)?)?;
+        Ok(())
+    }
+
+    /// Id and node addresses saved by `save`
+    pub fn load(path: &Path) -> Result<(NodeId, Vec<SocketAddr>)> {
+        let cache = de::from_bytes::<NodeCache>(&fs::read(path)?)?;
+        let nodes = krpc::decode_nodes(&cache.nodes)
+            .into_iter()
+            .map(|(_, addr)| addr)
+            .collect();
+        Ok((krpc::node_id(&cache.id)?, nodes))
+    }
+}
+
+/// Compact node info of the nodes closest to `target`
+fn closest_nodes(table: &RoutingTable, target: &NodeId) -> Vec<u8> {
+    let nodes: Vec<(NodeId, SocketAddr)> = table
+        .closest(target, K)
+        .into_iter()
+        .map(|node| (node.id, node.addr))
+        .collect();
+    krpc::encode_nodes(&nodes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn swarm(size: usize) -> Result<Vec<Dht>> {
+        let mut nodes = vec![];
+        for _ in 0..size {
+            let dht = Dht::bind("127.0.0.1:0", random_id()?).await?;
+            dht.listen();
+            nodes.push(dht);
+        }
+        let bootstrap = nodes[0].local_addr()?;
+        for dht in &nodes[1..] {
+            dht.bootstrap(&[bootstrap]).await;
+        }
+        Ok(nodes)
+    }
+
+    #[tokio::test]
+    async fn test_announce_and_get_peers() -> Result<()> {
+        let nodes = swarm(12).await?;
+        let info_hash = random_id()?;
+        assert!(nodes[3].announce(info_hash, 51413).await.is_empty());
+
+        let peers = nodes[11].get_peers(info_hash).await;
+        assert_eq!(peers, vec!["127.0.0.1:51413".parse()?]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_bad_token() -> Result<()> {
+        let nodes = swarm(2).await?;
+        let args = Args {
+            info_hash: Some(ByteBuf::from(vec![1; 20])),
+            port: Some(51413),
+            token: Some(ByteBuf::from(b"invalid".to_vec())),
+            ..Default::default()
+        };
+        let res = nodes[1]
+            .query(nodes[0].local_addr()?, "announce_peer", args)
+            .await;
+        assert!(res.is_err());
+        assert!(nodes[1].get_peers([1; 20]).await.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_node_cache() -> Result<()> {
+        let nodes = swarm(3).await?;
+        let path = std::env::temp_dir().join(format!("bitr-dht-{}", std::process::id()));
+        nodes[2].save(&path)?;
+        let (id, addrs) = Dht::load(&path)?;
+        fs::remove_file(path)?;
+
+        assert_eq!(id, nodes[2].id);
+        let mut addrs: Vec<u16> = addrs.iter().map(|addr| addr.port()).collect();
+        addrs.sort_unstable();
+        let mut expected = vec![nodes[0].local_addr()?.port(), nodes[1].local_addr()?.port()];
+        expected.sort_unstable();
+        assert_eq!(addrs, expected);
+        Ok(())
+    }
+}
@@ -0,0 +1,217 @@
+use serde::de::{self, Deserialize, Deserializer};
+use serde_bencode::value::Value;
+use serde_bytes::ByteBuf;
+use std::convert::TryInto;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+use super::routing::NodeId;
+use crate::Result;
+
+/// A KRPC message, see BEP 5
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct Message {
+    /// transaction id, echoed back in the response
+    pub t: ByteBuf,
+    /// `q` for queries, `r` for responses and `e` for errors
+    pub y: String,
+    /// name of the queried method
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub q: Option<String>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub a: Option<Args>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub r: Option<Response>,
+    /// error code and message
+    #[serde(default)]
+    #[serde(deserialize_with = "deserialize_error")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub e: Option<(i64, String)>,
+}
+
+/// Arguments of all the queries
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct Args {
+    /// id of the querying node
+    pub id: ByteBuf,
+    /// find_node
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub target: Option<ByteBuf>,
+    /// get_peers and announce_peer
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub info_hash: Option<ByteBuf>,
+    /// announce_peer
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub port: Option<u16>,
+    /// announce_peer, received in the get_peers response
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token: Option<ByteBuf>,
+    /// announce_peer, use the source port of the packet instead of `port`
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub implied_port: Option<u8>,
+}
+
+/// Values of all the responses
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct Response {
+    /// id of the responding node
+    pub id: ByteBuf,
+    /// compact node info of the closest nodes
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nodes: Option<ByteBuf>,
+    /// compact peer info of the peers of a torrent
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub values: Option<Vec<ByteBuf>>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token: Option<ByteBuf>,
+}
+
+impl Message {
+    pub fn query(t: Vec<u8>, method: &str, args: Args) -> Self {
+        Self {
+            t: ByteBuf::from(t),
+            y: "q".to_string(),
+            q: Some(method.to_string()),
+            a: Some(args),
+            ..Default::default()
+        }
+    }
+
+    pub fn response(t: ByteBuf, response: Response) -> Self {
+        Self {
+            t,
+            y: "r".to_string(),
+            r: Some(response),
+            ..Default::default()
+        }
+    }
+
+    pub fn error(t: ByteBuf, code: i64, message: &str) -> Self {
+        Self {
+            t,
+            y: "e".to_string(),
+            e: Some((code, message.to_string())),
+            ..Default::default()
+        }
+    }
+}
+
+/// serde_bencode leaves the end of a list behind when deserializing it as a tuple,
+/// so the error is read as a list of values instead
+fn deserialize_error<'de, D>(
+    deserializer: D,
+) -> std::result::Result<Option<(i64, String)>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let values = Vec::<Value>::deserialize(deserializer)?;
+    match values.as_slice() {
+        [Value::Int(code), Value::Bytes(message), ..] => {
+            Ok(Some((*code, String::from_utf8_lossy(message).into_owned())))
+        }
+        _ => Err(de::Error::custom("Invalid KRPC error")),
+    }
+}
+
+pub fn node_id(bytes: &[u8]) -> Result<NodeId> {
+    Ok(bytes.try_into().map_err(|_| "Node id is not 20 bytes")?)
+}
+
+/// Compact node info, 20 byte id followed by the compact IPv4 address.
+/// IPv6 nodes are skipped since they can't be represented.
+pub fn encode_nodes(nodes: &[(NodeId, SocketAddr)]) -> Vec<u8> {
+    let mut bytes = vec![];
+    for (id, addr) in nodes {
+        if let Some(peer) = encode_peer(addr) {
+            bytes.extend_from_slice(id);
+            bytes.extend_from_slice(&peer);
+        }
+    }
+    bytes
+}
+
+pub fn decode_nodes(bytes: &[u8]) -> Vec<(NodeId, SocketAddr)> {
+    bytes
+        .chunks_exact(26)
+        .filter_map(|chunk| {
+            let id = node_id(&chunk[..20]).ok()?;
+            Some((id, decode_peer(&chunk[20..])?))
+        })
+        .collect()
+}
+
+/// Compact peer info, 4 byte IPv4 address followed by the port
+pub fn encode_peer(addr: &SocketAddr) -> Option<Vec<u8>> {
+    match addr.ip() {
+        IpAddr::V4(ip) => {
+            let mut bytes = ip.octets().to_vec();
+            bytes.extend_from_slice(&addr.port().to_be_bytes());
+            Some(bytes)
+        }
+        IpAddr::V6(_) => None,
+    }
+}
+
+pub fn decode_peer(bytes: &[u8]) -> Option<SocketAddr> {
+    if bytes.len() != 6 {
+        return None;
+    }
+    let ip = Ipv4Addr::new(bytes[0], bytes[1], bytes[2], bytes[3]);
+    Some(SocketAddr::new(
+        IpAddr::V4(ip),
+        u16::from_be_bytes([bytes[4], bytes[5]]),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_bencode::{de, ser};
+
+    #[test]
+    fn test_encode_query() -> Result<()> {
+        // example from BEP 5
+        let args = Args {
+            id: ByteBuf::from(b"abcdefghij0123456789".to_vec()),
+            ..Default::default()
+        };
+        let query = Message::query(b"aa".to_vec(), "ping", args);
+        assert_eq!(
+            ser::to_bytes(&query)?,
+            b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t2:aa1:y1:qe"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_decode_error() -> Result<()> {
+        let error =
+            de::from_bytes::<Message>(b"d1:eli201e23:A Generic Error Ocurrede1:t2:aa1:y1:ee")?;
+        assert_eq!(error.y, "e");
+        assert_eq!(error.e, Some((201, "A Generic Error Ocurred".to_string())));
+        Ok(())
+    }
+
+    #[test]
+    fn test_compact_nodes() -> Result<()> {
+        let nodes = vec![
+            ([1; 20], "10.0.0.1:6881".parse()?),
+            ([2; 20], "[::1]:6881".parse()?),
+            ([3; 20], "10.0.0.3:6883".parse()?),
+        ];
+        let bytes = encode_nodes(&nodes);
+        assert_eq!(bytes.len(), 52);
+        assert_eq!(decode_nodes(&bytes), vec![nodes[0], nodes[2]]);
+        Ok(())
+    }
+}
@@ -0,0 +1,169 @@
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::time::Instant;
+
+pub type NodeId = [u8; 20];
+
+/// number of nodes in every bucket
+pub const K: usize = 8;
+/// nodes which didn't respond this many times in a row are replaced first
+const MAX_FAILURES: u32 = 3;
+/// nodes which weren't heard from for this long can be replaced
+const STALE_AFTER: Duration = Duration::from_secs(15 * 60);
+
+/// XOR distance between two ids, ids compare as big endian numbers
+pub fn distance(a: &NodeId, b: &NodeId) -> NodeId {
+    let mut distance = [0; 20];
+    for i in 0..20 {
+        distance[i] = a[i] ^ b[i];
+    }
+    distance
+}
+
+#[derive(Debug, Clone)]
+pub struct Node {
+    pub id: NodeId,
+    pub addr: SocketAddr,
+    last_seen: Instant,
+    /// queries in a row which timed out
+    failures: u32,
+}
+
+impl Node {
+    fn is_bad(&self) -> bool {
+        self.failures >= MAX_FAILURES
+            || (self.failures > 0 && self.last_seen.elapsed() > STALE_AFTER)
+    }
+}
+
+/// Kademlia routing table with one bucket for every bit of the id.
+/// Nodes in bucket `i` share exactly `i` leading bits with our id.
+#[derive(Debug)]
+pub struct RoutingTable {
+    id: NodeId,
+    buckets: Vec<Vec<Node>>,
+}
+
+impl RoutingTable {
+    pub fn new(id: NodeId) -> Self {
+        Self {
+            id,
+            buckets: vec![vec![]; 160],
+        }
+    }
+
+    /// `None` for our own id
+    fn bucket_index(&self, id: &NodeId) -> Option<usize> {
+        let distance = distance(&self.id, id);
+        let leading_zeros = distance
+            .iter()
+            .position(|byte| *byte != 0)
+            .map(|i| i * 8 + distance[i].leading_zeros() as usize)?;
+        Some(leading_zeros)
+    }
+
+    /// Record that the node was heard from, it is added if its bucket has room
+    pub fn insert(&mut self, id: NodeId, addr: SocketAddr) {
+        let index = match self.bucket_index(&id) {
+            Some(index) => index,
+            None => return,
+        };
+        let bucket = &mut self.buckets[index];
+        let node = Node {
+            id,
+            addr,
+            last_seen: Instant::now(),
+            failures: 0,
+        };
+        // the most recently seen nodes are kept at the end
+        if let Some(position) = bucket.iter().position(|node| node.id == id) {
+            bucket.remove(position);
+            bucket.push(node);
+        } else if bucket.len() < K {
+            bucket.push(node);
+        } else if let Some(position) = bucket.iter().position(Node::is_bad) {
+            bucket.remove(position);
+            bucket.push(node);
+        }
+    }
+
+    /// Record that a query to the node timed out
+    pub fn failed(&mut self, addr: &SocketAddr) {
+        for bucket in self.buckets.iter_mut() {
+            if let Some(node) = bucket.iter_mut().find(|node| node.addr == *addr) {
+                node.failures += 1;
+                return;
+            }
+        }
+    }
+
+    /// Up to `count` good nodes ordered by their distance to `target`
+    pub fn closest(&self, target: &NodeId, count: usize) -> Vec<Node> {
+        let mut nodes: Vec<Node> = self
+            .buckets
+            .iter()
+            .flatten()
+            .filter(|node| !node.is_bad())
+            .cloned()
+            .collect();
+        nodes.sort_by_key(|node| distance(&node.id, target));
+        nodes.truncate(count);
+        nodes
+    }
+
+    pub fn nodes(&self) -> Vec<Node> {
+        self.buckets.iter().flatten().cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn id(first_byte: u8) -> NodeId {
+        let mut id = [0; 20];
+        id[0] = first_byte;
+        id
+    }
+
+    #[test]
+    fn test_closest() {
+        let mut table = RoutingTable::new(id(0));
+        let addr = "127.0.0.1:6881".parse().unwrap();
+        for first_byte in [0b1000_0000, 0b0100_0000, 0b0110_0000, 0b0000_0001] {
+            table.insert(id(first_byte), addr);
+        }
+        // our own id is never added
+        table.insert(id(0), addr);
+        assert_eq!(table.nodes().len(), 4);
+
+        let closest: Vec<u8> = table
+            .closest(&id(0b0111_0000), 3)
+            .iter()
+            .map(|node| node.id[0])
+            .collect();
+        assert_eq!(closest, vec![0b0110_0000, 0b0100_0000, 0b0000_0001]);
+    }
+
+    #[test]
+    fn test_full_bucket() {
+        let mut table = RoutingTable::new(id(0));
+        // all of them share no leading bits with our id and end up in the same bucket
+        for i in 0..=K as u8 {
+            let addr = SocketAddr::from(([127, 0, 0, 1], 6881 + i as u16));
+            table.insert(id(0x80 + i), addr);
+        }
+        assert_eq!(table.nodes().len(), K);
+
+        // a node which stopped responding makes room for a new one
+        let first = table.nodes()[0].addr;
+        for _ in 0..MAX_FAILURES {
+            table.failed(&first);
+        }
+        table.insert(id(0xff), "127.0.0.1:7000".parse().unwrap());
+        let nodes = table.nodes();
+        assert_eq!(nodes.len(), K);
+        assert!(nodes.iter().all(|node| node.addr != first));
+        assert_eq!(nodes[K - 1].id, id(0xff));
+    }
+}
@@ -1,63 +1,354 @@
-use std::collections::HashMap;
-use std::fs::File;
-use std::io;
-use std::os::unix::prelude::FileExt;
-use tokio::task::{self, spawn_blocking, JoinHandle};
+use bitvec::{order::Msb0, prelude::BitSlice, prelude::BitVec};
+use std::path::PathBuf;
+use std::sync::{atomic::Ordering, Arc, Mutex};
+use tokio::task::{self, JoinHandle};
 
-use tokio::sync::mpsc::UnboundedReceiver;
+use tokio::sync::broadcast;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
 
-use crate::{manager::DownloadedPiece, Result};
+use crate::disk_pool::DiskPool;
+use crate::manager::{DownloadedPiece, TransferStats};
+use crate::recheck::{self, Recheck};
+use crate::storage::Storage;
+use crate::{tracker::Event, Result};
+
+/// haves sent while a peer is busy beyond this are dropped for it
+const HAVE_CAPACITY: usize = 1024;
+
+/// Pieces which are verified and written to disk
+#[derive(Debug)]
+pub struct HavePieces {
+    bitfield: Mutex<BitVec<Msb0, u8>>,
+}
+
+impl HavePieces {
+    pub fn new(total_pieces: u32) -> Self {
+        Self {
+            bitfield: Mutex::new(BitVec::repeat(false, total_pieces as usize)),
+        }
+    }
+
+    pub fn insert(&self, index: u32) {
+        if let Some(mut bit) = self.bitfield.lock().unwrap().get_mut(index as usize) {
+            *bit = true;
+        }
+    }
+
+    pub fn contains(&self, index: u32) -> bool {
+        self.bitfield
+            .lock()
+            .unwrap()
+            .get(index as usize)
+            .is_some_and(|bit| *bit)
+    }
+
+    pub fn total_pieces(&self) -> u32 {
+        self.bitfield.lock().unwrap().len() as u32
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.bitfield.lock().unwrap().all()
+    }
+
+    pub fn bitfield(&self) -> BitVec<Msb0, u8> {
+        self.bitfield.lock().unwrap().clone()
+    }
+
+    /// Whether any of the `pieces` of a peer is one we don't have yet
+    pub fn lacks_any(&self, pieces: &BitSlice<Msb0, u8>) -> bool {
+        let bitfield = self.bitfield.lock().unwrap();
+        pieces
+            .iter_ones()
+            .any(|index| bitfield.get(index).is_some_and(|bit| !*bit))
+    }
+}
+
+/// Position of a block within the torrent
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BlockInfo {
+    pub piece_index: u32,
+    /// zero-based byte offset within the piece
+    pub begin: u32,
+    pub length: u32,
+}
+
+/// What the peers need to serve the pieces we have
+#[derive(Debug, Clone)]
+pub struct DiskHandle {
+    pub have: Arc<HavePieces>,
+    pub pool: DiskPool,
+    /// index of every piece once it is written
+    pub haves: broadcast::Sender<u32>,
+}
+
+impl DiskHandle {
+    /// Nothing of the torrent in `storage` is available yet
+    pub fn new(storage: Box<dyn Storage>) -> Self {
+        let total_pieces = storage.layout().total_pieces();
+        let (haves, _) = broadcast::channel(HAVE_CAPACITY);
+        Self {
+            have: Arc::new(HavePieces::new(total_pieces)),
+            pool: DiskPool::new(storage),
+            haves,
+        }
+    }
+
+    /// Read a block of a piece we have
+    pub async fn read_block(&self, block: &BlockInfo) -> Result<Vec<u8>> {
+        if !self.have.contains(block.piece_index) {
+            Err("Piece isn't available")?;
+        }
+        self.pool
+            .read(block.piece_index, block.begin, block.length)
+            .await
+    }
+}
+
+/// A piece which the disk threads are done writing
+#[derive(Debug)]
+struct WrittenPiece {
+    index: u32,
+    length: u64,
+    result: Result<()>,
+}
 
 pub struct DiskManager {
     receive_pieces: UnboundedReceiver<DownloadedPiece>,
-    file: File,
-    piece_length: u64,
+    handle: DiskHandle,
+    send_written: UnboundedSender<WrittenPiece>,
+    receive_written: UnboundedReceiver<WrittenPiece>,
+    /// pieces queued on the disk threads which aren't written yet
+    writing: u32,
     total_pieces: u32,
     completed_pieces: u32,
+    stats: Arc<TransferStats>,
+    /// notifies the announcer once all the pieces are written
+    send_to_announcer: UnboundedSender<Event>,
+    /// the files are moved here once all the pieces are written
+    move_to: Option<PathBuf>,
 }
 
 impl DiskManager {
     pub fn new(
         receive_pieces: UnboundedReceiver<DownloadedPiece>,
-        file_name: &str,
-        piece_length: u64,
-        total_pieces: u32,
-    ) -> Result<Self> {
-        let file = File::create(file_name)?;
-        Ok(Self {
-            file,
+        storage: Box<dyn Storage>,
+        stats: Arc<TransferStats>,
+        send_to_announcer: UnboundedSender<Event>,
+        move_to: Option<PathBuf>,
+    ) -> Self {
+        let total_pieces = storage.layout().total_pieces();
+        let (send_written, receive_written) = mpsc::unbounded_channel();
+        Self {
             receive_pieces,
-            piece_length,
+            handle: DiskHandle::new(storage),
+            send_written,
+            receive_written,
+            writing: 0,
             total_pieces,
             completed_pieces: 0,
-        })
+            stats,
+            send_to_announcer,
+            move_to,
+        }
+    }
+
+    pub fn handle(&self) -> DiskHandle {
+        self.handle.clone()
+    }
+
+    /// Mark the pieces which are on disk from an earlier run as available
+    pub fn seed(&mut self, have: &BitVec<Msb0, u8>) {
+        let storage = self.handle.pool.storage();
+        for index in 0..self.total_pieces {
+            let available = have.get(index as usize).is_some_and(|bit| *bit);
+            if available && !self.handle.have.contains(index) {
+                self.handle.have.insert(index);
+                self.completed_pieces += 1;
+                self.stats
+                    .left
+                    .fetch_sub(storage.layout().piece_length(index), Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Hash the data on disk, the pieces which match `piece_hashes` are available
+    pub async fn recheck(&mut self, piece_hashes: &[[u8; 20]]) -> Result<Recheck> {
+        let recheck = recheck::recheck(&self.handle.pool, piece_hashes).await?;
+        self.seed(&recheck.have());
+        Ok(recheck)
     }
 
     pub fn listen_for_pieces(mut self) -> JoinHandle<()> {
-        let handle = task::spawn(async move {
-            while let Some(piece) = self.receive_pieces.recv().await {
-                let piece_data = piece.blocks.iter().fold(vec![], |mut acc, blk| {
-                    acc.extend_from_slice(&blk.data);
-                    acc
-                });
-                match self
-                    .file
-                    .write_all_at(&piece_data, (piece.index as u64) * self.piece_length)
-                {
-                    Err(e) => println!("Some err piece #{}", piece.index),
-                    Ok(_) => {
-                        self.completed_pieces += 1;
-                        println!(
-                            "Downloaded:- {:.9}% {} out of {}",
-                            self.completed_pieces as f32 / self.total_pieces as f32,
-                            piece.index,
-                            self.total_pieces
-                        );
-                        //println!("Wrote piece #{}", piece.index)
+        task::spawn(async move {
+            let mut closed = false;
+            // the pieces which are still being written are waited for
+            while !closed || self.writing > 0 {
+                tokio::select! {
+                    piece = self.receive_pieces.recv(), if !closed => match piece {
+                        Some(piece) => self.write_piece(piece).await,
+                        None => closed = true,
+                    },
+                    Some(written) = self.receive_written.recv() => {
+                        self.writing -= 1;
+                        self.piece_written(written).await;
                     }
-                };
+                }
+            }
+            if let Err(e) = self.handle.pool.flush().await {
+                eprintln!("Flushing the data failed: {}", e);
+            }
+        })
+    }
+
+    /// Queue the piece on the disk threads, waiting while they are busy
+    async fn write_piece(&mut self, piece: DownloadedPiece) {
+        let piece_data = piece.blocks.iter().fold(vec![], |mut acc, blk| {
+            acc.extend_from_slice(&blk.data);
+            acc
+        });
+        let index = piece.index;
+        let length = piece_data.len() as u64;
+        let receiver = match self.handle.pool.write(index, piece_data).await {
+            Ok(receiver) => receiver,
+            Err(e) => {
+                println!("Some err piece #{}: {}", index, e);
+                return;
             }
+        };
+        self.writing += 1;
+        let send_written = self.send_written.clone();
+        task::spawn(async move {
+            let result = receiver.await.unwrap_or_else(|e| Err(e.into()));
+            send_written
+                .send(WrittenPiece {
+                    index,
+                    length,
+                    result,
+                })
+                .unwrap_or(());
         });
-        handle
+    }
+
+    async fn piece_written(&mut self, written: WrittenPiece) {
+        match written.result {
+            Err(e) => println!("Some err piece #{}: {}", written.index, e),
+            Ok(_) => {
+                self.handle.have.insert(written.index);
+                // no peers might be connected
+                self.handle.haves.send(written.index).unwrap_or(0);
+                self.completed_pieces += 1;
+                self.stats.left.fetch_sub(written.length, Ordering::Relaxed);
+                println!(
+                    "Downloaded:- {:.9}% {} out of {}",
+                    self.completed_pieces as f32 / self.total_pieces as f32,
+                    written.index,
+                    self.total_pieces
+                );
+                //println!("Wrote piece #{}", written.index)
+                if self.completed_pieces == self.total_pieces {
+                    if let Err(e) = self.complete().await {
+                        eprintln!("Finishing the download failed: {}", e);
+                    }
+                    if self.send_to_announcer.send(Event::Completed).is_err() {
+                        eprintln!("Receiver Dropped");
+                    }
+                }
+            }
+        };
+    }
+
+    /// Make sure all the data reaches the disk, and move it to its final place
+    async fn complete(&mut self) -> Result<()> {
+        self.handle.pool.flush().await?;
+        if let Some(dir) = self.move_to.clone() {
+            self.handle.pool.move_to(dir.clone()).await?;
+            println!("Moved the files to {}", dir.display());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{Layout, MemoryStorage};
+    use ring::digest;
+    use std::convert::TryInto;
+    use std::sync::atomic::AtomicU64;
+    use tokio::sync::mpsc;
+
+    fn disk_manager(storage: MemoryStorage, stats: Arc<TransferStats>) -> DiskManager {
+        let (_tx, rx) = mpsc::unbounded_channel();
+        let (send_to_announcer, _receive_events) = mpsc::unbounded_channel();
+        DiskManager::new(rx, Box::new(storage), stats, send_to_announcer, None)
+    }
+
+    fn stats(left: u64) -> Arc<TransferStats> {
+        Arc::new(TransferStats {
+            uploaded: AtomicU64::new(0),
+            downloaded: AtomicU64::new(0),
+            left: AtomicU64::new(left),
+        })
+    }
+
+    #[test]
+    fn test_lacks_any() {
+        let have = HavePieces::new(3);
+        have.insert(1);
+        let mut pieces: BitVec<Msb0, u8> = BitVec::repeat(false, 3);
+        assert!(!have.lacks_any(&pieces));
+        pieces.set(1, true);
+        assert!(!have.lacks_any(&pieces));
+        pieces.set(2, true);
+        assert!(have.lacks_any(&pieces));
+    }
+
+    #[tokio::test]
+    async fn test_read_block() -> Result<()> {
+        let storage = MemoryStorage::new(Layout::new(vec![(PathBuf::from("a"), 10)], 4));
+        storage.write_at(&[0, 1, 2, 3, 4, 5, 6, 7, 8, 9], 0)?;
+        let disk_manager = disk_manager(storage, stats(10));
+
+        // blocks are only served from pieces we have
+        let block = BlockInfo {
+            piece_index: 1,
+            begin: 1,
+            length: 3,
+        };
+        let disk = disk_manager.handle();
+        assert!(disk.read_block(&block).await.is_err());
+        disk.have.insert(1);
+        assert_eq!(disk.read_block(&block).await?, vec![5, 6, 7]);
+        // the final piece is shorter than the others
+        disk.have.insert(2);
+        let block = BlockInfo {
+            piece_index: 2,
+            begin: 0,
+            length: 3,
+        };
+        assert!(disk.read_block(&block).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_recheck() -> Result<()> {
+        // data left over from an earlier run, the second piece is corrupt and the third was never written
+        let storage = MemoryStorage::new(Layout::new(vec![(PathBuf::from("a"), 10)], 4));
+        storage.write_at(&[0, 1, 2, 3, 4, 5, 6, 0], 0)?;
+        let stats = stats(10);
+        let mut disk_manager = disk_manager(storage, stats.clone());
+
+        let hash = |data: &[u8]| -> [u8; 20] {
+            let sha1 = digest::digest(&digest::SHA1_FOR_LEGACY_USE_ONLY, data);
+            sha1.as_ref().try_into().unwrap()
+        };
+        let piece_hashes = [hash(&[0, 1, 2, 3]), hash(&[4, 5, 6, 7]), hash(&[8, 9])];
+        disk_manager.recheck(&piece_hashes).await?;
+        let have = disk_manager.handle().have;
+        assert!(have.contains(0));
+        assert!(!have.contains(1));
+        assert!(!have.contains(2));
+        assert_eq!(stats.left.load(Ordering::Relaxed), 6);
+        Ok(())
     }
 }
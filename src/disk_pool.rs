@@ -0,0 +1,185 @@
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, RwLock, RwLockReadGuard};
+use std::thread;
+use tokio::sync::{mpsc, oneshot};
+
+use crate::storage::Storage;
+use crate::Result;
+
+/// jobs beyond this make the ones submitting them wait, so a slow disk slows down the peers
+const QUEUE_DEPTH: usize = 64;
+
+/// Work for the disk threads, the result is sent back through the transmitter
+#[derive(Debug)]
+enum Job {
+    Read {
+        index: u32,
+        begin: u32,
+        length: u32,
+        transmitter: oneshot::Sender<Result<Vec<u8>>>,
+    },
+    Write {
+        index: u32,
+        data: Vec<u8>,
+        transmitter: oneshot::Sender<Result<()>>,
+    },
+    Hash {
+        index: u32,
+        transmitter: oneshot::Sender<Result<[u8; 20]>>,
+    },
+    Flush {
+        transmitter: oneshot::Sender<Result<()>>,
+    },
+    /// waits for the jobs which are running, none start until the files are moved
+    Move {
+        dir: PathBuf,
+        transmitter: oneshot::Sender<Result<()>>,
+    },
+}
+
+/// Threads doing the blocking disk I/O, so that it never stalls the async runtime
+#[derive(Debug, Clone)]
+pub struct DiskPool {
+    send_jobs: mpsc::Sender<Job>,
+    storage: Arc<RwLock<Box<dyn Storage>>>,
+}
+
+impl DiskPool {
+    /// Start a thread per core, they stop once every handle to the pool is dropped
+    pub fn new(storage: Box<dyn Storage>) -> Self {
+        let threads = thread::available_parallelism().map_or(1, |threads| threads.get());
+        let (send_jobs, receive_jobs) = mpsc::channel(QUEUE_DEPTH);
+        let receive_jobs = Arc::new(Mutex::new(receive_jobs));
+        let storage = Arc::new(RwLock::new(storage));
+        for _ in 0..threads {
+            let receive_jobs = receive_jobs.clone();
+            let storage = storage.clone();
+            thread::spawn(move || work(&storage, &receive_jobs));
+        }
+        Self { send_jobs, storage }
+    }
+
+    /// Whether the queue is full, the peers hold back on requesting blocks until it drains
+    pub fn is_congested(&self) -> bool {
+        self.send_jobs.capacity() == 0
+    }
+
+    /// The storage the threads work on, for what doesn't need to go through the queue
+    pub fn storage(&self) -> RwLockReadGuard<'_, Box<dyn Storage>> {
+        self.storage.read().unwrap()
+    }
+
+    /// Queue the job, waiting while the queue is full. The receiver gets the result once it is done.
+    async fn queue<T>(
+        &self,
+        job: impl FnOnce(oneshot::Sender<Result<T>>) -> Job,
+    ) -> Result<oneshot::Receiver<Result<T>>> {
+        let (tx, rx) = oneshot::channel();
+        if self.send_jobs.send(job(tx)).await.is_err() {
+            Err("Disk threads stopped")?;
+        }
+        Ok(rx)
+    }
+
+    pub async fn read(&self, index: u32, begin: u32, length: u32) -> Result<Vec<u8>> {
+        let job = |transmitter| Job::Read {
+            index,
+            begin,
+            length,
+            transmitter,
+        };
+        self.queue(job).await?.await?
+    }
+
+    /// Queue writing a whole piece, the receiver gets the result once it is written
+    pub async fn write(&self, index: u32, data: Vec<u8>) -> Result<oneshot::Receiver<Result<()>>> {
+        let job = |transmitter| Job::Write {
+            index,
+            data,
+            transmitter,
+        };
+        self.queue(job).await
+    }
+
+    /// Queue hashing a piece, the receiver gets its SHA-1
+    pub async fn hash(&self, index: u32) -> Result<oneshot::Receiver<Result<[u8; 20]>>> {
+        self.queue(|transmitter| Job::Hash { index, transmitter })
+            .await
+    }
+
+    pub async fn flush(&self) -> Result<()> {
+        self.queue(|transmitter| Job::Flush { transmitter })
+            .await?
+            .await?
+    }
+
+    pub async fn move_to(&self, dir: PathBuf) -> Result<()> {
+        self.queue(|transmitter| Job::Move { dir, transmitter })
+            .await?
+            .await?
+    }
+}
+
+/// Run the jobs until the queue is closed
+fn work(storage: &RwLock<Box<dyn Storage>>, receive_jobs: &Mutex<mpsc::Receiver<Job>>) {
+    loop {
+        // the lock is released before the job runs, so that the other threads can take the next ones
+        let job = receive_jobs.lock().unwrap().blocking_recv();
+        // whoever submitted the job may not be waiting for it anymore
+        match job {
+            Some(Job::Read {
+                index,
+                begin,
+                length,
+                transmitter,
+            }) => {
+                let data = storage.read().unwrap().read_block(index, begin, length);
+                transmitter.send(data).unwrap_or(());
+            }
+            Some(Job::Write {
+                index,
+                data,
+                transmitter,
+            }) => {
+                let written = storage.read().unwrap().write_piece(index, data);
+                transmitter.send(written).unwrap_or(());
+            }
+            Some(Job::Hash { index, transmitter }) => {
+                let hash = storage.read().unwrap().hash_piece(index);
+                transmitter.send(hash).unwrap_or(());
+            }
+            Some(Job::Flush { transmitter }) => {
+                transmitter
+                    .send(storage.read().unwrap().flush())
+                    .unwrap_or(());
+            }
+            Some(Job::Move { dir, transmitter }) => {
+                let moved = storage.write().unwrap().move_to(&dir);
+                transmitter.send(moved).unwrap_or(());
+            }
+            None => break,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{Layout, MemoryStorage};
+
+    #[tokio::test]
+    async fn test_disk_pool() -> Result<()> {
+        let layout = Layout::new(vec![(PathBuf::from("a"), 10)], 4);
+        let pool = DiskPool::new(Box::new(MemoryStorage::new(layout)));
+        // the writes run in parallel, each is waited for on its own
+        let first = pool.write(0, vec![0, 1, 2, 3]).await?;
+        let last = pool.write(2, vec![8, 9]).await?;
+        first.await??;
+        last.await??;
+        assert_eq!(pool.read(2, 1, 1).await?, vec![9]);
+        assert!(pool.read(2, 0, 3).await.is_err());
+        assert_eq!(pool.hash(0).await?.await??, pool.storage().hash_piece(0)?);
+        pool.flush().await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,240 @@
+use serde_bencode::{de, ser};
+use serde_bytes::ByteBuf;
+use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+
+use crate::{message::Msg, Result};
+
+/// reserved bit which advertises support for the extension protocol
+pub const EXTENSION_PROTOCOL_BIT: (usize, u8) = (5, 0x10);
+/// number of outstanding requests we allow a peer to have
+pub const MAX_REQUESTS: u32 = 250;
+
+/// Whether the reserved bytes of a handshake have the extension protocol bit set
+pub fn supports_extensions(reserved_bytes: &[u8]) -> bool {
+    let (byte, bit) = EXTENSION_PROTOCOL_BIT;
+    reserved_bytes[byte] & bit != 0
+}
+
+/// Payload of the extended message with id 0, see BEP 10
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct ExtendedHandshake {
+    /// extension names mapped to the extended message ids of the sender, 0 disables an extension
+    #[serde(default)]
+    pub m: HashMap<String, i64>,
+    /// client name and version
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub v: Option<ByteBuf>,
+    /// tcp port the sender listens on
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub p: Option<u16>,
+    /// number of outstanding requests the sender allows
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reqq: Option<u32>,
+    /// compact ip address of the receiver as seen by the sender
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub yourip: Option<ByteBuf>,
+    /// size of the info dictionary, see BEP 9
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metadata_size: Option<usize>,
+}
+
+/// An extension which is negotiated in the extended handshake
+pub trait Extension: Send {
+    /// key of the extension in the `m` dictionary
+    fn name(&self) -> &'static str;
+
+    /// Add extension specific keys to our extended handshake
+    fn extend_handshake(&self, _handshake: &mut ExtendedHandshake) {}
+
+    /// Called once the extended handshake of the peer arrives
+    fn on_handshake(&mut self, _handshake: &ExtendedHandshake) -> Result<()> {
+        Ok(())
+    }
+
+    /// Handle a message of the extension and return the payloads to send back
+    fn on_message(&mut self, payload: &[u8]) -> Result<Vec<Vec<u8>>>;
+
+    /// Called periodically, returns the payloads to send to the peer
+    fn on_tick(&mut self) -> Result<Vec<Vec<u8>>> {
+        Ok(vec![])
+    }
+}
+
+/// The extensions of a single peer connection.
+/// Our extended message id for an extension is its position in the registry plus one.
+pub struct ExtensionRegistry {
+    extensions: Vec<Box<dyn Extension>>,
+    /// extended message ids the peer assigned to the extensions it supports
+    peer_ids: HashMap<String, u8>,
+    /// extended handshake of the peer, `None` until it arrives
+    pub peer_handshake: Option<ExtendedHandshake>,
+}
+
+impl fmt::Debug for ExtensionRegistry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let names: Vec<&str> = self.extensions.iter().map(|e| e.name()).collect();
+        f.debug_struct("ExtensionRegistry")
+            .field("extensions", &names)
+            .field("peer_ids", &self.peer_ids)
+            .finish()
+    }
+}
+
+impl ExtensionRegistry {
+    pub fn new(extensions: Vec<Box<dyn Extension>>) -> Self {
+        Self {
+            extensions,
+            peer_ids: HashMap::new(),
+            peer_handshake: None,
+        }
+    }
+
+    /// Extended handshake advertising every registered extension
+    pub fn handshake(&self, port: Option<u16>, peer_ip: Option<IpAddr>) -> Result<Msg> {
+        let mut handshake = ExtendedHandshake {
+            m: self
+                .extensions
+                .iter()
+                .enumerate()
+                .map(|(index, extension)| (extension.name().to_string(), index as i64 + 1))
+                .collect(),
+            v: Some(ByteBuf::from(
+                format!("bitr {}", env!("CARGO_PKG_VERSION")).into_bytes(),
+            )),
+            p: port,
+            reqq: Some(MAX_REQUESTS),
+            yourip: peer_ip.map(|ip| match ip {
+                IpAddr::V4(ip) => ByteBuf::from(ip.octets().to_vec()),
+                IpAddr::V6(ip) => ByteBuf::from(ip.octets().to_vec()),
+            }),
+            metadata_size: None,
+        };
+        for extension in &self.extensions {
+            extension.extend_handshake(&mut handshake);
+        }
+        Ok(Msg::Extended {
+            id: 0,
+            payload: ser::to_bytes(&handshake)?,
+        })
+    }
+
+    /// Dispatch an extended message and return the messages which have to be sent back
+    pub fn on_message(&mut self, id: u8, payload: &[u8]) -> Result<Vec<Msg>> {
+        if id == 0 {
+            let handshake = de::from_bytes::<ExtendedHandshake>(payload)?;
+            // the handshake can be sent again to update the ids, 0 disables an extension
+            for (name, &id) in &handshake.m {
+                match id {
+                    1..=255 => self.peer_ids.insert(name.clone(), id as u8),
+                    _ => self.peer_ids.remove(name),
+                };
+            }
+            for extension in self.extensions.iter_mut() {
+                extension.on_handshake(&handshake)?;
+            }
+            self.peer_handshake = Some(handshake);
+            return Ok(vec![]);
+        }
+
+        let extension = match self.extensions.get_mut(id as usize - 1) {
+            Some(extension) => extension,
+            None => Err(format!("Unknown extended message id: {}", id))?,
+        };
+        let responses = extension.on_message(payload)?;
+        // the peer can't receive messages of extensions it didn't announce
+        let peer_id = match self.peer_ids.get(extension.name()) {
+            Some(&peer_id) => peer_id,
+            None => return Ok(vec![]),
+        };
+        Ok(responses
+            .into_iter()
+            .map(|payload| Msg::Extended {
+                id: peer_id,
+                payload,
+            })
+            .collect())
+    }
+
+    /// Collect the periodic messages of the extensions which the peer supports
+    pub fn tick(&mut self) -> Result<Vec<Msg>> {
+        let mut msgs = vec![];
+        for extension in self.extensions.iter_mut() {
+            let peer_id = match self.peer_ids.get(extension.name()) {
+                Some(&peer_id) => peer_id,
+                None => continue,
+            };
+            for payload in extension.on_tick()? {
+                msgs.push(Msg::Extended {
+                    id: peer_id,
+                    payload,
+                });
+            }
+        }
+        Ok(msgs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Echoes every message back to the peer
+    struct Echo;
+
+    impl Extension for Echo {
+        fn name(&self) -> &'static str {
+            "echo"
+        }
+        fn on_message(&mut self, payload: &[u8]) -> Result<Vec<Vec<u8>>> {
+            Ok(vec![payload.to_vec()])
+        }
+    }
+
+    #[test]
+    fn test_handshake() -> Result<()> {
+        let registry = ExtensionRegistry::new(vec![Box::new(Echo)]);
+        let payload = match registry.handshake(Some(6881), Some("10.0.0.2".parse()?))? {
+            Msg::Extended { id: 0, payload } => payload,
+            msg => panic!("Unexpected message {:?}", msg),
+        };
+        assert_eq!(
+            payload,
+            format!(
+                "d1:md4:echoi1ee1:pi6881e4:reqqi250e1:v{}:bitr {}6:yourip4:\x0a\x00\x00\x02e",
+                5 + env!("CARGO_PKG_VERSION").len(),
+                env!("CARGO_PKG_VERSION")
+            )
+            .as_bytes()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_dispatch() -> Result<()> {
+        let mut registry = ExtensionRegistry::new(vec![Box::new(Echo)]);
+        // nothing can be sent back before the peer tells us its id for the extension
+        assert!(registry.on_message(1, b"ping")?.is_empty());
+
+        registry.on_message(0, b"d1:md4:echoi7e6:ut_pexi0ee4:reqqi64ee")?;
+        assert_eq!(registry.peer_handshake.as_ref().unwrap().reqq, Some(64));
+        match registry.on_message(1, b"ping")?.as_slice() {
+            [Msg::Extended { id: 7, payload }] => assert_eq!(payload, b"ping"),
+            msgs => panic!("Unexpected messages {:?}", msgs),
+        }
+        assert!(registry.on_message(2, b"ping").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_reserved_bit() {
+        assert!(supports_extensions(&[0, 0, 0, 0, 0, 0x10, 0, 0]));
+        assert!(!supports_extensions(&[0; 8]));
+    }
+}
@@ -0,0 +1,83 @@
+use ring::digest;
+use std::collections::HashSet;
+use std::net::IpAddr;
+
+/// reserved bit which advertises support for the fast extension, see BEP 6
+pub const FAST_EXTENSION_BIT: (usize, u8) = (7, 0x04);
+/// number of pieces a peer may request from us while it is choked
+pub const ALLOWED_FAST_COUNT: u32 = 10;
+
+/// Whether the reserved bytes of a handshake have the fast extension bit set
+pub fn supports_fast_extension(reserved_bytes: &[u8]) -> bool {
+    let (byte, bit) = FAST_EXTENSION_BIT;
+    reserved_bytes[byte] & bit != 0
+}
+
+/// Canonical allowed fast set of a peer, derived from its ip so that it doesn't change across reconnects.
+/// Only defined for IPv4 peers.
+pub fn allowed_fast_set(
+    ip: IpAddr,
+    info_hash: &[u8],
+    total_pieces: u32,
+    count: u32,
+) -> HashSet<u32> {
+    let mut allowed_fast = HashSet::new();
+    let ip = match ip {
+        IpAddr::V4(ip) => ip,
+        IpAddr::V6(_) => return allowed_fast,
+    };
+    let count = count.min(total_pieces);
+
+    // peers in the same /24 network get the same set
+    let mut x = (u32::from(ip) & 0xFFFFFF00).to_be_bytes().to_vec();
+    x.extend_from_slice(info_hash);
+    while (allowed_fast.len() as u32) < count {
+        x = digest::digest(&digest::SHA1_FOR_LEGACY_USE_ONLY, &x)
+            .as_ref()
+            .to_vec();
+        for chunk in x.chunks_exact(4) {
+            if allowed_fast.len() as u32 == count {
+                break;
+            }
+            let y = u32::from_be_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+            allowed_fast.insert(y % total_pieces);
+        }
+    }
+    allowed_fast
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_allowed_fast_set() {
+        // example from BEP 6
+        let ip = "80.4.4.200".parse().unwrap();
+        let info_hash = [0xaa; 20];
+        let seven = allowed_fast_set(ip, &info_hash, 1313, 7);
+        assert_eq!(
+            seven,
+            [1059, 431, 808, 1217, 287, 376, 1188]
+                .iter()
+                .copied()
+                .collect()
+        );
+        let nine = allowed_fast_set(ip, &info_hash, 1313, 9);
+        assert_eq!(
+            nine,
+            [1059, 431, 808, 1217, 287, 376, 1188, 353, 508]
+                .iter()
+                .copied()
+                .collect()
+        );
+        // can't allow more pieces than the torrent has
+        assert_eq!(allowed_fast_set(ip, &info_hash, 3, 10).len(), 3);
+    }
+
+    #[test]
+    fn test_reserved_bit() {
+        assert!(supports_fast_extension(&[0, 0, 0, 0, 0, 0, 0, 0x04]));
+        assert!(!supports_fast_extension(&[0, 0, 0, 0, 0, 0x10, 0, 0]));
+    }
+}
@@ -1,51 +1,304 @@
 use std::error::Error;
+use std::path::PathBuf;
 use tokio::sync::mpsc;
 
 #[macro_use]
 extern crate serde_derive;
 
+mod announcer;
+mod bencode;
+mod choker;
+mod dht;
 mod disk;
+mod disk_pool;
+mod extension;
+mod fast;
+mod listener;
+mod magnet;
 mod manager;
 mod message;
+mod metadata;
 mod peer;
+mod pex;
+mod pipeline;
+mod recheck;
+mod resume;
+mod storage;
 mod torrent;
 mod tracker;
 mod utils;
 
+use choker::{Choker, ChokerCommand};
+use disk_pool::DiskPool;
+use listener::IncomingPeer;
 use manager::{Command, DownloadedPiece, Manager};
+use recheck::{PieceCheck, Recheck};
+use storage::{FileStorage, Layout, StorageKind};
+use torrent::Torrent;
+use tracker::{Event, Tracker, TrackerPeer};
+
+/// size of the block cache in MiB unless it is given
+const DEFAULT_CACHE_SIZE: usize = 64;
 
 // create an alias for the result type
 pub type Result<T> = std::result::Result<T, Box<dyn Error + Send + Sync>>;
 
+const USAGE: &str = "Usage:
+    bitr <path to torrent file or magnet link> [--recheck] [--storage file|memory|mmap] [--cache <MiB>] [--move-to <dir>]
+    bitr scrape <path to torrent file>...
+    bitr verify <path to torrent file>...";
+
 pub async fn run() -> Result<()> {
-    // path to the torrent file
-    let file_path = std::env::args()
-        .nth(1)
-        .ok_or("path to torrent file is missing\nUsage: bitr <path to torrent file>")?;
+    let mut args = std::env::args().skip(1);
+    let command = args
+        .next()
+        .ok_or(format!("path to torrent file is missing\n{}", USAGE))?;
+
+    match command.as_str() {
+        "scrape" => {
+            let paths: Vec<String> = args.collect();
+            if paths.is_empty() {
+                Err(format!("path to torrent file is missing\n{}", USAGE))?;
+            }
+            scrape(paths).await
+        }
+        "verify" => {
+            let paths: Vec<String> = args.collect();
+            if paths.is_empty() {
+                Err(format!("path to torrent file is missing\n{}", USAGE))?;
+            }
+            verify(paths).await
+        }
+        // path to the torrent file or a magnet link
+        _ => download(command, DownloadOptions::parse(args)?).await,
+    }
+}
+
+/// Print the swarm statistics from every tracker of the torrents
+async fn scrape(paths: Vec<String>) -> Result<()> {
+    // scrape each tracker only once for all the torrents it knows about
+    let torrents = paths
+        .iter()
+        .map(|path| Torrent::new(&PathBuf::from(path)))
+        .collect::<Result<Vec<Torrent>>>()?;
+    // tracker url along with all the torrents it knows about
+    let mut trackers: Vec<(String, Vec<&Torrent>)> = vec![];
+    for torrent in &torrents {
+        for url in torrent.tracker_tiers()?.into_iter().flatten() {
+            match trackers
+                .iter_mut()
+                .find(|(tracker_url, _)| *tracker_url == url)
+            {
+                Some((_, torrents)) => torrents.push(torrent),
+                None => trackers.push((url, vec![torrent])),
+            }
+        }
+    }
+
+    for (url, torrents) in trackers {
+        println!("{}", url);
+        let info_hashes: Vec<Vec<u8>> = torrents.iter().map(|t| t.info_hash.clone()).collect();
+        match Tracker::new(url).scrape(&info_hashes).await {
+            Ok(stats) => {
+                for torrent in torrents {
+                    let name = &torrent.info.name;
+                    match stats.get(&torrent.info_hash) {
+                        Some(stats) => println!(
+                            "\t{}\tseeders: {}\tleechers: {}\tcompleted: {}",
+                            name, stats.complete, stats.incomplete, stats.downloaded
+                        ),
+                        None => println!("\t{}\tnot known to the tracker", name),
+                    }
+                }
+            }
+            Err(e) => println!("\terror: {}", e),
+        }
+    }
+    Ok(())
+}
+
+/// Check the data of the torrents on disk against their piece hashes
+async fn verify(paths: Vec<String>) -> Result<()> {
+    for path in paths {
+        let torrent = Torrent::new(&PathBuf::from(path))?;
+        let info = &torrent.info;
+        // nothing is created for the files which are missing
+        let storage = FileStorage::open(Layout::new(info.file_layout()?, info.piece_length));
+        let pool = DiskPool::new(Box::new(storage));
+        println!("{}", info.name);
+        print_recheck(&recheck::recheck(&pool, &info.piece_hashes()).await?);
+    }
+    Ok(())
+}
+
+/// Print how many pieces checked out, along with the files which didn't
+fn print_recheck(recheck: &Recheck) {
+    println!(
+        "\tvalid: {}\tmissing: {}\tcorrupt: {}",
+        recheck.count(PieceCheck::Valid),
+        recheck.count(PieceCheck::Missing),
+        recheck.count(PieceCheck::Corrupt)
+    );
+    let corrupt = recheck.indexes(PieceCheck::Corrupt);
+    if !corrupt.is_empty() {
+        println!("\tcorrupt pieces: {:?}", corrupt);
+    }
+    for file in &recheck.files {
+        if !file.exists {
+            println!("\t{}\tmissing", file.path.display());
+        } else if file.bad_pieces > 0 {
+            println!(
+                "\t{}\t{} of {} pieces incomplete",
+                file.path.display(),
+                file.bad_pieces,
+                file.total_pieces
+            );
+        }
+    }
+}
+
+/// Flags of the download command
+#[derive(Debug)]
+struct DownloadOptions {
+    /// hash the data on disk even if the resume state is current
+    recheck: bool,
+    storage: StorageKind,
+    /// bytes of pieces kept in memory, none are with 0
+    cache_size: usize,
+    /// where the files go once the download is complete
+    move_to: Option<PathBuf>,
+}
+
+impl DownloadOptions {
+    fn parse(mut args: impl Iterator<Item = String>) -> Result<Self> {
+        let mut options = Self {
+            recheck: false,
+            storage: StorageKind::default(),
+            cache_size: DEFAULT_CACHE_SIZE * 1024 * 1024,
+            move_to: None,
+        };
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--recheck" => options.recheck = true,
+                "--storage" => {
+                    let storage = args.next().ok_or("storage is missing")?;
+                    options.storage = storage.parse()?;
+                }
+                "--cache" => {
+                    let size: usize = args.next().ok_or("cache size is missing")?.parse()?;
+                    options.cache_size = size * 1024 * 1024;
+                }
+                "--move-to" => {
+                    let dir = args.next().ok_or("directory is missing")?;
+                    options.move_to = Some(PathBuf::from(dir));
+                }
+                _ => Err(format!("unknown option {}\n{}", arg, USAGE))?,
+            }
+        }
+        Ok(options)
+    }
+}
 
-    let manager = Manager::new(file_path)?;
-    // send request to tracker to get the list of peers
-    let res = manager.send_tracker_request()?;
+async fn download(source: String, options: DownloadOptions) -> Result<()> {
+    let mut manager = if source.starts_with("magnet:") {
+        Manager::from_magnet(&source).await?
+    } else {
+        Manager::new(source)?
+    };
+    manager.start_dht().await;
+    let stats = manager.transfer_stats();
 
     // create mpsc channel for communication between piece picker and all peers
     let (send_to_manager, receive_from_peers) = mpsc::unbounded_channel::<Command>();
     // create mpsc channel for communication between piece picker and disk manager
     let (send_to_disk_manager, receive_pieces) = mpsc::unbounded_channel::<DownloadedPiece>();
-    // spawn a new tokio task for each peer
-    let handles = manager.connect_to_peers(res, send_to_manager);
-
-    let mut piece_picker = manager.spawn_piece_picker(send_to_disk_manager);
+    // create mpsc channel for passing the peers received from the tracker
+    let (send_peers, receive_peers) = mpsc::unbounded_channel::<Vec<TrackerPeer>>();
+    send_peers.send(manager.initial_peers())?;
+    // create mpsc channel for the connections peers open to us
+    let (send_incoming, receive_incoming) = mpsc::unbounded_channel::<IncomingPeer>();
+    // create mpsc channel for lifecycle events which have to be reported to the tracker
+    let (send_to_announcer, receive_events) = mpsc::unbounded_channel::<Event>();
 
-    let disk_manager = manager.spawn_disk_manager(receive_pieces)?;
+    // the peers read the blocks they serve through the disk manager
+    let mut disk_manager = manager.spawn_disk_manager(
+        receive_pieces,
+        options.storage,
+        options.cache_size,
+        options.move_to,
+        stats.clone(),
+        send_to_announcer.clone(),
+    )?;
+    // nothing is left over from an earlier run when the data is kept in memory
+    let resume = match options.storage.is_persistent() {
+        true => Some(manager.resume_file(disk_manager.handle())?),
+        false => None,
+    };
+    // continue from the saved state, or from whatever data is on disk if it is stale
+    let partial = match resume.as_ref().map(|resume| resume.load()) {
+        Some(Some(state)) if !options.recheck => {
+            disk_manager.seed(&state.have());
+            state.partial
+        }
+        Some(_) => {
+            println!("Checking existing data");
+            print_recheck(&disk_manager.recheck(&manager.piece_hashes()).await?);
+            vec![]
+        }
+        None => vec![],
+    };
+    let disk = disk_manager.handle();
+    let pool = disk.pool.clone();
     let disk_handle = disk_manager.listen_for_pieces();
-    // listen on mpsc channel for different commands from the peers
-    piece_picker.listen_to_commands(receive_from_peers).await;
 
-    for handle in handles {
-        handle.await?;
+    // create mpsc channel for the peers to register with the choker
+    let (send_to_choker, receive_choker_commands) = mpsc::unbounded_channel::<ChokerCommand>();
+    let _choker_handle = Choker::new(receive_choker_commands, disk.have.clone()).listen();
+
+    // without the listener only outgoing connections are made, and no port is advertised
+    let _listener_handle = match manager.spawn_listener(send_incoming).await {
+        Ok(handle) => Some(handle),
+        Err(e) => {
+            eprintln!("Listening for incoming connections failed: {}", e);
+            None
+        }
+    };
+    let mut piece_picker = manager.spawn_piece_picker(send_to_disk_manager, stats.clone());
+    if let Some(resume) = resume {
+        piece_picker.resume(&disk.have.bitfield(), partial, resume);
+    }
+
+    // spawn a new tokio task for each peer
+    let _peers_handle = manager.connect_to_peers(
+        receive_peers,
+        receive_incoming,
+        send_peers.clone(),
+        send_to_manager,
+        disk,
+        stats.clone(),
+        send_to_choker,
+        piece_picker.cancels(),
+    );
+    // look for peers on the DHT as well
+    let _dht_handle = manager.spawn_dht_announcer(send_peers.clone())?;
+    // announce to the tracker periodically to get the list of peers
+    let announcer = manager.spawn_announcer(stats.clone(), send_peers, receive_events)?;
+    let announcer_handle = announcer.listen_for_events();
+
+    tokio::select! {
+        // listen on mpsc channel for different commands from the peers
+        _ = piece_picker.listen_to_commands(receive_from_peers) => {}
+        res = tokio::signal::ctrl_c() => res?,
     }
 
-    disk_handle.await?;
+    // let the tracker know that we are leaving the swarm
+    send_to_announcer.send(Event::Stopped)?;
+    piece_picker.close(disk_handle).await?;
+    announcer_handle.await?;
+    if let Some(stats) = pool.storage().cache_stats() {
+        println!("Cache hits: {}\tmisses: {}", stats.hits, stats.misses);
+    }
+    manager.save_dht()?;
 
     Ok(())
 }
@@ -0,0 +1,134 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::AsyncReadExt;
+use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::task::JoinHandle;
+use tokio::time;
+
+use crate::Result;
+
+/// peers which don't send their handshake within this time are dropped
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A connection opened by a peer, along with the handshake it sent
+#[derive(Debug)]
+pub struct IncomingPeer {
+    pub stream: TcpStream,
+    pub addr: SocketAddr,
+    pub handshake: [u8; 68],
+}
+
+/// Accepts connections from peers and routes them to the torrent their handshake asks for
+#[derive(Debug)]
+pub struct Listener {
+    listener: TcpListener,
+    torrents: HashMap<Vec<u8>, UnboundedSender<IncomingPeer>>,
+}
+
+impl Listener {
+    pub async fn bind<A: ToSocketAddrs>(addr: A) -> Result<Listener> {
+        Ok(Listener {
+            listener: TcpListener::bind(addr).await?,
+            torrents: HashMap::new(),
+        })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    /// Connections for `info_hash` are sent over `send_incoming`
+    pub fn add_torrent(
+        &mut self,
+        info_hash: Vec<u8>,
+        send_incoming: UnboundedSender<IncomingPeer>,
+    ) {
+        self.torrents.insert(info_hash, send_incoming);
+    }
+
+    pub fn listen(self) -> JoinHandle<()> {
+        let Listener { listener, torrents } = self;
+        let torrents = Arc::new(torrents);
+        tokio::spawn(async move {
+            loop {
+                let (stream, addr) = match listener.accept().await {
+                    Ok(res) => res,
+                    Err(e) => {
+                        eprintln!("Accepting a connection failed: {}", e);
+                        continue;
+                    }
+                };
+                let torrents = torrents.clone();
+                // a slow handshake shouldn't hold up the other connections
+                tokio::spawn(async move {
+                    if let Err(e) = route(stream, addr, &torrents).await {
+                        eprintln!("Incoming connection from {} failed: {}", addr, e);
+                    }
+                });
+            }
+        })
+    }
+}
+
+async fn route(
+    mut stream: TcpStream,
+    addr: SocketAddr,
+    torrents: &HashMap<Vec<u8>, UnboundedSender<IncomingPeer>>,
+) -> Result<()> {
+    let mut handshake = [0; 68];
+    time::timeout(HANDSHAKE_TIMEOUT, stream.read_exact(&mut handshake)).await??;
+    if handshake[..20] != b"\x13BitTorrent protocol"[..] {
+        Err("Not a BitTorrent handshake")?;
+    }
+    let send_incoming = torrents
+        .get(&handshake[28..48])
+        .ok_or("Handshake is for an unknown torrent")?;
+    send_incoming.send(IncomingPeer {
+        stream,
+        addr,
+        handshake,
+    })?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncWriteExt;
+    use tokio::sync::mpsc;
+
+    fn handshake(info_hash: u8) -> Vec<u8> {
+        let mut handshake = b"\x13BitTorrent protocol".to_vec();
+        handshake.extend_from_slice(&[0; 8]);
+        handshake.extend_from_slice(&[info_hash; 20]);
+        handshake.extend_from_slice(&[9; 20]);
+        handshake
+    }
+
+    #[tokio::test]
+    async fn test_routes_by_info_hash() -> Result<()> {
+        let mut listener = Listener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let (send_first, mut receive_first) = mpsc::unbounded_channel();
+        let (send_second, mut receive_second) = mpsc::unbounded_channel();
+        listener.add_torrent(vec![1; 20], send_first);
+        listener.add_torrent(vec![2; 20], send_second);
+        listener.listen();
+
+        let mut unknown = TcpStream::connect(addr).await?;
+        unknown.write_all(&handshake(3)).await?;
+        // the connection is closed without a response
+        assert_eq!(unknown.read(&mut [0; 1]).await?, 0);
+
+        let mut stream = TcpStream::connect(addr).await?;
+        stream.write_all(&handshake(2)).await?;
+        let incoming = receive_second.recv().await.ok_or("No incoming peer")?;
+        assert_eq!(incoming.handshake.to_vec(), handshake(2));
+        assert_eq!(incoming.addr, stream.local_addr()?);
+        assert!(receive_first.try_recv().is_err());
+        Ok(())
+    }
+}
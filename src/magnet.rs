@@ -0,0 +1,150 @@
+use reqwest::Url;
+
+use crate::tracker::TrackerPeer;
+use crate::Result;
+
+/// A parsed `magnet:?xt=urn:btih:...` link, see BEP 9
+#[derive(Debug, PartialEq)]
+pub struct Magnet {
+    pub info_hash: Vec<u8>,
+    /// `dn`, only meant to be shown while the metadata is being fetched
+    pub display_name: Option<String>,
+    /// `tr`
+    pub trackers: Vec<String>,
+    /// `x.pe`
+    pub peers: Vec<TrackerPeer>,
+}
+
+impl Magnet {
+    pub fn parse(uri: &str) -> Result<Magnet> {
+        let url = Url::parse(uri)?;
+        if url.scheme() != "magnet" {
+            Err("Not a magnet link")?;
+        }
+
+        let mut info_hash = None;
+        let mut display_name = None;
+        let mut trackers = vec![];
+        let mut peers = vec![];
+        // query_pairs takes care of the percent-encoding
+        for (key, value) in url.query_pairs() {
+            match key.as_ref() {
+                "xt" => {
+                    // other exact topics like `urn:btmh` belong to v2 torrents
+                    if let Some(hash) = value.strip_prefix("urn:btih:") {
+                        info_hash = Some(decode_info_hash(hash)?);
+                    }
+                }
+                "dn" => display_name = Some(value.into_owned()),
+                "tr" => trackers.push(value.into_owned()),
+                "x.pe" => peers.push(parse_peer(&value)?),
+                _ => {}
+            }
+        }
+
+        Ok(Magnet {
+            info_hash: info_hash.ok_or("Magnet link has no btih info hash")?,
+            display_name,
+            trackers,
+            peers,
+        })
+    }
+}
+
+/// Info hashes are either 40 hex or 32 base32 characters
+fn decode_info_hash(hash: &str) -> Result<Vec<u8>> {
+    if !hash.is_ascii() {
+        Err(format!("Invalid info hash in magnet link: {}", hash))?;
+    }
+    let bytes = match hash.len() {
+        40 => (0..40)
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hash[i..i + 2], 16))
+            .collect::<std::result::Result<Vec<u8>, _>>()?,
+        32 => decode_base32(hash)?,
+        _ => Err(format!("Invalid info hash in magnet link: {}", hash))?,
+    };
+    Ok(bytes)
+}
+
+/// RFC 4648 base32 without padding
+fn decode_base32(input: &str) -> Result<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(input.len() * 5 / 8);
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+    for c in input.bytes() {
+        let value = match c.to_ascii_uppercase() {
+            c @ b'A'..=b'Z' => c - b'A',
+            c @ b'2'..=b'7' => c - b'2' + 26,
+            _ => Err(format!("Invalid base32 character: {}", c as char))?,
+        };
+        buffer = (buffer << 5) | value as u32;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((buffer >> bits) as u8);
+        }
+    }
+    Ok(bytes)
+}
+
+/// `host:port`, IPv6 addresses are enclosed in brackets
+fn parse_peer(peer: &str) -> Result<TrackerPeer> {
+    let (host, port) = peer
+        .rsplit_once(':')
+        .ok_or(format!("Peer address is missing the port: {}", peer))?;
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    Ok(TrackerPeer {
+        ip: host.to_string(),
+        port: port.parse()?,
+        peer_id: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_hex_magnet() -> Result<()> {
+        let magnet = Magnet::parse("magnet:?xt=urn:btih:146EDFF7185C3356C038FC4040AA717B239658B4&dn=file.bin&tr=udp%3A%2F%2Ftracker.example%3A80&tr=https://tracker.example/announce&x.pe=10.0.0.1:6881&x.pe=[::1]:51413")?;
+        assert_eq!(
+            magnet.info_hash,
+            [
+                0x14, 0x6e, 0xdf, 0xf7, 0x18, 0x5c, 0x33, 0x56, 0xc0, 0x38, 0xfc, 0x40, 0x40, 0xaa,
+                0x71, 0x7b, 0x23, 0x96, 0x58, 0xb4
+            ]
+        );
+        assert_eq!(magnet.display_name.as_deref(), Some("file.bin"));
+        assert_eq!(
+            magnet.trackers,
+            vec![
+                "udp://tracker.example:80",
+                "https://tracker.example/announce"
+            ]
+        );
+        assert_eq!(magnet.peers[0].ip, "10.0.0.1");
+        assert_eq!(magnet.peers[1].ip, "::1");
+        assert_eq!(magnet.peers[1].port, 51413);
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_base32_magnet() -> Result<()> {
+        let hex = Magnet::parse("magnet:?xt=urn:btih:146edff7185c3356c038fc4040aa717b239658b4")?;
+        let base32 = Magnet::parse("magnet:?xt=urn:btih:CRXN75YYLQZVNQBY7RAEBKTRPMRZMWFU")?;
+        assert_eq!(hex, base32);
+        assert!(base32.trackers.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid_magnet() {
+        assert!(Magnet::parse("magnet:?dn=missing").is_err());
+        assert!(Magnet::parse("magnet:?xt=urn:btih:1234").is_err());
+        assert!(Magnet::parse(
+            "https://tracker.example/?xt=urn:btih:146edff7185c3356c038fc4040aa717b239658b4"
+        )
+        .is_err());
+    }
+}
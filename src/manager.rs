@@ -1,16 +1,55 @@
 use bitvec::{order::Msb0, prelude::BitVec};
 use ring::digest;
-use std::path::PathBuf;
-use std::{collections::HashMap, u32};
-use std::{convert::TryInto, usize};
+use serde_bytes::ByteBuf;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::convert::TryInto;
+use std::path::{Path, PathBuf};
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, Mutex,
+};
 use tokio::sync::{
-    mpsc::{UnboundedReceiver, UnboundedSender},
+    broadcast,
+    mpsc::{self, UnboundedReceiver, UnboundedSender},
     oneshot,
 };
 use tokio::task::JoinHandle;
+use tokio::time::{self, Duration, Instant};
+
+use crate::choker::ChokerCommand;
+use crate::dht::{self, Dht, NodeId, BOOTSTRAP_NODES};
+use crate::disk::{BlockInfo, DiskHandle, DiskManager};
+use crate::extension::{Extension, ExtensionRegistry};
+use crate::fast::{self, ALLOWED_FAST_COUNT};
+use crate::listener::{IncomingPeer, Listener};
+use crate::peer::{Peer, PeerShared};
+use crate::pex::{ConnectedPeers, UtPex};
+use crate::resume::{PartialBlock, PartialPiece, ResumeFile};
+use crate::storage::{CachedStorage, StorageKind};
+use crate::tracker::{AnnounceRequest, Event, Tracker, TrackerPeer};
+use crate::Result;
+use crate::{announcer::Announcer, torrent::Torrent, utils};
+use crate::{magnet::Magnet, metadata, metadata::UtMetadata};
+
+/// bittorrent port
+pub const PORT: u16 = 6881;
+/// maximum number of simultaneous peer connections
+const MAX_CONNECTIONS: usize = 50;
+/// peers are banned once this many of the pieces they sent blocks of failed the hash check
+const MAX_HASH_FAILURES: u32 = 3;
+/// blocks which haven't arrived within this time are requested from other peers
+pub const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+/// how often the requested blocks are checked for the timeout
+const STALLED_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+/// cancels sent while a peer is busy beyond this are dropped for it
+const CANCEL_CAPACITY: usize = 256;
+/// how often the resume state is saved
+const RESUME_INTERVAL: Duration = Duration::from_secs(60);
+/// file the DHT node id and routing table are kept in between runs
+const DHT_CACHE: &str = ".bitr_dht";
+/// how often the torrent is looked up and announced on the DHT
+const DHT_ANNOUNCE_INTERVAL: Duration = Duration::from_secs(15 * 60);
 
-use crate::{disk::DiskManager, peer::Peer, torrent::Torrent, tracker, utils};
-use crate::{tracker::TrackerResponse, Result};
 // TODO
 // Create an mpsc channel and clone the transmitter and give it to all the tasks
 // peers will send messages through this containing a oneshot transmitter
@@ -18,10 +57,16 @@ use crate::{tracker::TrackerResponse, Result};
 // peer will  get the message using its oneshot receiver
 #[derive(Debug)]
 pub struct Manager {
-    path: PathBuf,
     client_peer_id: Vec<u8>,
     //peer_list: Vec<Peer>,
     torrent: Torrent,
+    /// peers known before the first announce, e.g. `x.pe` of a magnet link
+    initial_peers: Vec<TrackerPeer>,
+    /// disabled for private torrents, see BEP 27
+    dht: Option<Dht>,
+    banned: Arc<BanList>,
+    /// port peers can connect to us on, `None` until the listener is bound
+    listen_port: Option<u16>,
     //pub piece_picker: PiecePicker,
 }
 
@@ -34,87 +79,454 @@ impl Manager {
 
         let torrent = Torrent::new(&path)?;
         Ok(Manager {
-            path,
             client_peer_id,
             torrent,
+            initial_peers: vec![],
+            dht: None,
+            banned: Arc::new(BanList::default()),
+            listen_port: None,
         })
     }
-    pub fn send_tracker_request(&self) -> Result<TrackerResponse> {
-        let url = self.torrent.generate_tracker_url(&self.client_peer_id)?;
-        println!("{}", url.as_str());
+    /// Fetch the metadata of a magnet link from the peers returned by its trackers
+    pub async fn from_magnet(uri: &str) -> Result<Manager> {
+        let magnet = Magnet::parse(uri)?;
+        let client_peer_id = utils::generate_peer_id()?;
+        println!(
+            "Fetching metadata for {}",
+            magnet.display_name.as_deref().unwrap_or("magnet link")
+        );
+
+        let (send_peers, receive_peers) = mpsc::unbounded_channel::<Vec<TrackerPeer>>();
+        send_peers.send(magnet.peers.clone())?;
+        for url in &magnet.trackers {
+            let mut tracker = Tracker::new(url.clone());
+            let request = AnnounceRequest {
+                info_hash: magnet.info_hash.clone(),
+                peer_id: client_peer_id.clone(),
+                // nothing listens for connections while the metadata is fetched
+                port: 0,
+                uploaded: 0,
+                downloaded: 0,
+                // the size isn't known yet, anything but zero tells the tracker that we are leeching
+                left: 1,
+                event: None,
+            };
+            let send_peers = send_peers.clone();
+            tokio::spawn(async move {
+                match tracker.announce(&request).await {
+                    // the metadata may already have arrived from another peer
+                    Ok(res) => send_peers.send(res.peers).unwrap_or(()),
+                    Err(e) => eprintln!("Announce to {} failed: {}", tracker.announce_url, e),
+                }
+            });
+        }
+        // a private torrent's magnet link can't be told apart, so the DHT is only dropped once the metadata arrives
+        let dht = match start_dht().await {
+            Ok(dht) => {
+                let info_hash: NodeId = magnet.info_hash.as_slice().try_into()?;
+                let (lookup, send_peers) = (dht.clone(), send_peers.clone());
+                tokio::spawn(async move {
+                    bootstrap_dht(&lookup, &[]).await;
+                    let peers = lookup.get_peers(info_hash).await;
+                    send_peers
+                        .send(peers.into_iter().map(TrackerPeer::from).collect())
+                        .unwrap_or(());
+                });
+                Some(dht)
+            }
+            Err(e) => {
+                eprintln!("Starting the DHT failed: {}", e);
+                None
+            }
+        };
+        drop(send_peers);
 
-        let res = tracker::send_tracker_request(url)?;
-        Ok(res)
+        let info_bytes = metadata::fetch_metadata(
+            receive_peers,
+            magnet.info_hash.clone(),
+            client_peer_id.clone(),
+        )
+        .await?;
+        let torrent = Torrent::from_info_bytes(&info_bytes, &magnet.trackers)?;
+        let dht = dht.filter(|_| !torrent.info.is_private());
+        Ok(Manager {
+            client_peer_id,
+            torrent,
+            initial_peers: magnet.peers,
+            dht,
+            banned: Arc::new(BanList::default()),
+            listen_port: None,
+        })
+    }
+    pub fn initial_peers(&self) -> Vec<TrackerPeer> {
+        self.initial_peers.clone()
+    }
+    /// Open the DHT node, unless the torrent is private or it is already open.
+    /// It joins the DHT in the background once the announcer is spawned.
+    pub async fn start_dht(&mut self) {
+        if self.dht.is_some() || self.torrent.info.is_private() {
+            return;
+        }
+        match start_dht().await {
+            Ok(dht) => self.dht = Some(dht),
+            Err(e) => eprintln!("Starting the DHT failed: {}", e),
+        }
+    }
+    /// Save the routing table so that the next run doesn't have to bootstrap from scratch
+    pub fn save_dht(&self) -> Result<()> {
+        match &self.dht {
+            Some(dht) => dht.save(Path::new(DHT_CACHE)),
+            None => Ok(()),
+        }
+    }
+    /// Look up and announce the torrent on the DHT periodically, the peers go into the connection queue
+    pub fn spawn_dht_announcer(
+        &self,
+        send_peers: UnboundedSender<Vec<TrackerPeer>>,
+    ) -> Result<Option<JoinHandle<()>>> {
+        let dht = match &self.dht {
+            Some(dht) => dht.clone(),
+            None => return Ok(None),
+        };
+        let info_hash: NodeId = self.torrent.info_hash.as_slice().try_into()?;
+        let nodes = self.torrent.dht_nodes();
+        let port = self.listen_port;
+        Ok(Some(tokio::spawn(async move {
+            bootstrap_dht(&dht, &nodes).await;
+            let mut interval = time::interval(DHT_ANNOUNCE_INTERVAL);
+            loop {
+                interval.tick().await;
+                // without a listener the torrent is only looked up
+                let peers = match port {
+                    Some(port) => dht.announce(info_hash, port).await,
+                    None => dht.get_peers(info_hash).await,
+                };
+                if send_peers
+                    .send(peers.into_iter().map(TrackerPeer::from).collect())
+                    .is_err()
+                {
+                    break;
+                }
+            }
+        })))
+    }
+    pub fn spawn_announcer(
+        &self,
+        stats: Arc<TransferStats>,
+        send_peers: UnboundedSender<Vec<TrackerPeer>>,
+        receive_events: UnboundedReceiver<Event>,
+    ) -> Result<Announcer> {
+        let tiers = self
+            .torrent
+            .tracker_tiers()?
+            .into_iter()
+            .map(|tier| tier.into_iter().map(Tracker::new).collect())
+            .collect();
+        Ok(Announcer::new(
+            tiers,
+            self.torrent.info_hash.clone(),
+            self.client_peer_id.clone(),
+            // without a listener the trackers are told that we can't be connected to
+            self.listen_port.unwrap_or(0),
+            stats,
+            send_peers,
+            receive_events,
+        ))
+    }
+    pub fn transfer_stats(&self) -> Arc<TransferStats> {
+        Arc::new(TransferStats {
+            uploaded: AtomicU64::new(0),
+            downloaded: AtomicU64::new(0),
+            left: AtomicU64::new(self.torrent.info.total_length()),
+        })
+    }
+    pub fn piece_hashes(&self) -> Vec<[u8; 20]> {
+        self.torrent.info.piece_hashes()
+    }
+    /// The resume state of the torrent, made of the pieces `disk` has
+    pub fn resume_file(&self, disk: DiskHandle) -> Result<ResumeFile> {
+        Ok(ResumeFile::new(
+            self.torrent.info.resume_path()?,
+            self.torrent.info.file_layout()?,
+            disk,
+        ))
     }
     pub fn spawn_piece_picker(
         &self,
         send_to_disk_manager: UnboundedSender<DownloadedPiece>,
+        stats: Arc<TransferStats>,
     ) -> PiecePicker {
-        let pieces = self.torrent.info.pieces.to_vec();
-        let piece_hashes: Vec<[u8; 20]> = pieces
-            .chunks_exact(20)
-            // try to remove this unwrap
-            .map(|chunk| chunk.try_into().unwrap())
-            .collect();
+        let piece_hashes = self.piece_hashes();
         let total_pieces = piece_hashes.len();
-        println!("{}", total_pieces);
-
         let piece_length = self.torrent.info.piece_length;
-        let file_length = self.torrent.info.length.unwrap();
+        let file_length = self.torrent.info.total_length();
 
-        let piece_picker = PiecePicker::new(
+        PiecePicker::new(
             total_pieces as u32,
             piece_hashes,
             piece_length as u32,
-            file_length as u32,
+            file_length,
             send_to_disk_manager,
-        );
-        piece_picker
+            stats,
+            self.banned.clone(),
+        )
     }
+    /// The data is kept in `storage` behind a cache of `cache_size` bytes, and moved to `move_to` once it is complete
     pub fn spawn_disk_manager(
         &self,
         receive_pieces: UnboundedReceiver<DownloadedPiece>,
+        storage: StorageKind,
+        cache_size: usize,
+        move_to: Option<PathBuf>,
+        stats: Arc<TransferStats>,
+        send_to_announcer: UnboundedSender<Event>,
     ) -> Result<DiskManager> {
-        let disk_manager = DiskManager::new(
-            receive_pieces,
-            &self.torrent.info.name,
-            self.torrent.info.piece_length,
-            (self.torrent.info.pieces.to_vec().len() / 20) as u32,
-        )?;
+        let info = &self.torrent.info;
+        let kind = storage;
+        let mut storage = kind.create(info.file_layout()?, info.piece_length)?;
+        // the data in memory doesn't need another copy
+        if cache_size > 0 && kind.is_persistent() {
+            storage = Box::new(CachedStorage::new(storage, cache_size));
+        }
+        let disk_manager =
+            DiskManager::new(receive_pieces, storage, stats, send_to_announcer, move_to);
         Ok(disk_manager)
     }
+    /// Accept the connections peers open to us on `PORT`, or on any free port if it is taken.
+    /// The port is advertised to the trackers, the DHT and the peers from then on.
+    pub async fn spawn_listener(
+        &mut self,
+        send_incoming: UnboundedSender<IncomingPeer>,
+    ) -> Result<JoinHandle<()>> {
+        // another client might already be using the port
+        let mut listener = match Listener::bind(("0.0.0.0", PORT)).await {
+            Ok(listener) => listener,
+            Err(_) => Listener::bind(("0.0.0.0", 0)).await?,
+        };
+        let addr = listener.local_addr()?;
+        println!("Listening for peers on {}", addr);
+        self.listen_port = Some(addr.port());
+        listener.add_torrent(self.torrent.info_hash.clone(), send_incoming);
+        Ok(listener.listen())
+    }
+    /// Connect to the peers received from the trackers and other peers and take over the incoming connections.
+    /// Peers wait in a queue while `MAX_CONNECTIONS` connections are open, incoming connections beyond that are refused.
+    #[allow(clippy::too_many_arguments)]
     pub fn connect_to_peers(
         &self,
-        res: TrackerResponse,
+        mut receive_peers: UnboundedReceiver<Vec<TrackerPeer>>,
+        mut receive_incoming: UnboundedReceiver<IncomingPeer>,
+        send_peers: UnboundedSender<Vec<TrackerPeer>>,
         send_to_manager: UnboundedSender<Command>,
-    ) -> Vec<JoinHandle<()>> {
-        let handles: Vec<JoinHandle<()>> = res
-            .peers
-            .into_iter()
-            .map(|tracker_peer| {
-                let mut peer = Peer::new(
-                    tracker_peer.ip,
-                    tracker_peer.port,
-                    tracker_peer.peer_id.to_vec(),
-                    send_to_manager.clone(),
-                );
-                let info = self.torrent.info_hash.clone();
-                let client_peer_id = self.client_peer_id.clone();
-                tokio::spawn(async move {
-                    if let Err(e) = peer.connect(&info, &client_peer_id).await {
-                        eprintln!("Some error occured:- {:?}", e);
-                        eprintln!("Closing the connection");
-                    };
-                })
-            })
-            .collect();
-        handles
+        disk: DiskHandle,
+        stats: Arc<TransferStats>,
+        choker: UnboundedSender<ChokerCommand>,
+        cancels: broadcast::Sender<EndgameCancel>,
+    ) -> JoinHandle<()> {
+        let context = PeerContext {
+            info_hash: self.torrent.info_hash.clone(),
+            client_peer_id: self.client_peer_id.clone(),
+            info_bytes: Arc::new(self.torrent.info_bytes.clone()),
+            total_pieces: self.torrent.info.total_pieces(),
+            private: self.torrent.info.is_private(),
+            shared: PeerShared {
+                transmitter: send_to_manager,
+                connected: Arc::new(ConnectedPeers::default()),
+                dht: self.dht.clone(),
+                disk,
+                stats,
+                choker,
+                banned: self.banned.clone(),
+                cancels,
+                listen_port: self.listen_port,
+            },
+            send_peers,
+        };
+        tokio::spawn(async move {
+            // trackers keep returning the peers we are already connected to, the queued and connected
+            // ones are known until their connection closes
+            let mut known_peers = HashSet::new();
+            let mut queue = VecDeque::new();
+            let (send_closed, mut receive_closed) =
+                mpsc::unbounded_channel::<Option<(String, u16)>>();
+            let mut open_connections = 0;
+            loop {
+                tokio::select! {
+                    tracker_peers = receive_peers.recv() => match tracker_peers {
+                        Some(tracker_peers) => {
+                            for tracker_peer in tracker_peers {
+                                if known_peers.insert((tracker_peer.ip.clone(), tracker_peer.port)) {
+                                    queue.push_back(tracker_peer);
+                                }
+                            }
+                        }
+                        None => break,
+                    },
+                    Some(incoming) = receive_incoming.recv() => {
+                        let banned = context.shared.banned.contains(&incoming.addr.ip().to_string());
+                        if open_connections < MAX_CONNECTIONS && !banned {
+                            context.spawn_incoming(incoming, send_closed.clone());
+                            open_connections += 1;
+                        }
+                    }
+                    Some(closed) = receive_closed.recv() => {
+                        open_connections -= 1;
+                        if let Some(addr) = closed {
+                            known_peers.remove(&addr);
+                        }
+                    }
+                }
+                while open_connections < MAX_CONNECTIONS {
+                    match queue.pop_front() {
+                        Some(tracker_peer) if context.shared.banned.contains(&tracker_peer.ip) => {
+                            known_peers.remove(&(tracker_peer.ip, tracker_peer.port));
+                        }
+                        Some(tracker_peer) => {
+                            context.spawn_peer(tracker_peer, send_closed.clone());
+                            open_connections += 1;
+                        }
+                        None => break,
+                    }
+                }
+            }
+        })
+    }
+}
+
+/// Everything the peer tasks of a torrent share
+#[derive(Debug, Clone)]
+struct PeerContext {
+    info_hash: Vec<u8>,
+    client_peer_id: Vec<u8>,
+    /// served over ut_metadata
+    info_bytes: Arc<Vec<u8>>,
+    total_pieces: u32,
+    private: bool,
+    shared: PeerShared,
+    /// peers learned over ut_pex go back into the connection queue
+    send_peers: UnboundedSender<Vec<TrackerPeer>>,
+}
+
+impl PeerContext {
+    fn new_peer(&self, ip: String, port: u16, peer_id: Option<Vec<u8>>) -> Peer {
+        let allowed_fast = match ip.parse() {
+            Ok(ip) => {
+                fast::allowed_fast_set(ip, &self.info_hash, self.total_pieces, ALLOWED_FAST_COUNT)
+            }
+            Err(_) => HashSet::new(),
+        };
+        // ut_metadata has to come first, see `UtMetadata`
+        let mut extensions: Vec<Box<dyn Extension>> =
+            vec![Box::new(UtMetadata::new(self.info_bytes.clone()))];
+        if !self.private {
+            extensions.push(Box::new(UtPex::new(
+                (ip.clone(), port),
+                self.shared.connected.clone(),
+                self.send_peers.clone(),
+            )));
+        }
+        Peer::new(
+            ip,
+            port,
+            peer_id,
+            ExtensionRegistry::new(extensions),
+            allowed_fast,
+            self.shared.clone(),
+        )
+    }
+
+    /// Spawn a task for the peer, `send_closed` gets its address once the connection ends
+    fn spawn_peer(
+        &self,
+        tracker_peer: TrackerPeer,
+        send_closed: UnboundedSender<Option<(String, u16)>>,
+    ) {
+        let addr = (tracker_peer.ip.clone(), tracker_peer.port);
+        let mut peer = self.new_peer(
+            tracker_peer.ip,
+            tracker_peer.port,
+            tracker_peer.peer_id.map(|peer_id| peer_id.to_vec()),
+        );
+        let info = self.info_hash.clone();
+        let client_peer_id = self.client_peer_id.clone();
+        tokio::spawn(async move {
+            if let Err(e) = peer.connect(&info, &client_peer_id).await {
+                eprintln!("Some error occured:- {:?}", e);
+                eprintln!("Closing the connection");
+            };
+            // frees the connection slot, the peer may be connected to again
+            send_closed.send(Some(addr)).unwrap_or(());
+        });
+    }
+
+    /// Spawn a task for a peer which connected to us, `send_closed` is notified once the connection ends
+    fn spawn_incoming(
+        &self,
+        incoming: IncomingPeer,
+        send_closed: UnboundedSender<Option<(String, u16)>>,
+    ) {
+        let mut peer = self.new_peer(incoming.addr.ip().to_string(), incoming.addr.port(), None);
+        let client_peer_id = self.client_peer_id.clone();
+        tokio::spawn(async move {
+            if let Err(e) = peer
+                .accept(incoming.stream, incoming.handshake, &client_peer_id)
+                .await
+            {
+                eprintln!("Some error occured:- {:?}", e);
+                eprintln!("Closing the connection");
+            };
+            // frees the connection slot
+            send_closed.send(None).unwrap_or(());
+        });
+    }
+}
+
+/// Open the DHT node with the id of the earlier run, its routing table is empty until it is bootstrapped
+async fn start_dht() -> Result<Dht> {
+    let id = match Dht::load(Path::new(DHT_CACHE)) {
+        Ok((id, _)) => id,
+        Err(_) => dht::random_id()?,
+    };
+    // another client might already be using the port
+    let dht = match Dht::bind(("0.0.0.0", PORT), id).await {
+        Ok(dht) => dht,
+        Err(_) => Dht::bind(("0.0.0.0", 0), id).await?,
+    };
+    dht.listen();
+    Ok(dht)
+}
+
+/// Fill the routing table from the nodes of the earlier run, the `nodes` of the torrent and the routers
+async fn bootstrap_dht(dht: &Dht, nodes: &[String]) {
+    let mut bootstrap = Dht::load(Path::new(DHT_CACHE)).map_or(vec![], |(_, nodes)| nodes);
+    let mut hosts = nodes.to_vec();
+    hosts.extend(BOOTSTRAP_NODES.iter().map(|host| host.to_string()));
+    bootstrap.extend(dht::resolve(&hosts).await);
+    dht.bootstrap(&bootstrap).await;
+    if let Err(e) = dht.save(Path::new(DHT_CACHE)) {
+        eprintln!("Saving the DHT nodes failed: {}", e);
+    }
+}
+
+/// Peers which sent corrupt data, by ip
+#[derive(Debug, Default)]
+pub struct BanList {
+    ips: Mutex<HashSet<String>>,
+}
+
+impl BanList {
+    pub fn ban(&self, ip: &str) {
+        self.ips.lock().unwrap().insert(ip.to_string());
+    }
+
+    pub fn contains(&self, ip: &str) -> bool {
+        self.ips.lock().unwrap().contains(ip)
     }
 }
 
 #[derive(Debug)]
 pub struct PiecePicker {
-    file_length: u32,
+    file_length: u64,
     total_pieces: u32,
     piece_map: Vec<PiecePos>,
     pieces: Vec<u32>,
@@ -125,6 +537,24 @@ pub struct PiecePicker {
     pub peer_bitfields: HashMap<Vec<u8>, BitVec<Msb0, u8>>,
     send_to_disk_manager: UnboundedSender<DownloadedPiece>,
     downloaded_pieces: HashMap<u32, DownloadedPiece>,
+    stats: Arc<TransferStats>,
+    /// pieces every peer allows us to request while it chokes us, see BEP 6
+    allowed_fast: HashMap<Vec<u8>, HashSet<u32>>,
+    /// pieces the peers suggested, tried before the rarest ones
+    suggested: HashMap<Vec<u8>, Vec<u32>>,
+    /// ip of the peer which sent every block of a piece, by the offset of the block
+    contributors: HashMap<u32, HashMap<u32, String>>,
+    /// blocks of the pieces which failed the hash check with the ip which sent each of them,
+    /// the ones which differ from the copy that passes are corrupt
+    failed_blocks: HashMap<u32, Vec<(String, DownloadedBlock)>>,
+    /// pieces which failed the hash check, by the ip of the contributors
+    hash_failures: HashMap<String, u32>,
+    banned: Arc<BanList>,
+    /// blocks which arrived while they were requested from other peers as well
+    cancels: broadcast::Sender<EndgameCancel>,
+    resume: Option<ResumeFile>,
+    /// the periodic save of the resume state which runs in the background
+    saving: Option<JoinHandle<()>>,
 }
 
 impl PiecePicker {
@@ -132,8 +562,10 @@ impl PiecePicker {
         total_pieces: u32,
         piece_hashes: Vec<[u8; 20]>,
         piece_length: u32,
-        file_length: u32,
+        file_length: u64,
         send_to_disk_manager: UnboundedSender<DownloadedPiece>,
+        stats: Arc<TransferStats>,
+        banned: Arc<BanList>,
     ) -> Self {
         let piece_map = (0..total_pieces)
             .map(|index| PiecePos::new(0, PieceState::NotDownloading, index))
@@ -144,6 +576,7 @@ impl PiecePicker {
         // 35 peers are mostly enough for a file, and we receive only 30 peers at a time from the tracker
         // so we assume a safe number of 50 max peers connected at a time
         let priority_boundaries = vec![total_pieces; 50];
+        let (cancels, _) = broadcast::channel(CANCEL_CAPACITY);
         Self {
             file_length,
             total_pieces,
@@ -156,29 +589,173 @@ impl PiecePicker {
             peer_bitfields: HashMap::new(),
             send_to_disk_manager,
             downloaded_pieces: HashMap::new(),
+            stats,
+            allowed_fast: HashMap::new(),
+            suggested: HashMap::new(),
+            contributors: HashMap::new(),
+            failed_blocks: HashMap::new(),
+            hash_failures: HashMap::new(),
+            banned,
+            cancels,
+            resume: None,
+            saving: None,
+        }
+    }
+    /// Continue a download, the pieces in `have` are never picked and only the blocks missing from
+    /// the `partial` pieces are. The state is saved to `resume` from now on.
+    pub fn resume(
+        &mut self,
+        have: &BitVec<Msb0, u8>,
+        partial: Vec<PartialPiece>,
+        resume: ResumeFile,
+    ) {
+        for index in 0..self.total_pieces {
+            if have.get(index as usize).is_some_and(|bit| *bit) {
+                let mut downloading_piece = DownloadingPiece::new(index, self.piece_length(index));
+                for block in downloading_piece.blocks.iter_mut() {
+                    block.state = BlockState::Finished;
+                }
+                self.downloading.insert(index, downloading_piece);
+            }
+        }
+        for piece in partial {
+            if piece.index >= self.total_pieces || self.downloading.contains_key(&piece.index) {
+                continue;
+            }
+            let piece_length = self.piece_length(piece.index);
+            let mut downloading_piece = DownloadingPiece::new(piece.index, piece_length);
+            let mut downloaded_piece = DownloadedPiece::new(piece.index, piece_length);
+            for partial_block in piece.blocks {
+                let block = downloading_piece.blocks.iter_mut().find(|block| {
+                    block.begin == partial_block.begin
+                        && block.length as usize == partial_block.data.len()
+                });
+                if let Some(block) = block {
+                    block.state = BlockState::Finished;
+                    downloaded_piece.add_downloaded_block(DownloadedBlock::new(
+                        piece.index,
+                        partial_block.begin,
+                        partial_block.data.into_vec(),
+                    ));
+                }
+            }
+            self.downloading.insert(piece.index, downloading_piece);
+            self.downloaded_pieces.insert(piece.index, downloaded_piece);
+        }
+        self.resume = Some(resume);
+    }
+    /// Blocks of the pieces which aren't complete yet
+    fn partial_pieces(&self) -> Vec<PartialPiece> {
+        self.downloaded_pieces
+            .values()
+            .map(|piece| PartialPiece {
+                index: piece.index,
+                blocks: piece
+                    .blocks
+                    .iter()
+                    .filter(|block| !block.data.is_empty())
+                    .map(|block| PartialBlock {
+                        begin: block.begin,
+                        data: ByteBuf::from(block.data.clone()),
+                    })
+                    .collect(),
+            })
+            .collect()
+    }
+    /// Save the state of the download in the background so that a restart continues from here.
+    /// Nothing is saved while the previous save is still running.
+    fn save_resume(&mut self) {
+        let resume = match &self.resume {
+            Some(resume) => resume.clone(),
+            None => return,
+        };
+        if self
+            .saving
+            .as_ref()
+            .is_some_and(|saving| !saving.is_finished())
+        {
+            return;
+        }
+        let partial = self.partial_pieces();
+        self.saving = Some(tokio::spawn(async move {
+            if let Err(e) = resume.save(partial).await {
+                eprintln!("Saving the resume state failed: {}", e);
+            }
+        }));
+    }
+    /// Stop handing pieces to the disk manager and wait for it to write and flush the ones it has,
+    /// the state is saved once they are on disk
+    pub async fn close(mut self, disk_handle: JoinHandle<()>) -> Result<()> {
+        let partial = self.partial_pieces();
+        let resume = self.resume.take();
+        let saving = self.saving.take();
+        // the disk manager finishes once the sender is dropped
+        drop(self);
+        disk_handle.await?;
+        // the periodic save mustn't overwrite the final one
+        if let Some(saving) = saving {
+            saving.await?;
+        }
+        match resume {
+            Some(resume) => resume.save(partial).await,
+            None => Ok(()),
+        }
+    }
+    /// The peers cancel the requests for these blocks
+    pub fn cancels(&self) -> broadcast::Sender<EndgameCancel> {
+        self.cancels.clone()
+    }
+    /// Length of the piece, taking into account that the final piece may be shorter
+    fn piece_length(&self, index: u32) -> u32 {
+        if index == self.total_pieces - 1 {
+            (self.file_length - index as u64 * self.piece_length as u64) as u32
+        } else {
+            self.piece_length
         }
     }
     pub fn register_bitfield(&mut self, peer_id: Vec<u8>, mut bitfield: BitVec<Msb0, u8>) {
         bitfield.resize(self.pieces.len(), false);
+        // a bitfield or have all replaces what the peer sent before
+        if let Some(previous) = self.peer_bitfields.remove(&peer_id) {
+            for piece in previous.iter_ones() {
+                self.decrement_piece_availability(piece);
+            }
+        }
 
         // if bitfield has all pieces
         // todo find a better solution to update availability when bitfield has all pieces
         // try to somehow generalize the case
         for (piece, available_piece) in bitfield.iter().enumerate() {
             if *available_piece {
-                if piece == 0 {
-                    println!("{}, {:?}", piece, peer_id);
-                }
                 self.increment_piece_availability(piece);
             }
         }
         self.peer_bitfields.insert(peer_id, bitfield);
     }
+    /// Add a piece the peer announced to its bitfield, peers may send haves without a bitfield
+    pub fn register_have(&mut self, peer_id: Vec<u8>, piece_index: usize) {
+        let total_pieces = self.pieces.len();
+        if piece_index >= total_pieces {
+            return;
+        }
+        let bitfield = self
+            .peer_bitfields
+            .entry(peer_id)
+            .or_insert_with(|| BitVec::repeat(false, total_pieces));
+        // duplicate haves are only counted once
+        if bitfield[piece_index] {
+            return;
+        }
+        bitfield.set(piece_index, true);
+        self.increment_piece_availability(piece_index);
+    }
     pub fn increment_piece_availability(&mut self, piece: usize) {
-        let avail = self.piece_map[piece].peer_count;
-        if piece == 0 {
-            println!("{}, {}", piece, avail);
+        // downloading pieces are kept at the start, out of the rarity order
+        if let PieceState::Downloading = self.piece_map[piece].state {
+            self.piece_map[piece].peer_count += 1;
+            return;
         }
+        let avail = self.piece_map[piece].peer_count;
         self.priority_boundaries[avail as usize] -= 1;
         self.piece_map[piece].peer_count += 1;
         let piece_index = self.piece_map[piece].index;
@@ -192,6 +769,9 @@ impl PiecePicker {
     }
     fn decrement_piece_availability(&mut self, piece: usize) {
         self.piece_map[piece].peer_count -= 1;
+        if let PieceState::Downloading = self.piece_map[piece].state {
+            return;
+        }
         let avail = self.piece_map[piece].peer_count;
         let piece_index = self.piece_map[piece].index;
         let other_index = self.priority_boundaries[avail as usize];
@@ -204,58 +784,292 @@ impl PiecePicker {
 
         self.priority_boundaries[avail as usize] += 1;
     }
-    pub fn pick_intial_pieces(&mut self, peer_id: &Vec<u8>) -> Option<Vec<Option<Block>>> {
-        let pieces: Vec<Option<Block>> = (0..5).map(|_| self.pick_piece(&peer_id)).collect();
-        let no_piece = pieces.iter().all(|block| block.is_none());
-        if no_piece {
-            None
-        } else {
-            Some(pieces)
-        }
+    /// Pick up to `count` blocks to request from the peer at once
+    pub fn pick_blocks(&mut self, peer_id: &[u8], choked: bool, count: usize) -> Vec<Block> {
+        (0..count)
+            .map_while(|_| self.pick_piece(peer_id, choked))
+            .collect()
     }
-    pub fn pick_piece(&mut self, peer_id: &Vec<u8>) -> Option<Block> {
+    /// Pick the next block to request from the peer.
+    /// A peer which chokes us only serves the pieces in its allowed fast set.
+    pub fn pick_piece(&mut self, peer_id: &[u8], choked: bool) -> Option<Block> {
         let peer_bitfield = self.peer_bitfields.get(peer_id)?;
+        let allowed_fast = self.allowed_fast.get(peer_id);
+        if choked && allowed_fast.is_none() {
+            return None;
+        }
         let mut selected_index = self.pieces.len();
         let mut selected_block: Option<Block> = None;
 
-        for (index, piece) in self.pieces.iter().enumerate() {
-            // if peer has the piece
-            if peer_bitfield[*piece as usize] {
-                // check if final piece
-                let piece_length = if index as u32 == self.total_pieces - 1 {
-                    self.file_length - (index as u32 * self.piece_length)
-                } else {
-                    self.piece_length
-                };
-                let downloading_piece = self
-                    .downloading
-                    .entry(*piece)
-                    .or_insert(DownloadingPiece::new(*piece, piece_length));
+        // suggested pieces are tried first, the rest in the order of their rarity
+        let suggested = self.suggested.get(peer_id).map(|pieces| pieces.as_slice());
+        let candidates = suggested
+            .unwrap_or(&[])
+            .iter()
+            .map(|piece| self.piece_map[*piece as usize].index as usize)
+            .chain(0..self.pieces.len())
+            .filter(|index| {
+                let piece = self.pieces[*index];
+                peer_bitfield[piece as usize]
+                    && (!choked || allowed_fast.is_some_and(|pieces| pieces.contains(&piece)))
+            })
+            .collect::<Vec<usize>>();
+        for &index in &candidates {
+            let piece = self.pieces[index];
+            let piece_length = self.piece_length(piece);
+            let downloading_piece = self
+                .downloading
+                .entry(piece)
+                .or_insert(DownloadingPiece::new(piece, piece_length));
 
-                for block in downloading_piece.blocks.iter_mut() {
-                    if let BlockState::Open = block.state {
-                        selected_index = index;
-                        block.state = BlockState::Requested;
-                        selected_block = Some(Block::new(
+            for block in downloading_piece.blocks.iter_mut() {
+                if let BlockState::Open = block.state {
+                    selected_index = index;
+                    block.state = BlockState::Requested {
+                        peer_ids: vec![peer_id.to_vec()],
+                        since: Instant::now(),
+                    };
+                    selected_block = Some(Block::new(
+                        block.piece_index,
+                        block.begin,
+                        Some(block.length),
+                    ));
+                    break;
+                }
+            }
+
+            if selected_block.is_some() {
+                break;
+            }
+        }
+
+        if selected_index != self.pieces.len() {
+            self.priortize_downloading_piece(selected_index);
+        }
+
+        if selected_block.is_none() && self.in_endgame() {
+            return self.pick_endgame(peer_id, &candidates);
+        }
+        selected_block
+    }
+    /// Every block is either requested or downloaded
+    fn in_endgame(&self) -> bool {
+        self.downloading.len() == self.total_pieces as usize
+            && self
+                .downloading
+                .values()
+                .flat_map(|piece| piece.blocks.iter())
+                .all(|block| !matches!(block.state, BlockState::Open))
+    }
+    /// Request a block from the peer which is already requested from others,
+    /// so that the end of the download doesn't wait on the slowest peer.
+    /// `candidates` are the positions of the pieces the peer can send.
+    fn pick_endgame(&mut self, peer_id: &[u8], candidates: &[usize]) -> Option<Block> {
+        for &index in candidates {
+            let piece = self.pieces[index];
+            let downloading_piece = match self.downloading.get_mut(&piece) {
+                Some(piece) => piece,
+                None => continue,
+            };
+            for block in downloading_piece.blocks.iter_mut() {
+                if let BlockState::Requested { peer_ids, since } = &mut block.state {
+                    if !peer_ids.iter().any(|requested| requested == peer_id) {
+                        peer_ids.push(peer_id.to_vec());
+                        *since = Instant::now();
+                        return Some(Block::new(
                             block.piece_index,
                             block.begin,
                             Some(block.length),
                         ));
-                        break;
                     }
                 }
-
-                if selected_block.is_some() {
-                    break;
+            }
+        }
+        None
+    }
+    fn suggest_piece(&mut self, peer_id: Vec<u8>, piece_index: u32) {
+        if piece_index >= self.total_pieces {
+            return;
+        }
+        let suggested = self.suggested.entry(peer_id).or_default();
+        if !suggested.contains(&piece_index) {
+            suggested.push(piece_index);
+        }
+    }
+    fn allow_fast(&mut self, peer_id: Vec<u8>, piece_index: u32) {
+        if piece_index < self.total_pieces {
+            self.allowed_fast
+                .entry(peer_id)
+                .or_default()
+                .insert(piece_index);
+        }
+    }
+    /// Make a requested block available to other peers again, e.g. after the peer rejected it
+    fn release_block(&mut self, peer_id: &[u8], piece_index: u32, begin: u32) {
+        let downloading_piece = match self.downloading.get_mut(&piece_index) {
+            Some(piece) => piece,
+            None => return,
+        };
+        for block in downloading_piece.blocks.iter_mut() {
+            if block.begin == begin {
+                block.release(peer_id);
+            }
+        }
+    }
+    /// Forget a peer which disconnected, the blocks requested from it go back to the other peers
+    fn release_peer(&mut self, peer_id: &[u8]) {
+        for downloading_piece in self.downloading.values_mut() {
+            for block in downloading_piece.blocks.iter_mut() {
+                block.release(peer_id);
+            }
+        }
+        if let Some(bitfield) = self.peer_bitfields.remove(peer_id) {
+            for (piece, available_piece) in bitfield.iter().enumerate() {
+                if *available_piece {
+                    self.decrement_piece_availability(piece);
                 }
             }
         }
-
-        if selected_index != self.pieces.len() {
-            self.priortize_downloading_piece(selected_index);
+        self.allowed_fast.remove(peer_id);
+        self.suggested.remove(peer_id);
+    }
+    /// Open the blocks which were requested before `deadline` so other peers can pick them
+    fn release_stalled(&mut self, deadline: Instant) {
+        for downloading_piece in self.downloading.values_mut() {
+            for block in downloading_piece.blocks.iter_mut() {
+                if matches!(block.state, BlockState::Requested { since, .. } if since < deadline) {
+                    block.state = BlockState::Open;
+                }
+            }
+        }
+    }
+    /// Store the block and check the piece once all of its blocks arrived.
+    /// A piece which fails the hash check is downloaded again.
+    fn downloaded_block(&mut self, ip: String, peer_id: Vec<u8>, block: DownloadedBlock) {
+        self.stats
+            .downloaded
+            .fetch_add(block.data.len() as u64, Ordering::Relaxed);
+        let index = block.piece_index;
+        // blocks of pieces which were never picked, e.g. past the end of the torrent, are dropped
+        let finished = self.downloading.get_mut(&index).and_then(|piece| {
+            piece
+                .blocks
+                .iter_mut()
+                .find(|blk| blk.begin == block.begin && blk.length as usize == block.data.len())
+        });
+        let finished = match finished {
+            Some(finished) => finished,
+            None => return,
+        };
+        // the block may also be requested from other peers during the endgame or after a timeout
+        match std::mem::replace(&mut finished.state, BlockState::Finished) {
+            // another peer was faster, or the piece is already verified
+            BlockState::Finished => return,
+            BlockState::Requested { peer_ids, .. } => {
+                let peer_ids: Vec<Vec<u8>> = peer_ids
+                    .into_iter()
+                    .filter(|requested| *requested != peer_id)
+                    .collect();
+                if !peer_ids.is_empty() {
+                    let block = BlockInfo {
+                        piece_index: index,
+                        begin: finished.begin,
+                        length: finished.length,
+                    };
+                    // no peers might be connected
+                    self.cancels
+                        .send(EndgameCancel { block, peer_ids })
+                        .unwrap_or(0);
+                }
+            }
+            BlockState::Open => {}
+        }
+        self.contributors
+            .entry(index)
+            .or_default()
+            .insert(block.begin, ip);
+        let piece_length = self.piece_length(index);
+        let downloaded_piece = self
+            .downloaded_pieces
+            .entry(index)
+            .or_insert(DownloadedPiece::new(index, piece_length));
+        downloaded_piece.add_downloaded_block(block);
+        if !downloaded_piece.all_blocks_downloaded {
+            return;
         }
 
-        selected_block
+        let piece = self.downloaded_pieces.remove(&index).unwrap();
+        let contributors = self.contributors.remove(&index).unwrap_or_default();
+        let piece_data = piece.blocks.iter().fold(vec![], |mut acc, blk| {
+            acc.extend_from_slice(&blk.data);
+            acc
+        });
+        let sha1 = digest::digest(&digest::SHA1_FOR_LEGACY_USE_ONLY, &piece_data);
+        let sha1 = sha1.as_ref();
+        //check if the hash matches
+        let valid = self
+            .piece_hashes
+            .get(index as usize)
+            .is_some_and(|hash| sha1 == hash);
+        if valid {
+            self.find_corrupt_blocks(&piece);
+            if self.send_to_disk_manager.send(piece).is_err() {
+                eprintln!("Receiver Dropped");
+            };
+        } else {
+            self.hash_failed(piece, contributors);
+        }
+    }
+    /// Make the blocks of the piece available again. A peer which sent all of them is at fault,
+    /// with several peers the blocks are kept until the piece passes to tell which were corrupt.
+    fn hash_failed(&mut self, piece: DownloadedPiece, contributors: HashMap<u32, String>) {
+        let index = piece.index;
+        eprintln!("Piece #{} failed the hash check", index);
+        if let Some(downloading_piece) = self.downloading.get_mut(&index) {
+            for block in downloading_piece.blocks.iter_mut() {
+                block.state = BlockState::Open;
+            }
+        }
+        let ips: HashSet<String> = contributors.values().cloned().collect();
+        // blocks left over from an earlier run have no contributor
+        if ips.len() == 1 && contributors.len() == piece.blocks.len() {
+            self.hash_failure(ips.into_iter().next().unwrap());
+            return;
+        }
+        let failed_blocks = self.failed_blocks.entry(index).or_default();
+        for block in piece.blocks {
+            if let Some(ip) = contributors.get(&block.begin) {
+                failed_blocks.push((ip.clone(), block));
+            }
+        }
+    }
+    /// Count a hash failure against the peers whose blocks of an earlier attempt differ from the piece which passed
+    fn find_corrupt_blocks(&mut self, piece: &DownloadedPiece) {
+        let culprits: HashSet<String> = self
+            .failed_blocks
+            .remove(&piece.index)
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|(_, failed)| {
+                piece
+                    .blocks
+                    .iter()
+                    .any(|block| block.begin == failed.begin && block.data != failed.data)
+            })
+            .map(|(ip, _)| ip)
+            .collect();
+        for ip in culprits {
+            self.hash_failure(ip);
+        }
+    }
+    /// Ban the peers which keep sending corrupt data
+    fn hash_failure(&mut self, ip: String) {
+        let failures = self.hash_failures.entry(ip.clone()).or_insert(0);
+        *failures += 1;
+        if *failures >= MAX_HASH_FAILURES {
+            eprintln!("Banning {} for sending corrupt data", ip);
+            self.banned.ban(&ip);
+        }
     }
     /// move the downloading piece at start
     /// index is the index of the piece in the pieces vector
@@ -265,6 +1079,7 @@ impl PiecePicker {
 
         // udpate piece map for the removed piece
         self.piece_map[removed_piece as usize].index = 0;
+        self.piece_map[removed_piece as usize].state = PieceState::Downloading;
 
         // piece was removed and inserted at begining
         // so piece map and priority boundaries need to be updated
@@ -280,94 +1095,78 @@ impl PiecePicker {
         }
     }
     pub async fn listen_to_commands(&mut self, mut receive_from_peers: UnboundedReceiver<Command>) {
-        while let Some(cmd) = receive_from_peers.recv().await {
+        let mut interval = time::interval(STALLED_CHECK_INTERVAL);
+        let mut resume_interval = time::interval(RESUME_INTERVAL);
+        loop {
+            let cmd = tokio::select! {
+                cmd = receive_from_peers.recv() => match cmd {
+                    Some(cmd) => cmd,
+                    None => break,
+                },
+                _ = interval.tick() => {
+                    self.release_stalled(Instant::now() - REQUEST_TIMEOUT);
+                    continue;
+                }
+                _ = resume_interval.tick() => {
+                    self.save_resume();
+                    continue;
+                }
+            };
             match cmd {
                 Command::BitfieldRecieved { peer_id, bitfield } => {
                     self.register_bitfield(peer_id, bitfield);
                     //println!("Recieved bitfield from peer");
                 }
-                Command::PickInitialPieces {
+                Command::PickBlocks {
                     peer_id,
+                    choked,
+                    count,
                     transmitter,
                 } => {
-                    let blocks = self.pick_intial_pieces(&peer_id);
-                    match blocks {
-                        Some(blks) => {
-                            if let Err(_) = transmitter.send(Command::SelectedInitialPieces(blks)) {
-                                eprintln!("Receiver Dropped");
-                            };
-                        }
-                        None => {
-                            if let Err(_) = transmitter.send(Command::NoPiece) {
-                                eprintln!("Receiver Dropped");
-                            };
-                        }
-                    }
+                    let blocks = self.pick_blocks(&peer_id, choked, count);
+                    let reply = if blocks.is_empty() {
+                        Command::NoPiece
+                    } else {
+                        Command::SelectedBlocks(blocks)
+                    };
+                    if transmitter.send(reply).is_err() {
+                        eprintln!("Receiver Dropped");
+                    };
                 }
-                Command::PickPiece {
+                Command::HavePiece {
                     peer_id,
-                    transmitter,
+                    piece_index,
                 } => {
-                    let block = self.pick_piece(&peer_id);
-                    match block {
-                        Some(blk) => {
-                            if let Err(_) = transmitter.send(Command::SelectedPiece(blk)) {
-                                eprintln!("Receiver Dropped");
-                            };
-                        }
-                        None => {
-                            if let Err(_) = transmitter.send(Command::NoPiece) {
-                                eprintln!("Receiver Dropped");
-                            };
-                        }
-                    }
+                    self.register_have(peer_id, piece_index);
                 }
-                Command::HavePiece {
+                Command::HaveAll { peer_id } => {
+                    let bitfield = BitVec::repeat(true, self.total_pieces as usize);
+                    self.register_bitfield(peer_id, bitfield);
+                }
+                Command::SuggestPiece {
                     peer_id,
                     piece_index,
                 } => {
-                    let mut already_has_piece = false;
-                    if let Some(bitfield) = self.peer_bitfields.get_mut(&peer_id) {
-                        if !(*bitfield.get_mut(piece_index).unwrap()) {
-                            *bitfield.get_mut(piece_index).unwrap() = true;
-                        } else {
-                            already_has_piece = true
-                        };
-                    }
-                    if !already_has_piece {
-                        self.increment_piece_availability(piece_index);
-                    }
+                    self.suggest_piece(peer_id, piece_index);
                 }
-                Command::DownloadedBlock(block) => {
-                    let index = block.piece_index;
-                    // check if final piece
-                    let piece_length = if index as u32 == self.total_pieces - 1 {
-                        self.file_length - (index as u32 * self.piece_length)
-                    } else {
-                        self.piece_length
-                    };
-                    let downloaded_piece = self
-                        .downloaded_pieces
-                        .entry(index)
-                        .or_insert(DownloadedPiece::new(index, piece_length));
-                    downloaded_piece.add_downloaded_block(block);
-                    if downloaded_piece.all_blocks_downloaded {
-                        let piece_data =
-                            downloaded_piece.blocks.iter().fold(vec![], |mut acc, blk| {
-                                acc.extend_from_slice(&blk.data);
-                                acc
-                            });
-                        let sha1 = digest::digest(&digest::SHA1_FOR_LEGACY_USE_ONLY, &piece_data);
-                        let sha1 = sha1.as_ref();
-                        //check if the hash matches
-                        if sha1 == self.piece_hashes.get(index as usize).unwrap() {
-                            let piece = self.downloaded_pieces.remove(&index).unwrap();
-                            if let Err(_) = self.send_to_disk_manager.send(piece) {
-                                eprintln!("Receiver Dropped");
-                            };
-                        }
-                        // todo implement part where hashes dont match
-                    }
+                Command::AllowedFast {
+                    peer_id,
+                    piece_index,
+                } => {
+                    self.allow_fast(peer_id, piece_index);
+                }
+                Command::RejectedBlock {
+                    peer_id,
+                    piece_index,
+                    begin,
+                } => {
+                    self.release_block(&peer_id, piece_index, begin);
+                }
+                Command::DownloadedBlock { ip, peer_id, block } => {
+                    self.downloaded_block(ip, peer_id, block);
+                }
+                Command::PeerDisconnected { peer_id } => {
+                    self.release_peer(&peer_id);
                 }
                 _ => {}
             }
@@ -400,8 +1199,6 @@ enum PieceState {
 
 #[derive(Debug)]
 pub struct DownloadingPiece {
-    // kind of redundant, maybe remove it later
-    index: u32,
     blocks: Vec<Block>,
 }
 
@@ -416,22 +1213,25 @@ impl DownloadingPiece {
         }
         let blocks = (0..no_of_blocks)
             .map(|i| {
-                if (final_block_len != 0) && (index == no_of_blocks - 1) {
+                if (final_block_len != 0) && (i == no_of_blocks - 1) {
                     Block::new(index, i * 16384, Some(final_block_len))
                 } else {
                     Block::new(index, i * 16384, None)
                 }
             })
             .collect();
-        Self { index, blocks }
+        Self { blocks }
     }
 }
 
 #[derive(Debug)]
 enum BlockState {
     Open,
-    Requested,
-    Writing,
+    /// the peers the block was requested from, more than one during the endgame, and when it was last requested
+    Requested {
+        peer_ids: Vec<Vec<u8>>,
+        since: Instant,
+    },
     Finished,
 }
 
@@ -456,6 +1256,15 @@ impl Block {
             state,
         }
     }
+    /// The peer won't send the block, it is open again unless it is requested from other peers as well
+    fn release(&mut self, peer_id: &[u8]) {
+        if let BlockState::Requested { peer_ids, .. } = &mut self.state {
+            peer_ids.retain(|requested| requested != peer_id);
+            if peer_ids.is_empty() {
+                self.state = BlockState::Open;
+            }
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -511,6 +1320,25 @@ impl DownloadedPiece {
     }
 }
 
+/// Transfer counters reported to the tracker
+#[derive(Debug)]
+pub struct TransferStats {
+    /// bytes sent to peers
+    pub uploaded: AtomicU64,
+    /// bytes received from peers
+    pub downloaded: AtomicU64,
+    /// bytes which haven't been written to disk yet
+    pub left: AtomicU64,
+}
+
+/// A block which arrived while it was also requested from other peers during the endgame
+#[derive(Debug, Clone)]
+pub struct EndgameCancel {
+    pub block: BlockInfo,
+    /// peers which have to cancel their request
+    pub peer_ids: Vec<Vec<u8>>,
+}
+
 /// Commands that will be sent over the Message Channel
 #[derive(Debug)]
 pub enum Command {
@@ -518,20 +1346,279 @@ pub enum Command {
         peer_id: Vec<u8>,
         bitfield: BitVec<Msb0, u8>,
     },
-    PickInitialPieces {
+    PickBlocks {
         peer_id: Vec<u8>,
+        /// only allowed fast pieces can be requested from a peer which chokes us
+        choked: bool,
+        /// free slots in the peer's request queue
+        count: usize,
         transmitter: oneshot::Sender<Command>,
     },
-    PickPiece {
+    SelectedBlocks(Vec<Block>),
+    NoPiece,
+    DownloadedBlock {
+        /// ip of the peer which sent the block
+        ip: String,
         peer_id: Vec<u8>,
-        transmitter: oneshot::Sender<Command>,
+        block: DownloadedBlock,
     },
-    SelectedInitialPieces(Vec<Option<Block>>),
-    SelectedPiece(Block),
-    NoPiece,
-    DownloadedBlock(DownloadedBlock),
     HavePiece {
         peer_id: Vec<u8>,
         piece_index: usize,
     },
+    HaveAll {
+        peer_id: Vec<u8>,
+    },
+    SuggestPiece {
+        peer_id: Vec<u8>,
+        piece_index: u32,
+    },
+    AllowedFast {
+        peer_id: Vec<u8>,
+        piece_index: u32,
+    },
+    /// the peer won't send a block we requested
+    RejectedBlock {
+        peer_id: Vec<u8>,
+        piece_index: u32,
+        begin: u32,
+    },
+    /// the connection to the peer was closed
+    PeerDisconnected {
+        peer_id: Vec<u8>,
+    },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{Layout, MemoryStorage};
+    use tokio::sync::mpsc;
+
+    fn piece_picker(total_pieces: u32) -> PiecePicker {
+        let (send_to_disk_manager, _) = mpsc::unbounded_channel();
+        let stats = Arc::new(TransferStats {
+            uploaded: AtomicU64::new(0),
+            downloaded: AtomicU64::new(0),
+            left: AtomicU64::new(0),
+        });
+        PiecePicker::new(
+            total_pieces,
+            vec![[0; 20]; total_pieces as usize],
+            16384,
+            total_pieces as u64 * 16384,
+            send_to_disk_manager,
+            stats,
+            Arc::new(BanList::default()),
+        )
+    }
+
+    #[test]
+    fn test_allowed_fast_and_suggested_pieces() {
+        let mut picker = piece_picker(4);
+        let peer_id = vec![1; 20];
+        picker.register_bitfield(peer_id.clone(), BitVec::repeat(true, 4));
+
+        // nothing can be requested from a choking peer without allowed fast pieces
+        assert!(picker.pick_piece(&peer_id, true).is_none());
+        picker.allow_fast(peer_id.clone(), 2);
+        assert_eq!(picker.pick_piece(&peer_id, true).unwrap().piece_index, 2);
+        assert!(picker.pick_piece(&peer_id, true).is_none());
+
+        // a rejected block can be picked again
+        picker.release_block(&peer_id, 2, 0);
+        assert_eq!(picker.pick_piece(&peer_id, true).unwrap().piece_index, 2);
+
+        picker.suggest_piece(peer_id.clone(), 3);
+        assert_eq!(picker.pick_piece(&peer_id, false).unwrap().piece_index, 3);
+    }
+
+    #[test]
+    fn test_hash_failure() {
+        // none of the pieces hash to the zeroed hashes of the test picker
+        let mut picker = piece_picker(2);
+        let peer_id = vec![1; 20];
+        picker.register_bitfield(peer_id.clone(), BitVec::repeat(true, 2));
+
+        let block = picker.pick_piece(&peer_id, false).unwrap();
+        for _ in 0..MAX_HASH_FAILURES {
+            assert!(!picker.banned.contains("10.0.0.1"));
+            let data = DownloadedBlock::new(block.piece_index, 0, vec![1; 16384]);
+            picker.downloaded_block("10.0.0.1".to_string(), peer_id.clone(), data);
+            // the piece has to be downloaded again
+            assert!(picker.downloaded_pieces.is_empty());
+            let retry = picker.pick_piece(&peer_id, false).unwrap();
+            assert_eq!(retry.piece_index, block.piece_index);
+        }
+        assert!(picker.banned.contains("10.0.0.1"));
+    }
+
+    #[test]
+    fn test_hash_failure_with_several_peers() -> Result<()> {
+        // a single piece of two blocks
+        let mut picker = piece_picker(1);
+        picker.piece_length = 2 * 16384;
+        picker.file_length = 2 * 16384;
+        let data = [vec![1; 16384], vec![2; 16384]].concat();
+        let sha1 = digest::digest(&digest::SHA1_FOR_LEGACY_USE_ONLY, &data);
+        picker.piece_hashes[0] = sha1.as_ref().try_into()?;
+        picker.register_bitfield(vec![1; 20], BitVec::repeat(true, 1));
+        assert!(picker.pick_piece(&[1; 20], false).is_some());
+        assert!(picker.pick_piece(&[1; 20], false).is_some());
+
+        // it isn't known yet which of the peers sent the corrupt block
+        let first = DownloadedBlock::new(0, 0, vec![1; 16384]);
+        picker.downloaded_block("10.0.0.1".to_string(), vec![1; 20], first);
+        let corrupt = DownloadedBlock::new(0, 16384, vec![3; 16384]);
+        picker.downloaded_block("10.0.0.2".to_string(), vec![2; 20], corrupt);
+        assert!(picker.hash_failures.is_empty());
+
+        // the peer whose block differs from the copy which passes sent it
+        let first = DownloadedBlock::new(0, 0, vec![1; 16384]);
+        picker.downloaded_block("10.0.0.1".to_string(), vec![1; 20], first);
+        let second = DownloadedBlock::new(0, 16384, vec![2; 16384]);
+        picker.downloaded_block("10.0.0.3".to_string(), vec![3; 20], second);
+        assert_eq!(picker.hash_failures.get("10.0.0.2"), Some(&1));
+        assert_eq!(picker.hash_failures.len(), 1);
+        assert!(picker.failed_blocks.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_availability() {
+        let mut picker = piece_picker(3);
+        let (first, second) = (vec![1; 20], vec![2; 20]);
+        // haves without a bitfield, one of them twice
+        picker.register_have(first.clone(), 1);
+        picker.register_have(first.clone(), 1);
+        picker.register_have(first.clone(), 2);
+        // a second bitfield replaces the first one
+        picker.register_bitfield(second.clone(), BitVec::repeat(true, 3));
+        picker.register_bitfield(second.clone(), BitVec::repeat(true, 3));
+        picker.register_have(second.clone(), 0);
+        // pieces past the end are dropped
+        picker.register_have(first.clone(), 3);
+        let counts: Vec<u32> = picker
+            .piece_map
+            .iter()
+            .map(|piece| piece.peer_count)
+            .collect();
+        assert_eq!(counts, vec![1, 2, 2]);
+
+        picker.release_peer(&first);
+        picker.release_peer(&second);
+        assert!(picker.piece_map.iter().all(|piece| piece.peer_count == 0));
+        assert!(picker.peer_bitfields.is_empty());
+        assert!(picker
+            .priority_boundaries
+            .iter()
+            .all(|&boundary| boundary == 3));
+    }
+
+    #[test]
+    fn test_unrequested_blocks() {
+        let mut picker = piece_picker(2);
+        // blocks of pieces which weren't picked, or past the end, are dropped
+        let data = DownloadedBlock::new(0, 0, vec![1; 16384]);
+        picker.downloaded_block("10.0.0.1".to_string(), vec![1; 20], data);
+        let data = DownloadedBlock::new(2, 0, vec![1; 16384]);
+        picker.downloaded_block("10.0.0.1".to_string(), vec![1; 20], data);
+        assert!(picker.downloaded_pieces.is_empty());
+        assert!(picker.hash_failures.is_empty());
+    }
+
+    #[test]
+    fn test_release_peer() {
+        let mut picker = piece_picker(2);
+        let (first, second) = (vec![1; 20], vec![2; 20]);
+        picker.register_bitfield(first.clone(), BitVec::repeat(true, 2));
+        picker.register_bitfield(second.clone(), BitVec::repeat(true, 2));
+
+        let block = picker.pick_piece(&first, false).unwrap();
+        // the block requested from the first peer goes to the second one
+        picker.release_peer(&first);
+        assert!(picker.piece_map.iter().all(|piece| piece.peer_count == 1));
+        assert!(picker.pick_piece(&first, false).is_none());
+        let retry = picker.pick_piece(&second, false).unwrap();
+        assert_eq!(retry.piece_index, block.piece_index);
+        assert_eq!(retry.begin, block.begin);
+    }
+
+    #[test]
+    fn test_release_stalled() -> Result<()> {
+        let mut picker = piece_picker(1);
+        let peer_id = vec![1; 20];
+        picker.register_bitfield(peer_id.clone(), BitVec::repeat(true, 1));
+
+        assert!(picker.pick_piece(&peer_id, false).is_some());
+        let requested = Instant::now();
+        picker.release_stalled(requested - REQUEST_TIMEOUT);
+        assert!(picker.pick_piece(&peer_id, false).is_none());
+        picker.release_stalled(requested + REQUEST_TIMEOUT);
+        assert!(picker.pick_piece(&peer_id, false).is_some());
+
+        // blocks which arrived aren't requested again
+        let data = vec![1; 16384];
+        let sha1 = digest::digest(&digest::SHA1_FOR_LEGACY_USE_ONLY, &data);
+        picker.piece_hashes[0] = sha1.as_ref().try_into()?;
+        let data = DownloadedBlock::new(0, 0, data);
+        picker.downloaded_block("10.0.0.1".to_string(), peer_id.clone(), data);
+        picker.release_stalled(requested + REQUEST_TIMEOUT);
+        assert!(picker.pick_piece(&peer_id, false).is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_endgame() {
+        let mut picker = piece_picker(1);
+        let mut receive_cancels = picker.cancels().subscribe();
+        let (first, second) = (vec![1; 20], vec![2; 20]);
+        picker.register_bitfield(first.clone(), BitVec::repeat(true, 1));
+        picker.register_bitfield(second.clone(), BitVec::repeat(true, 1));
+
+        let block = picker.pick_piece(&first, false).unwrap();
+        assert!(picker.pick_piece(&first, false).is_none());
+        // the only block is requested from both peers
+        let duplicate = picker.pick_piece(&second, false).unwrap();
+        assert_eq!(
+            (duplicate.piece_index, duplicate.begin),
+            (block.piece_index, block.begin)
+        );
+        assert!(picker.pick_piece(&second, false).is_none());
+
+        // the second peer cancels once the first copy arrives
+        let data = DownloadedBlock::new(block.piece_index, block.begin, vec![1; 16384]);
+        picker.downloaded_block("10.0.0.1".to_string(), first, data);
+        let cancel = receive_cancels.try_recv().unwrap();
+        assert_eq!(cancel.block.begin, block.begin);
+        assert_eq!(cancel.peer_ids, vec![second]);
+    }
+
+    #[test]
+    fn test_resume() {
+        let mut picker = piece_picker(3);
+        let peer_id = vec![1; 20];
+        picker.register_bitfield(peer_id.clone(), BitVec::repeat(true, 3));
+        let mut have = BitVec::repeat(false, 3);
+        have.set(0, true);
+        let partial = vec![PartialPiece {
+            index: 1,
+            blocks: vec![PartialBlock {
+                begin: 0,
+                data: ByteBuf::from(vec![1; 16384]),
+            }],
+        }];
+        let storage = MemoryStorage::new(Layout::new(vec![(PathBuf::from("a"), 3 * 16384)], 16384));
+        let resume = ResumeFile::new(
+            PathBuf::from("resume"),
+            vec![],
+            DiskHandle::new(Box::new(storage)),
+        );
+        picker.resume(&have, partial.clone(), resume);
+        assert_eq!(picker.partial_pieces(), partial);
+
+        // only the missing piece is left
+        assert_eq!(picker.pick_piece(&peer_id, false).unwrap().piece_index, 2);
+        assert!(picker.pick_piece(&peer_id, false).is_none());
+    }
 }
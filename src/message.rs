@@ -26,6 +26,20 @@ pub enum Msg {
     },
     /// cancel: <len=0013><id=8><index><begin><length>
     Cancel { index: u32, begin: u32, length: u32 },
+    /// port: <len=0003><id=9><listen-port>, port of the DHT node, see BEP 5
+    Port(u16),
+    /// suggest piece: <len=0005><id=13><piece index>, see BEP 6
+    SuggestPiece(u32),
+    /// have all: <len=0001><id=14>
+    HaveAll,
+    /// have none: <len=0001><id=15>
+    HaveNone,
+    /// reject request: <len=0013><id=16><index><begin><length>
+    RejectRequest { index: u32, begin: u32, length: u32 },
+    /// allowed fast: <len=0005><id=17><piece index>
+    AllowedFast(u32),
+    /// extended: <len=0002+X><id=20><extended message id><payload>, see BEP 10
+    Extended { id: u8, payload: Vec<u8> },
 }
 
 impl Msg {
@@ -60,6 +74,25 @@ impl Msg {
                 begin: u32::from_be_bytes([payload[5], payload[6], payload[7], payload[8]]),
                 length: u32::from_be_bytes([payload[9], payload[10], payload[11], payload[12]]),
             },
+            9 if payload.len() == 3 => Msg::Port(u16::from_be_bytes([payload[1], payload[2]])),
+            13 if payload.len() == 5 => Msg::SuggestPiece(u32::from_be_bytes([
+                payload[1], payload[2], payload[3], payload[4],
+            ])),
+            14 => Msg::HaveAll,
+            15 => Msg::HaveNone,
+            16 if payload.len() == 13 => Msg::RejectRequest {
+                index: u32::from_be_bytes([payload[1], payload[2], payload[3], payload[4]]),
+                begin: u32::from_be_bytes([payload[5], payload[6], payload[7], payload[8]]),
+                length: u32::from_be_bytes([payload[9], payload[10], payload[11], payload[12]]),
+            },
+            17 if payload.len() == 5 => Msg::AllowedFast(u32::from_be_bytes([
+                payload[1], payload[2], payload[3], payload[4],
+            ])),
+            9 | 13 | 16 | 17 => Err("Message has an invalid length")?,
+            20 => Msg::Extended {
+                id: *payload.get(1).ok_or("Extended message ID is missing")?,
+                payload: payload[2..].to_vec(),
+            },
             _ => Err("Message ID is invalid")?,
         };
         Ok(msg)
@@ -136,6 +169,49 @@ impl Msg {
                 message_buffer.extend_from_slice(&(begin.to_be_bytes()));
                 message_buffer.extend_from_slice(&(length.to_be_bytes()));
             }
+            // port: <len=0003><id=9><listen-port>
+            Msg::Port(port) => {
+                message_buffer.extend_from_slice(&[0, 0, 0, 3, 9]);
+                message_buffer.extend_from_slice(&(port.to_be_bytes()));
+            }
+            // suggest piece: <len=0005><id=13><piece index>
+            Msg::SuggestPiece(index) => {
+                message_buffer.extend_from_slice(&[0, 0, 0, 5, 13]);
+                message_buffer.extend_from_slice(&(index.to_be_bytes()));
+            }
+            // have all: <len=0001><id=14>
+            Msg::HaveAll => {
+                message_buffer.extend_from_slice(&[0, 0, 0, 1, 14]);
+            }
+            // have none: <len=0001><id=15>
+            Msg::HaveNone => {
+                message_buffer.extend_from_slice(&[0, 0, 0, 1, 15]);
+            }
+            // reject request: <len=0013><id=16><index><begin><length>
+            Msg::RejectRequest {
+                index,
+                begin,
+                length,
+            } => {
+                message_buffer.extend_from_slice(&[0, 0, 0, 13, 16]);
+
+                message_buffer.extend_from_slice(&(index.to_be_bytes()));
+                message_buffer.extend_from_slice(&(begin.to_be_bytes()));
+                message_buffer.extend_from_slice(&(length.to_be_bytes()));
+            }
+            // allowed fast: <len=0005><id=17><piece index>
+            Msg::AllowedFast(index) => {
+                message_buffer.extend_from_slice(&[0, 0, 0, 5, 17]);
+                message_buffer.extend_from_slice(&(index.to_be_bytes()));
+            }
+            // extended: <len=0002+X><id=20><extended message id><payload>
+            Msg::Extended { id, payload } => {
+                let message_len: u32 = 2 + payload.len() as u32;
+                message_buffer.extend_from_slice(&(message_len.to_be_bytes()));
+                message_buffer.extend_from_slice(&[20, id]);
+
+                message_buffer.extend_from_slice(&payload);
+            }
         }
         message_buffer
     }
@@ -224,4 +300,69 @@ mod tests {
         );
         Ok(())
     }
+    #[test]
+    fn test_port_msg() -> Result<()> {
+        let msg = Msg::Port(6881).get_message();
+        assert_eq!(msg, &[0, 0, 0, 3, 9, 0x1a, 0xe1]);
+        match Msg::parse(msg[4..].to_vec())? {
+            Msg::Port(6881) => {}
+            msg => panic!("Unexpected message {:?}", msg),
+        }
+        assert!(Msg::parse(vec![9, 0x1a]).is_err());
+        assert!(Msg::parse(vec![9, 0x1a, 0xe1, 0]).is_err());
+        Ok(())
+    }
+    #[test]
+    fn test_fast_msgs() -> Result<()> {
+        assert_eq!(Msg::HaveAll.get_message(), &[0, 0, 0, 1, 14]);
+        assert_eq!(Msg::HaveNone.get_message(), &[0, 0, 0, 1, 15]);
+        assert_eq!(
+            Msg::SuggestPiece(10).get_message(),
+            &[0, 0, 0, 5, 13, 0, 0, 0, 10]
+        );
+        assert_eq!(
+            Msg::AllowedFast(10).get_message(),
+            &[0, 0, 0, 5, 17, 0, 0, 0, 10]
+        );
+        let msg = Msg::RejectRequest {
+            index: 309,
+            begin: 0,
+            length: 16384,
+        }
+        .get_message();
+        assert_eq!(
+            msg,
+            &[0, 0, 0, 13, 16, 0, 0, 1, 53, 0, 0, 0, 0, 0, 0, 64, 0]
+        );
+        match Msg::parse(msg[4..].to_vec())? {
+            Msg::RejectRequest {
+                index: 309,
+                begin: 0,
+                length: 16384,
+            } => {}
+            msg => panic!("Unexpected message {:?}", msg),
+        }
+        // truncated messages from a peer are errors
+        assert!(Msg::parse(vec![13, 0, 0]).is_err());
+        assert!(Msg::parse(vec![16, 0, 0, 1, 53, 0, 0, 0, 0]).is_err());
+        assert!(Msg::parse(vec![17]).is_err());
+        Ok(())
+    }
+    #[test]
+    fn test_extended_msg() -> Result<()> {
+        let msg = Msg::Extended {
+            id: 3,
+            payload: b"de".to_vec(),
+        }
+        .get_message();
+        assert_eq!(msg, &[0, 0, 0, 4, 20, 3, b'd', b'e']);
+        match Msg::parse(msg[4..].to_vec())? {
+            Msg::Extended { id, payload } => {
+                assert_eq!(id, 3);
+                assert_eq!(payload, b"de");
+            }
+            msg => panic!("Unexpected message {:?}", msg),
+        }
+        Ok(())
+    }
 }
@@ -0,0 +1,410 @@
+use serde_bencode::{de, ser};
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio::task::JoinHandle;
+use tokio::time;
+
+use crate::extension::{self, ExtendedHandshake, Extension};
+use crate::peer::Handshake;
+use crate::torrent::generate_info_hash;
+use crate::tracker::TrackerPeer;
+use crate::{bencode, message::Msg, Result};
+
+/// extended message id under which we receive ut_metadata messages
+const UT_METADATA_ID: u8 = 1;
+/// the metadata is exchanged in pieces of 16KiB
+const METADATA_PIECE_LENGTH: usize = 16384;
+/// longest message read while fetching the metadata, a metadata piece along with its header
+const MAX_MESSAGE_LENGTH: usize = METADATA_PIECE_LENGTH + 1024;
+/// don't trust a peer with allocating arbitrary amounts of memory
+const MAX_METADATA_SIZE: usize = 16 * 1024 * 1024;
+/// time a single peer gets to hand over the whole metadata
+const PEER_TIMEOUT: Duration = Duration::from_secs(30);
+
+const REQUEST: u8 = 0;
+const DATA: u8 = 1;
+const REJECT: u8 = 2;
+
+/// Dictionary at the start of every ut_metadata message, data messages are followed by the piece
+#[derive(Debug, Deserialize, Serialize)]
+struct MetadataMsg {
+    msg_type: u8,
+    piece: usize,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    total_size: Option<usize>,
+}
+
+/// Serves the info dictionary to peers which only have the magnet link.
+/// Has to be the first extension in the registry since `UT_METADATA_ID` is used for fetching.
+pub struct UtMetadata {
+    info_bytes: Arc<Vec<u8>>,
+}
+
+impl UtMetadata {
+    pub fn new(info_bytes: Arc<Vec<u8>>) -> Self {
+        Self { info_bytes }
+    }
+}
+
+impl Extension for UtMetadata {
+    fn name(&self) -> &'static str {
+        "ut_metadata"
+    }
+
+    fn extend_handshake(&self, handshake: &mut ExtendedHandshake) {
+        handshake.metadata_size = Some(self.info_bytes.len());
+    }
+
+    fn on_message(&mut self, payload: &[u8]) -> Result<Vec<Vec<u8>>> {
+        let dict_end = bencode::value_end(payload, 0)?;
+        let request = de::from_bytes::<MetadataMsg>(&payload[..dict_end])?;
+        if request.msg_type != REQUEST {
+            return Ok(vec![]);
+        }
+
+        // the piece comes from the peer, anything beyond the metadata is rejected
+        let total_pieces = self.info_bytes.len().div_ceil(METADATA_PIECE_LENGTH);
+        let start = match request.piece.checked_mul(METADATA_PIECE_LENGTH) {
+            Some(start) if request.piece < total_pieces => start,
+            _ => {
+                let reject = MetadataMsg {
+                    msg_type: REJECT,
+                    piece: request.piece,
+                    total_size: None,
+                };
+                return Ok(vec![ser::to_bytes(&reject)?]);
+            }
+        };
+        let end = (start + METADATA_PIECE_LENGTH).min(self.info_bytes.len());
+        let data = MetadataMsg {
+            msg_type: DATA,
+            piece: request.piece,
+            total_size: Some(self.info_bytes.len()),
+        };
+        let mut response = ser::to_bytes(&data)?;
+        response.extend_from_slice(&self.info_bytes[start..end]);
+        Ok(vec![response])
+    }
+}
+
+/// Fetch the info dictionary of a magnet link from the peers, see BEP 9.
+/// Every peer received over `receive_peers` is tried until one of them sends metadata matching `info_hash`.
+pub async fn fetch_metadata(
+    mut receive_peers: UnboundedReceiver<Vec<TrackerPeer>>,
+    info_hash: Vec<u8>,
+    peer_id: Vec<u8>,
+) -> Result<Vec<u8>> {
+    let (send_metadata, mut receive_metadata) = mpsc::unbounded_channel::<Vec<u8>>();
+    // dropped once there are no more peers, so that the receiver closes after the last attempt
+    let mut send_metadata = Some(send_metadata);
+    let mut known_peers = HashSet::new();
+    let mut handles = vec![];
+
+    let metadata = loop {
+        tokio::select! {
+            peers = receive_peers.recv(), if send_metadata.is_some() => match (peers, &send_metadata) {
+                (Some(peers), Some(send_metadata)) => {
+                    for peer in peers {
+                        if !known_peers.insert((peer.ip.clone(), peer.port)) {
+                            continue;
+                        }
+                        handles.push(spawn_fetch(peer, &info_hash, &peer_id, send_metadata.clone()));
+                    }
+                }
+                _ => send_metadata = None,
+            },
+            metadata = receive_metadata.recv() => break metadata,
+        }
+    };
+
+    for handle in handles {
+        handle.abort();
+    }
+    Ok(metadata.ok_or("None of the peers sent the metadata")?)
+}
+
+/// Try to fetch the metadata from a single peer, giving up after `PEER_TIMEOUT`
+fn spawn_fetch(
+    peer: TrackerPeer,
+    info_hash: &[u8],
+    peer_id: &[u8],
+    send_metadata: UnboundedSender<Vec<u8>>,
+) -> JoinHandle<()> {
+    let info_hash = info_hash.to_vec();
+    let peer_id = peer_id.to_vec();
+    tokio::spawn(async move {
+        let fetch = fetch_from_peer(&peer, &info_hash, &peer_id);
+        match time::timeout(PEER_TIMEOUT, fetch).await {
+            // the metadata may already have arrived from another peer
+            Ok(Ok(metadata)) => send_metadata.send(metadata).unwrap_or(()),
+            Ok(Err(e)) => eprintln!("Metadata from {}:{} failed: {}", peer.ip, peer.port, e),
+            Err(_) => eprintln!("Metadata from {}:{} timed out", peer.ip, peer.port),
+        }
+    })
+}
+
+/// Download all the metadata pieces from a single peer and verify them against the info hash
+async fn fetch_from_peer(
+    peer: &TrackerPeer,
+    info_hash: &Vec<u8>,
+    peer_id: &Vec<u8>,
+) -> Result<Vec<u8>> {
+    let mut stream = TcpStream::connect((peer.ip.as_str(), peer.port)).await?;
+    let handshake = Handshake::new(info_hash, peer_id, false).generate_handshake();
+    stream.write_all(&handshake).await?;
+
+    let mut received_handshake = [0; 68];
+    stream.read_exact(&mut received_handshake).await?;
+    if received_handshake[28..48] != info_hash[..] {
+        Err("Info hash in the handshake does not match")?;
+    }
+    if !extension::supports_extensions(&received_handshake[20..28]) {
+        Err("Peer does not support the extension protocol")?;
+    }
+
+    let handshake = ExtendedHandshake {
+        m: vec![("ut_metadata".to_string(), UT_METADATA_ID as i64)]
+            .into_iter()
+            .collect(),
+        ..Default::default()
+    };
+    let payload = ser::to_bytes(&handshake)?;
+    stream
+        .write_all(&Msg::Extended { id: 0, payload }.get_message())
+        .await?;
+
+    // wait for the extended handshake of the peer
+    let (ut_metadata, metadata_size) = loop {
+        let (id, payload) = read_extended(&mut stream).await?;
+        if id != 0 {
+            continue;
+        }
+        let handshake = de::from_bytes::<ExtendedHandshake>(&payload)?;
+        let ut_metadata = match handshake.m.get("ut_metadata") {
+            Some(&id) if id > 0 && id <= u8::MAX as i64 => id as u8,
+            _ => Err("Peer does not support ut_metadata")?,
+        };
+        let metadata_size = handshake.metadata_size.ok_or("Metadata size is missing")?;
+        if metadata_size == 0 || metadata_size > MAX_METADATA_SIZE {
+            Err(format!("Invalid metadata size: {}", metadata_size))?;
+        }
+        break (ut_metadata, metadata_size);
+    };
+
+    let total_pieces = metadata_size.div_ceil(METADATA_PIECE_LENGTH);
+    let mut metadata = Vec::with_capacity(metadata_size);
+    for piece in 0..total_pieces {
+        let request = MetadataMsg {
+            msg_type: REQUEST,
+            piece,
+            total_size: None,
+        };
+        let payload = ser::to_bytes(&request)?;
+        stream
+            .write_all(
+                &Msg::Extended {
+                    id: ut_metadata,
+                    payload,
+                }
+                .get_message(),
+            )
+            .await?;
+
+        let data = loop {
+            let (id, payload) = read_extended(&mut stream).await?;
+            if id != UT_METADATA_ID {
+                continue;
+            }
+            // the piece data follows the bencoded dictionary
+            let dict_end = bencode::value_end(&payload, 0)?;
+            let msg = de::from_bytes::<MetadataMsg>(&payload[..dict_end])?;
+            match msg.msg_type {
+                DATA if msg.piece == piece => break payload[dict_end..].to_vec(),
+                REJECT => Err("Peer rejected the metadata request")?,
+                _ => {}
+            }
+        };
+        // every piece except the last one is exactly 16KiB
+        let expected_length = METADATA_PIECE_LENGTH.min(metadata_size - metadata.len());
+        if data.len() != expected_length {
+            Err(format!("Metadata piece #{} has an invalid length", piece))?;
+        }
+        metadata.extend_from_slice(&data);
+    }
+
+    if generate_info_hash(&metadata) != *info_hash {
+        Err("Metadata does not match the info hash")?;
+    }
+    Ok(metadata)
+}
+
+/// Read messages until an extended message arrives, everything else is skipped
+async fn read_extended(stream: &mut TcpStream) -> Result<(u8, Vec<u8>)> {
+    loop {
+        let mut buffer = [0; 4];
+        stream.read_exact(&mut buffer).await?;
+        let payload_length = u32::from_be_bytes(buffer);
+        // keep alive message
+        if payload_length == 0 {
+            continue;
+        }
+        // the length comes from the peer, it is checked before anything is allocated
+        if payload_length as usize > MAX_MESSAGE_LENGTH {
+            Err("Message is too long")?;
+        }
+        let mut buffer = vec![0; payload_length as usize];
+        stream.read_exact(&mut buffer).await?;
+        // other messages might use ids which `Msg` doesn't know about
+        if buffer[0] != 20 {
+            continue;
+        }
+        if let Msg::Extended { id, payload } = Msg::parse(buffer)? {
+            return Ok((id, payload));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    /// Serve `metadata` over ut_metadata to a single connection
+    async fn fake_peer(listener: TcpListener, info_hash: Vec<u8>, metadata: Vec<u8>) -> Result<()> {
+        let (mut stream, _) = listener.accept().await?;
+        let mut handshake = [0; 68];
+        stream.read_exact(&mut handshake).await?;
+        stream
+            .write_all(&Handshake::new(&info_hash, &vec![3; 20], false).generate_handshake())
+            .await?;
+
+        // announce ut_metadata under a different id than the client uses
+        let handshake = ExtendedHandshake {
+            m: vec![("ut_metadata".to_string(), 3)].into_iter().collect(),
+            metadata_size: Some(metadata.len()),
+            ..Default::default()
+        };
+        let payload = ser::to_bytes(&handshake)?;
+        stream
+            .write_all(&Msg::Extended { id: 0, payload }.get_message())
+            .await?;
+
+        let total_pieces = metadata.len().div_ceil(METADATA_PIECE_LENGTH);
+        for _ in 0..total_pieces {
+            let (id, payload) = loop {
+                match read_extended(&mut stream).await? {
+                    (0, _) => continue,
+                    msg => break msg,
+                }
+            };
+            assert_eq!(id, 3);
+            let request = de::from_bytes::<MetadataMsg>(&payload)?;
+            assert_eq!(request.msg_type, REQUEST);
+
+            let start = request.piece * METADATA_PIECE_LENGTH;
+            let end = (start + METADATA_PIECE_LENGTH).min(metadata.len());
+            let response = MetadataMsg {
+                msg_type: DATA,
+                piece: request.piece,
+                total_size: Some(metadata.len()),
+            };
+            let mut payload = ser::to_bytes(&response)?;
+            payload.extend_from_slice(&metadata[start..end]);
+            stream
+                .write_all(
+                    &Msg::Extended {
+                        id: UT_METADATA_ID,
+                        payload,
+                    }
+                    .get_message(),
+                )
+                .await?;
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_fetch_metadata() -> Result<()> {
+        // spans two metadata pieces
+        let metadata: Vec<u8> = (0..20000).map(|i| i as u8).collect();
+        let info_hash = generate_info_hash(&metadata);
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let port = listener.local_addr()?.port();
+        let server = tokio::spawn(fake_peer(listener, info_hash.clone(), metadata.clone()));
+
+        let (send_peers, receive_peers) = mpsc::unbounded_channel();
+        let peer = TrackerPeer {
+            ip: "127.0.0.1".to_string(),
+            port,
+            peer_id: None,
+        };
+        // the same peer from two sources is only tried once
+        send_peers.send(vec![peer.clone(), peer])?;
+        drop(send_peers);
+
+        let fetched = fetch_metadata(receive_peers, info_hash, vec![2; 20]).await?;
+        assert_eq!(fetched, metadata);
+        server.await??;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_metadata_hash_mismatch() -> Result<()> {
+        let metadata = b"d4:name4:teste".to_vec();
+        let info_hash = vec![1; 20];
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let port = listener.local_addr()?.port();
+        let server = tokio::spawn(fake_peer(listener, info_hash.clone(), metadata));
+
+        let (send_peers, receive_peers) = mpsc::unbounded_channel();
+        send_peers.send(vec![TrackerPeer {
+            ip: "127.0.0.1".to_string(),
+            port,
+            peer_id: None,
+        }])?;
+        drop(send_peers);
+
+        assert!(fetch_metadata(receive_peers, info_hash, vec![2; 20])
+            .await
+            .is_err());
+        server.await??;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_message_too_long() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let mut client = TcpStream::connect(listener.local_addr()?).await?;
+        let (mut server, _) = listener.accept().await?;
+        server.write_all(&u32::MAX.to_be_bytes()).await?;
+        assert!(read_extended(&mut client).await.is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_serve_metadata() -> Result<()> {
+        let metadata: Vec<u8> = (0..20000).map(|i| i as u8).collect();
+        let mut ut_metadata = UtMetadata::new(Arc::new(metadata.clone()));
+        let mut handshake = ExtendedHandshake::default();
+        ut_metadata.extend_handshake(&mut handshake);
+        assert_eq!(handshake.metadata_size, Some(20000));
+
+        let response = ut_metadata.on_message(b"d8:msg_typei0e5:piecei1ee")?;
+        let mut expected = b"d8:msg_typei1e5:piecei1e10:total_sizei20000ee".to_vec();
+        expected.extend_from_slice(&metadata[METADATA_PIECE_LENGTH..]);
+        assert_eq!(response, vec![expected]);
+
+        let response = ut_metadata.on_message(b"d8:msg_typei0e5:piecei2ee")?;
+        assert_eq!(response, vec![b"d8:msg_typei2e5:piecei2ee".to_vec()]);
+        // the offset of the piece would overflow
+        let huge = format!("d8:msg_typei0e5:piecei{}ee", i64::MAX);
+        let response = ut_metadata.on_message(huge.as_bytes())?;
+        assert!(response[0].starts_with(b"d8:msg_typei2e"));
+        Ok(())
+    }
+}
@@ -1,11 +1,31 @@
+use bitvec::prelude::BitVec;
+use std::collections::{HashSet, VecDeque};
+use std::future;
+use std::net::SocketAddr;
+use std::sync::{atomic::Ordering, Arc};
+use std::time::Duration;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
 use tokio::net::TcpStream;
-use tokio::sync::{mpsc::UnboundedSender, oneshot};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::sync::mpsc::{self, Receiver, Sender, UnboundedReceiver, UnboundedSender};
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+use tokio::time::{self, Instant};
 
-use crate::{manager::Command, message::Msg};
-use crate::{manager::DownloadedBlock, Result};
+use crate::choker::{ChokerCommand, PeerStats};
+use crate::dht::{self, Dht, DHT_BIT};
+use crate::disk::{BlockInfo, DiskHandle};
+use crate::extension::{self, ExtensionRegistry, EXTENSION_PROTOCOL_BIT, MAX_REQUESTS};
+use crate::fast::{self, FAST_EXTENSION_BIT};
+use crate::manager::{
+    BanList, Command, DownloadedBlock, EndgameCancel, TransferStats, REQUEST_TIMEOUT,
+};
+use crate::pex::{self, ConnectedPeers};
+use crate::pipeline::Pipeline;
+use crate::{message::Msg, Result};
 
-struct Handshake<'a> {
+pub struct Handshake<'a> {
     info_hash: &'a Vec<u8>,
     peer_id: &'a Vec<u8>,
     reserved_bytes: Vec<u8>,
@@ -14,8 +34,16 @@ struct Handshake<'a> {
 }
 
 impl<'a> Handshake<'a> {
-    pub fn new(info_hash: &'a Vec<u8>, peer_id: &'a Vec<u8>) -> Self {
-        let reserved_bytes = vec![0; 8];
+    /// `dht` advertises that we run a DHT node
+    pub fn new(info_hash: &'a Vec<u8>, peer_id: &'a Vec<u8>, dht: bool) -> Self {
+        let mut reserved_bytes = vec![0; 8];
+        for (byte, bit) in [EXTENSION_PROTOCOL_BIT, FAST_EXTENSION_BIT] {
+            reserved_bytes[byte] |= bit;
+        }
+        if dht {
+            let (byte, bit) = DHT_BIT;
+            reserved_bytes[byte] |= bit;
+        }
         let protocol = b"BitTorrent protocol".to_vec();
         let protocol_length = vec![19];
         Handshake {
@@ -47,8 +75,13 @@ impl<'a> Handshake<'a> {
     }
 }
 
-#[derive(Debug)]
-enum ChokeState {
+/// how often the extensions get to send their periodic messages, ut_pex allows one per minute
+const EXTENSION_INTERVAL: Duration = Duration::from_secs(60);
+/// larger requests are rejected, clients request 16 KiB blocks
+const MAX_BLOCK_LENGTH: u32 = 128 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ChokeState {
     Unchoked,
     Choked,
 }
@@ -59,10 +92,31 @@ enum InterestState {
     NotInterested,
 }
 
+/// Parts of the torrent every peer connection talks to
+#[derive(Debug, Clone)]
+pub struct PeerShared {
+    pub transmitter: UnboundedSender<Command>,
+    /// live connections of the torrent, advertised over ut_pex
+    pub connected: Arc<ConnectedPeers>,
+    /// nodes learned from port messages are added to it
+    pub dht: Option<Dht>,
+    /// pieces we have and where the requested blocks are read from
+    pub disk: DiskHandle,
+    pub stats: Arc<TransferStats>,
+    /// decides whether we choke the peer
+    pub choker: UnboundedSender<ChokerCommand>,
+    pub banned: Arc<BanList>,
+    /// blocks to cancel during the endgame
+    pub cancels: broadcast::Sender<EndgameCancel>,
+    /// advertised in the extended handshake, `None` without a listener
+    pub listen_port: Option<u16>,
+}
+
 #[derive(Debug)]
 pub struct Peer {
     ip: String,
     port: u16,
+    // empty until the handshake if the tracker didn't tell us the peer id
     peer_id: Vec<u8>,
     // if we have choked the peer
     client_state: ChokeState,
@@ -72,45 +126,129 @@ pub struct Peer {
     peer_state: ChokeState,
     // if the peer is interested in the client
     peer_interest: InterestState,
-    transmitter: UnboundedSender<Command>,
+    extensions: ExtensionRegistry,
+    // if both sides support the fast extension
+    fast_extension: bool,
+    // pieces the peer may request while we choke it
+    allowed_fast: HashSet<u32>,
+    shared: PeerShared,
+    // blocks the peer requested which haven't been sent yet
+    requests: VecDeque<BlockInfo>,
+    // what the choker ranks the peer by
+    peer_stats: Arc<PeerStats>,
+    // blocks we requested from the peer which haven't arrived yet
+    pipeline: Pipeline,
+    // address the other peers learn over ut_pex, incoming peers need to tell us their listen port first
+    pex_addr: Option<(String, u16)>,
 }
 
 impl Peer {
     pub fn new(
         ip: String,
         port: u16,
-        peer_id: Vec<u8>,
-        transmitter: UnboundedSender<Command>,
+        peer_id: Option<Vec<u8>>,
+        extensions: ExtensionRegistry,
+        allowed_fast: HashSet<u32>,
+        shared: PeerShared,
     ) -> Self {
         Self {
             ip,
             port,
-            peer_id,
-            client_state: ChokeState::Unchoked,
-            client_interest: InterestState::Interested,
+            peer_id: peer_id.unwrap_or_default(),
+            client_state: ChokeState::Choked,
+            client_interest: InterestState::NotInterested,
             peer_state: ChokeState::Choked,
             peer_interest: InterestState::NotInterested,
-            transmitter,
+            extensions,
+            fast_extension: false,
+            allowed_fast,
+            shared,
+            requests: VecDeque::new(),
+            peer_stats: Arc::new(PeerStats::default()),
+            pipeline: Pipeline::new(Instant::now()),
+            pex_addr: None,
         }
     }
 
-    // TODO
-    /// Perform integrity check on handshake
-    /// Parse all the frames and perform appropriate action
-    /// use Bytes and tokio
-    /// read first four char and find length using it.
-    /// create a vec buffer using the message length and read using read_exact
+    /// Ask the piece picker for enough blocks to fill the request queue and request them from the peer
+    async fn request_blocks(&mut self, writer: &mut OwnedWriteHalf) -> Result<()> {
+        // the peer has nothing we need
+        if let InterestState::NotInterested = self.client_interest {
+            return Ok(());
+        }
+        let now = Instant::now();
+        // the piece picker hands stalled blocks to other peers
+        self.pipeline.expire(now, REQUEST_TIMEOUT);
+        let count = self.pipeline.free_slots();
+        // hold back while the disk can't keep up, the blocks on their way bring us back here
+        let congested = self.shared.disk.pool.is_congested() && self.pipeline.in_flight() > 0;
+        if count == 0 || congested {
+            return Ok(());
+        }
+        let choked = matches!(self.peer_state, ChokeState::Choked);
+        let (tx, rx) = oneshot::channel::<Command>();
+        self.shared.transmitter.send(Command::PickBlocks {
+            peer_id: self.peer_id.clone(),
+            choked,
+            count,
+            transmitter: tx,
+        })?;
+
+        match rx.await? {
+            Command::SelectedBlocks(blocks) => {
+                // all the requests go out in a single write
+                let mut requests = vec![];
+                for block in blocks {
+                    let req_block = Msg::Request {
+                        index: block.piece_index,
+                        length: block.length,
+                        begin: block.begin,
+                    };
+                    requests.extend_from_slice(&req_block.get_message());
+                    self.pipeline.requested(block.piece_index, block.begin, now);
+                }
+                writer.write_all(&requests).await?;
+            }
+            // the peer's pieces are done or taken by others, the connection stays open so we can seed to it
+            Command::NoPiece => {}
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Tell the peer whether we want any of its pieces, nothing is sent if that didn't change
+    async fn set_client_interest(
+        &mut self,
+        interested: bool,
+        writer: &mut OwnedWriteHalf,
+    ) -> Result<()> {
+        let msg = match (&self.client_interest, interested) {
+            (InterestState::NotInterested, true) => {
+                self.client_interest = InterestState::Interested;
+                Msg::Interested
+            }
+            (InterestState::Interested, false) => {
+                self.client_interest = InterestState::NotInterested;
+                Msg::NotInterested
+            }
+            _ => return Ok(()),
+        };
+        writer.write_all(&msg.get_message()).await?;
+        Ok(())
+    }
+
+    /// Connect to the peer, verify its handshake and exchange messages until the connection fails
     pub async fn connect(&mut self, info_hash: &Vec<u8>, client_peer_id: &Vec<u8>) -> Result<()> {
         //let timeout = std::time::Duration::new(20, 0);
-        let ip = format!("{}:{}", self.ip, self.port);
         //println!("IP-{} ", ip);
 
         // send handshake
-        let handshake = Handshake::new(info_hash, client_peer_id);
+        let handshake = Handshake::new(info_hash, client_peer_id, self.shared.dht.is_some());
         let handshake = handshake.generate_handshake();
         //println!("Sending handshake:- {}", handshake.len());
-        let mut stream = TcpStream::connect(ip).await?;
-        stream.write(&handshake).await?;
+        // connect using a (host, port) tuple so that IPv6 addresses work as well
+        let mut stream = TcpStream::connect((self.ip.as_str(), self.port)).await?;
+        stream.write_all(&handshake).await?;
 
         // receive handshake
         let mut received_handshake = [0; 68];
@@ -119,138 +257,471 @@ impl Peer {
         //println!("{:x?}", &received_handshake.to_vec());
 
         // integrity check
-        let a = &received_handshake[28..48].to_vec() == info_hash;
-        //println!("Info Check:- {}", a);
+        if received_handshake[28..48] != info_hash[..] {
+            Err("Info hash in the handshake does not match")?;
+        }
+        if !self.peer_id.is_empty() && received_handshake[48..] != self.peer_id[..] {
+            Err("Peer id in the handshake does not match")?;
+        }
+        // trackers and other peers may return our own address
+        if received_handshake[48..] == client_peer_id[..] {
+            Err("Connected to ourselves")?;
+        }
+        self.peer_id = received_handshake[48..].to_vec();
 
-        let a = &received_handshake[48..].to_vec() == &self.peer_id;
-        //println!("Peer id check:- {}", a);
+        self.start_session(stream, &received_handshake[20..28], pex::OUTGOING)
+            .await
+    }
 
-        loop {
-            let mut buffer = [0; 4];
-            stream.read_exact(&mut buffer).await?;
-
-            //println!("Message Length:- {:x?}", &buffer);
-            let payload_length = u32::from_be_bytes(buffer);
-            // keep alive message
-            if payload_length == 0 {
-                continue;
+    /// Take over a connection the peer opened, its handshake was already read by the listener
+    pub async fn accept(
+        &mut self,
+        mut stream: TcpStream,
+        received_handshake: [u8; 68],
+        client_peer_id: &Vec<u8>,
+    ) -> Result<()> {
+        let info_hash = received_handshake[28..48].to_vec();
+        if received_handshake[48..] == client_peer_id[..] {
+            Err("Connected to ourselves")?;
+        }
+        let handshake = Handshake::new(&info_hash, client_peer_id, self.shared.dht.is_some());
+        stream.write_all(&handshake.generate_handshake()).await?;
+        self.peer_id = received_handshake[48..].to_vec();
+
+        self.start_session(stream, &received_handshake[20..28], 0)
+            .await
+    }
+
+    /// Set up the extensions the peer supports and exchange messages until the connection fails.
+    /// `flags` are advertised to other peers over ut_pex.
+    async fn start_session(
+        &mut self,
+        mut stream: TcpStream,
+        reserved_bytes: &[u8],
+        flags: u8,
+    ) -> Result<()> {
+        // pieces written from now on are announced with have messages
+        let receive_haves = self.shared.disk.haves.subscribe();
+        let receive_cancels = self.shared.cancels.subscribe();
+
+        // our pieces have to be the first message, with the fast extension the peer has to be told even if we have none
+        self.fast_extension = fast::supports_fast_extension(reserved_bytes);
+        let bitfield = self.shared.disk.have.bitfield();
+        let pieces = if self.fast_extension && bitfield.all() {
+            Some(Msg::HaveAll)
+        } else if bitfield.any() {
+            Some(Msg::Bitfield(bitfield))
+        } else if self.fast_extension {
+            Some(Msg::HaveNone)
+        } else {
+            None
+        };
+        if let Some(pieces) = pieces {
+            stream.write_all(&pieces.get_message()).await?;
+        }
+
+        // both sides have to set the reserved bit to use extended messages
+        if extension::supports_extensions(reserved_bytes) {
+            let handshake = self
+                .extensions
+                .handshake(self.shared.listen_port, self.ip.parse().ok())?;
+            stream.write_all(&handshake.get_message()).await?;
+        }
+
+        if self.fast_extension {
+            for &index in &self.allowed_fast {
+                stream
+                    .write_all(&Msg::AllowedFast(index).get_message())
+                    .await?;
+            }
+        }
+
+        // tell the peer where our DHT node listens if it runs one as well
+        if let Some(dht) = &self.shared.dht {
+            if dht::supports_dht(reserved_bytes) {
+                let port = dht.local_addr()?.port();
+                stream.write_all(&Msg::Port(port).get_message()).await?;
             }
-            let mut buffer = vec![0; (payload_length) as usize];
-            stream.read_exact(&mut buffer).await?;
-
-            let msg = Msg::parse(buffer)?;
-            //println!("{:x?}", msg);
-            match msg {
-                Msg::Bitfield(bitfield) => {
-                    //todo might not need to clone peer id here
-                    println!("Recieved bitfield from peer: {}", self.ip);
-                    let peer_id = self.peer_id.clone();
-                    self.transmitter
-                        .send(Command::BitfieldRecieved { peer_id, bitfield })?;
-                    // set current peer's bifield
-                    //self.bitfield = bitfield;
-                    // send interested msg
-                    stream.write(&Msg::Interested.get_message()).await?;
+        }
+
+        // incoming peers connect from an ephemeral port, nobody else could connect to it
+        if flags & pex::OUTGOING != 0 {
+            self.shared.connected.insert(&self.ip, self.port, flags);
+            self.pex_addr = Some((self.ip.clone(), self.port));
+        }
+        let (send_chokes, receive_chokes) = mpsc::unbounded_channel::<ChokeState>();
+        self.shared.choker.send(ChokerCommand::Register {
+            addr: (self.ip.clone(), self.port),
+            stats: self.peer_stats.clone(),
+            transmitter: send_chokes,
+        })?;
+        let (reader, mut writer) = stream.into_split();
+        let (send_msgs, receive_msgs) = mpsc::channel::<Result<Msg>>(32);
+        let reader = spawn_reader(reader, send_msgs);
+        let res = self
+            .run(
+                receive_msgs,
+                receive_haves,
+                receive_cancels,
+                receive_chokes,
+                &mut writer,
+            )
+            .await;
+        reader.abort();
+        if let Some((ip, port)) = self.pex_addr.take() {
+            self.shared.connected.remove(&ip, port);
+        }
+        self.shared
+            .choker
+            .send(ChokerCommand::Unregister {
+                addr: (self.ip.clone(), self.port),
+            })
+            .unwrap_or(());
+        // blocks requested from the peer are picked by the others
+        self.shared
+            .transmitter
+            .send(Command::PeerDisconnected {
+                peer_id: self.peer_id.clone(),
+            })
+            .unwrap_or(());
+        res
+    }
+
+    /// Handle the messages of the peer, serve its requests, announce our new pieces,
+    /// apply the choker's decisions and let the extensions send their periodic messages
+    async fn run(
+        &mut self,
+        mut receive_msgs: Receiver<Result<Msg>>,
+        mut receive_haves: broadcast::Receiver<u32>,
+        mut receive_cancels: broadcast::Receiver<EndgameCancel>,
+        mut receive_chokes: UnboundedReceiver<ChokeState>,
+        writer: &mut OwnedWriteHalf,
+    ) -> Result<()> {
+        let mut extension_interval = time::interval(EXTENSION_INTERVAL);
+        loop {
+            tokio::select! {
+                msg = receive_msgs.recv() => {
+                    let msg = msg.ok_or("Connection closed")??;
+                    self.handle_message(msg, writer).await?;
                 }
-                Msg::Unchoke => {
-                    self.peer_state = ChokeState::Unchoked;
-                    let (tx, rx) = oneshot::channel::<Command>();
-                    self.transmitter.send(Command::PickInitialPieces {
-                        peer_id: self.peer_id.clone(),
-                        transmitter: tx,
-                    })?;
-                    match rx.await? {
-                        Command::SelectedInitialPieces(blocks) => {
-                            // filter out None and convert rest to Request messages
-                            let req_blocks: Vec<Msg> = blocks
-                                .into_iter()
-                                .filter_map(|block| block)
-                                .map(|block| Msg::Request {
-                                    index: block.piece_index,
-                                    length: block.length,
-                                    begin: block.begin,
-                                })
-                                .collect();
-
-                            for req in req_blocks {
-                                stream.write(&req.get_message()).await?;
-                            }
-                        }
-                        Command::NoPiece => {
-                            Err("No piece left to pick")?;
+                // one block at a time, so that cancels arriving in the meantime are honored
+                _ = future::ready(()), if !self.requests.is_empty() => {
+                    self.serve_request(writer).await?;
+                }
+                have = receive_haves.recv() => match have {
+                    Ok(index) => {
+                        writer.write_all(&Msg::Have(index).get_message()).await?;
+                        // nothing is left to download from anyone
+                        if self.shared.disk.have.is_complete() {
+                            self.set_client_interest(false, writer).await?;
                         }
-                        _ => {}
                     }
-                    // send request msg
-                    /* stream
-                    .write(&[0, 0, 0, 13, 6, 0, 0, 0, 4, 0, 0, 0, 0, 0, 0, 64, 0])
-                    .await?; */
+                    // the peer only misses out on some of our pieces
+                    Err(RecvError::Lagged(_)) => {}
+                    Err(RecvError::Closed) => Err("Disk manager stopped")?,
+                },
+                cancel = receive_cancels.recv() => match cancel {
+                    Ok(cancel) if cancel.peer_ids.contains(&self.peer_id) => {
+                        let block = cancel.block;
+                        self.pipeline.released(block.piece_index, block.begin);
+                        let cancel = Msg::Cancel {
+                            index: block.piece_index,
+                            begin: block.begin,
+                            length: block.length,
+                        };
+                        writer.write_all(&cancel.get_message()).await?;
+                    }
+                    Ok(_) => {}
+                    // the peer sends a block we already have
+                    Err(RecvError::Lagged(_)) => {}
+                    Err(RecvError::Closed) => Err("Piece picker stopped")?,
+                },
+                state = receive_chokes.recv() => {
+                    let state = state.ok_or("Choker stopped")?;
+                    self.set_client_state(state, writer).await?;
                 }
+                _ = extension_interval.tick() => {
+                    for msg in self.extensions.tick()? {
+                        writer.write_all(&msg.get_message()).await?;
+                    }
+                }
+            }
+        }
+    }
 
-                Msg::Choke => {
-                    self.peer_state = ChokeState::Choked;
+    /// Read the oldest block the peer requested and send it
+    async fn serve_request(&mut self, writer: &mut OwnedWriteHalf) -> Result<()> {
+        let block = match self.requests.pop_front() {
+            Some(block) => block,
+            None => return Ok(()),
+        };
+        match self.shared.disk.read_block(&block).await {
+            Ok(data) => {
+                let piece = Msg::Piece {
+                    index: block.piece_index,
+                    begin: block.begin,
+                    block: data,
+                };
+                writer.write_all(&piece.get_message()).await?;
+                self.shared
+                    .stats
+                    .uploaded
+                    .fetch_add(block.length as u64, Ordering::Relaxed);
+                self.peer_stats
+                    .uploaded
+                    .fetch_add(block.length as u64, Ordering::Relaxed);
+            }
+            Err(e) => {
+                eprintln!("Reading block for {} failed: {}", self.ip, e);
+                self.reject(block, writer).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Choke or unchoke the peer. A choked peer's requests are dropped, except those for allowed fast pieces.
+    async fn set_client_state(
+        &mut self,
+        state: ChokeState,
+        writer: &mut OwnedWriteHalf,
+    ) -> Result<()> {
+        self.client_state = state;
+        match state {
+            ChokeState::Choked => {
+                writer.write_all(&Msg::Choke.get_message()).await?;
+                let requests = std::mem::take(&mut self.requests);
+                for block in requests {
+                    if self.allowed_fast.contains(&block.piece_index) {
+                        self.requests.push_back(block);
+                    } else {
+                        self.reject(block, writer).await?;
+                    }
                 }
-                Msg::Interested => {
-                    self.peer_interest = InterestState::Interested;
+            }
+            ChokeState::Unchoked => {
+                writer.write_all(&Msg::Unchoke.get_message()).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Tell the peer that a request won't be served, only possible with the fast extension
+    async fn reject(&mut self, block: BlockInfo, writer: &mut OwnedWriteHalf) -> Result<()> {
+        if self.fast_extension {
+            let reject = Msg::RejectRequest {
+                index: block.piece_index,
+                begin: block.begin,
+                length: block.length,
+            };
+            writer.write_all(&reject.get_message()).await?;
+        }
+        Ok(())
+    }
+
+    async fn handle_message(&mut self, msg: Msg, writer: &mut OwnedWriteHalf) -> Result<()> {
+        match msg {
+            Msg::Bitfield(bitfield) => {
+                //todo might not need to clone peer id here
+                println!("Recieved bitfield from peer: {}", self.ip);
+                let peer_id = self.peer_id.clone();
+                let interested = self.shared.disk.have.lacks_any(&bitfield);
+                self.shared
+                    .transmitter
+                    .send(Command::BitfieldRecieved { peer_id, bitfield })?;
+                // set current peer's bifield
+                //self.bitfield = bitfield;
+                self.set_client_interest(interested, writer).await?;
+            }
+            Msg::Unchoke => {
+                self.peer_state = ChokeState::Unchoked;
+                self.request_blocks(writer).await?;
+            }
+
+            Msg::Choke => {
+                self.peer_state = ChokeState::Choked;
+                // with the fast extension the peer rejects every request it drops
+                if !self.fast_extension {
+                    self.pipeline.clear();
                 }
-                Msg::NotInterested => {
-                    self.peer_interest = InterestState::NotInterested;
+            }
+            Msg::Interested => {
+                self.peer_interest = InterestState::Interested;
+                self.peer_stats.interested.store(true, Ordering::Relaxed);
+            }
+            Msg::NotInterested => {
+                self.peer_interest = InterestState::NotInterested;
+                self.peer_stats.interested.store(false, Ordering::Relaxed);
+            }
+            // a piece past the end would be out of range of every bitfield
+            Msg::Have(piece_index) if piece_index >= self.shared.disk.have.total_pieces() => {}
+            Msg::Have(piece_index) => {
+                self.shared.transmitter.send(Command::HavePiece {
+                    peer_id: self.peer_id.clone(),
+                    piece_index: piece_index as usize,
+                })?;
+                let missing = !self.shared.disk.have.contains(piece_index);
+                if missing && matches!(self.client_interest, InterestState::NotInterested) {
+                    self.set_client_interest(true, writer).await?;
+                    // the peer might have unchoked us while we weren't interested
+                    if let ChokeState::Unchoked = self.peer_state {
+                        self.request_blocks(writer).await?;
+                    }
                 }
-                Msg::Have(piece_index) => {
-                    self.transmitter.send(Command::HavePiece {
-                        peer_id: self.peer_id.clone(),
-                        piece_index: piece_index as usize,
-                    })?;
+            }
+            Msg::Request {
+                index,
+                begin,
+                length,
+            } => {
+                let block = BlockInfo {
+                    piece_index: index,
+                    begin,
+                    length,
+                };
+                // a choked peer may only ask for its allowed fast pieces
+                let choked = matches!(self.client_state, ChokeState::Choked)
+                    && !self.allowed_fast.contains(&index);
+                let valid = !choked
+                    && self.shared.disk.have.contains(index)
+                    && length <= MAX_BLOCK_LENGTH
+                    && self.requests.len() < MAX_REQUESTS as usize;
+                if !valid {
+                    self.reject(block, writer).await?;
+                } else if !self.requests.contains(&block) {
+                    self.requests.push_back(block);
                 }
-                Msg::Request {
-                    index: _,
-                    begin: _,
-                    length: _,
-                } => {
-                    //ignore
+            }
+            Msg::Piece {
+                index,
+                begin,
+                block,
+            } => {
+                // the piece picker bans peers whose blocks keep failing the hash check
+                if self.shared.banned.contains(&self.ip) {
+                    Err("Peer is banned for sending corrupt data")?;
                 }
-                Msg::Piece {
-                    index,
+                // write the block
+                //println!("Got piece from peer");
+                self.peer_stats
+                    .downloaded
+                    .fetch_add(block.len() as u64, Ordering::Relaxed);
+                self.pipeline
+                    .received(index, begin, block.len() as u32, Instant::now());
+                let downloaded_block = DownloadedBlock::new(index, begin, block);
+                self.shared.transmitter.send(Command::DownloadedBlock {
+                    ip: self.ip.clone(),
+                    peer_id: self.peer_id.clone(),
+                    block: downloaded_block,
+                })?;
+
+                self.request_blocks(writer).await?;
+            }
+            Msg::Cancel {
+                index,
+                begin,
+                length,
+            } => {
+                let block = BlockInfo {
+                    piece_index: index,
                     begin,
-                    block,
-                } => {
-                    // write the block
-                    //println!("Got piece from peer");
-                    let downloaded_block = DownloadedBlock::new(index, begin, block);
-                    self.transmitter
-                        .send(Command::DownloadedBlock(downloaded_block))?;
-
-                    let (tx, rx) = oneshot::channel::<Command>();
-                    self.transmitter.send(Command::PickPiece {
-                        peer_id: self.peer_id.clone(),
-                        transmitter: tx,
-                    })?;
-
-                    match rx.await? {
-                        Command::SelectedPiece(block) => {
-                            let req_block = Msg::Request {
-                                index: block.piece_index,
-                                length: block.length,
-                                begin: block.begin,
-                            };
-
-                            stream.write(&req_block.get_message()).await?;
-                        }
-                        Command::NoPiece => {
-                            Err("No piece left to pick")?;
-                        }
-                        _ => {}
-                    }
+                    length,
+                };
+                // with the fast extension every request has to be answered, even a cancelled one
+                if let Some(position) = self.requests.iter().position(|queued| *queued == block) {
+                    self.requests.remove(position);
+                    self.reject(block, writer).await?;
+                }
+            }
+            Msg::Port(port) => {
+                if let (Some(dht), Ok(ip)) = (&self.shared.dht, self.ip.parse()) {
+                    dht.add_node(SocketAddr::new(ip, port));
+                }
+            }
+            Msg::SuggestPiece(piece_index) => {
+                self.shared.transmitter.send(Command::SuggestPiece {
+                    peer_id: self.peer_id.clone(),
+                    piece_index,
+                })?;
+            }
+            Msg::HaveAll => {
+                self.shared.transmitter.send(Command::HaveAll {
+                    peer_id: self.peer_id.clone(),
+                })?;
+                let interested = !self.shared.disk.have.is_complete();
+                self.set_client_interest(interested, writer).await?;
+            }
+            Msg::HaveNone => {
+                self.shared.transmitter.send(Command::BitfieldRecieved {
+                    peer_id: self.peer_id.clone(),
+                    bitfield: BitVec::new(),
+                })?;
+            }
+            Msg::RejectRequest {
+                index,
+                begin,
+                length: _,
+            } => {
+                self.pipeline.released(index, begin);
+                self.shared.transmitter.send(Command::RejectedBlock {
+                    peer_id: self.peer_id.clone(),
+                    piece_index: index,
+                    begin,
+                })?;
+            }
+            Msg::AllowedFast(piece_index) => {
+                self.shared.transmitter.send(Command::AllowedFast {
+                    peer_id: self.peer_id.clone(),
+                    piece_index,
+                })?;
+                // blocks of allowed fast pieces can be requested without waiting for an unchoke
+                if let ChokeState::Choked = self.peer_state {
+                    self.request_blocks(writer).await?;
                 }
-                Msg::Cancel {
-                    index: _,
-                    begin: _,
-                    length: _,
-                } => {
-                    //ignore
+            }
+            Msg::Extended { id, payload } => {
+                for msg in self.extensions.on_message(id, &payload)? {
+                    writer.write_all(&msg.get_message()).await?;
+                }
+                let handshake = self.extensions.peer_handshake.as_ref();
+                if let Some(reqq) = handshake.and_then(|h| h.reqq) {
+                    self.pipeline.set_max_depth(reqq);
+                }
+                // an incoming peer is advertised once it tells us where it listens
+                if let (None, Some(port)) = (&self.pex_addr, handshake.and_then(|h| h.p)) {
+                    self.shared.connected.insert(&self.ip, port, 0);
+                    self.pex_addr = Some((self.ip.clone(), port));
                 }
             }
         }
         Ok(())
     }
 }
+
+/// Read messages from the peer until the connection fails, keep alive messages are skipped
+fn spawn_reader(mut reader: OwnedReadHalf, send_msgs: Sender<Result<Msg>>) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        loop {
+            let msg = read_message(&mut reader).await;
+            let failed = msg.is_err();
+            if send_msgs.send(msg).await.is_err() || failed {
+                break;
+            }
+        }
+    })
+}
+
+async fn read_message(reader: &mut OwnedReadHalf) -> Result<Msg> {
+    loop {
+        let mut buffer = [0; 4];
+        reader.read_exact(&mut buffer).await?;
+
+        let payload_length = u32::from_be_bytes(buffer);
+        // keep alive message
+        if payload_length == 0 {
+            continue;
+        }
+        let mut buffer = vec![0; (payload_length) as usize];
+        reader.read_exact(&mut buffer).await?;
+        return Msg::parse(buffer);
+    }
+}
@@ -0,0 +1,222 @@
+use serde_bencode::{de, ser};
+use serde_bytes::ByteBuf;
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc::UnboundedSender;
+
+use crate::extension::Extension;
+use crate::tracker::{self, TrackerPeer};
+use crate::Result;
+
+/// flag of peers we connected to ourselves, so they accept incoming connections
+pub const OUTGOING: u8 = 0x10;
+/// a single message may add or drop at most 50 peers
+const MAX_PEERS_PER_MESSAGE: usize = 50;
+
+/// Live connections of a torrent along with their ut_pex flags
+#[derive(Debug, Default)]
+pub struct ConnectedPeers {
+    peers: Mutex<HashMap<(String, u16), u8>>,
+}
+
+impl ConnectedPeers {
+    pub fn insert(&self, ip: &str, port: u16, flags: u8) {
+        self.peers
+            .lock()
+            .unwrap()
+            .insert((ip.to_string(), port), flags);
+    }
+
+    pub fn remove(&self, ip: &str, port: u16) {
+        self.peers.lock().unwrap().remove(&(ip.to_string(), port));
+    }
+
+    fn snapshot(&self) -> HashMap<(String, u16), u8> {
+        self.peers.lock().unwrap().clone()
+    }
+}
+
+/// Payload of a ut_pex message, all the peers are in the compact format
+#[derive(Debug, Default, Deserialize, Serialize)]
+struct PexMsg {
+    #[serde(default)]
+    added: ByteBuf,
+    #[serde(default)]
+    #[serde(rename = "added.f")]
+    added_flags: ByteBuf,
+    #[serde(default)]
+    added6: ByteBuf,
+    #[serde(default)]
+    #[serde(rename = "added6.f")]
+    added6_flags: ByteBuf,
+    #[serde(default)]
+    dropped: ByteBuf,
+    #[serde(default)]
+    dropped6: ByteBuf,
+}
+
+impl PexMsg {
+    fn is_empty(&self) -> bool {
+        self.added.is_empty()
+            && self.added6.is_empty()
+            && self.dropped.is_empty()
+            && self.dropped6.is_empty()
+    }
+}
+
+/// Append the compact form of the address to the IPv4 or IPv6 list, returns whether it was IPv6
+fn push_compact(ipv4: &mut ByteBuf, ipv6: &mut ByteBuf, ip: IpAddr, port: u16) -> bool {
+    let (list, octets, is_ipv6) = match ip {
+        IpAddr::V4(ip) => (ipv4, ip.octets().to_vec(), false),
+        IpAddr::V6(ip) => (ipv6, ip.octets().to_vec(), true),
+    };
+    list.extend_from_slice(&octets);
+    list.extend_from_slice(&port.to_be_bytes());
+    is_ipv6
+}
+
+/// Peer exchange, see BEP 11.
+/// Tells the peer about our connections and passes the peers it knows about on to the connection queue.
+pub struct UtPex {
+    /// address of the peer, it doesn't need to hear about itself
+    remote: (String, u16),
+    connected: Arc<ConnectedPeers>,
+    /// peers the remote side has already been told about
+    advertised: HashSet<(String, u16)>,
+    send_peers: UnboundedSender<Vec<TrackerPeer>>,
+}
+
+impl UtPex {
+    pub fn new(
+        remote: (String, u16),
+        connected: Arc<ConnectedPeers>,
+        send_peers: UnboundedSender<Vec<TrackerPeer>>,
+    ) -> Self {
+        Self {
+            remote,
+            connected,
+            advertised: HashSet::new(),
+            send_peers,
+        }
+    }
+}
+
+impl Extension for UtPex {
+    fn name(&self) -> &'static str {
+        "ut_pex"
+    }
+
+    fn on_message(&mut self, payload: &[u8]) -> Result<Vec<Vec<u8>>> {
+        let msg = de::from_bytes::<PexMsg>(payload)?;
+        let mut peers = tracker::decode_compact_peers(&msg.added, false);
+        peers.extend(tracker::decode_compact_peers(&msg.added6, true));
+        peers.truncate(MAX_PEERS_PER_MESSAGE);
+        if !peers.is_empty() && self.send_peers.send(peers).is_err() {
+            eprintln!("Receiver Dropped");
+        }
+        Ok(vec![])
+    }
+
+    /// Send the connections which were opened or closed since the last message
+    fn on_tick(&mut self) -> Result<Vec<Vec<u8>>> {
+        let connected = self.connected.snapshot();
+        let mut msg = PexMsg::default();
+
+        let mut added = 0;
+        for ((ip, port), flags) in &connected {
+            if added == MAX_PEERS_PER_MESSAGE {
+                break;
+            }
+            let addr = (ip.clone(), *port);
+            if addr == self.remote || self.advertised.contains(&addr) {
+                continue;
+            }
+            let ip = match ip.parse() {
+                Ok(ip) => ip,
+                Err(_) => continue,
+            };
+            if push_compact(&mut msg.added, &mut msg.added6, ip, *port) {
+                msg.added6_flags.push(*flags);
+            } else {
+                msg.added_flags.push(*flags);
+            }
+            self.advertised.insert(addr);
+            added += 1;
+        }
+
+        let dropped: Vec<(String, u16)> = self
+            .advertised
+            .iter()
+            .filter(|addr| !connected.contains_key(*addr))
+            .take(MAX_PEERS_PER_MESSAGE)
+            .cloned()
+            .collect();
+        for (ip, port) in dropped {
+            if let Ok(parsed) = ip.parse() {
+                push_compact(&mut msg.dropped, &mut msg.dropped6, parsed, port);
+            }
+            self.advertised.remove(&(ip, port));
+        }
+
+        if msg.is_empty() {
+            return Ok(vec![]);
+        }
+        Ok(vec![ser::to_bytes(&msg)?])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::mpsc;
+
+    #[test]
+    fn test_added_and_dropped() -> Result<()> {
+        let connected = Arc::new(ConnectedPeers::default());
+        let (send_peers, _receive_peers) = mpsc::unbounded_channel();
+        let mut pex = UtPex::new(
+            ("10.0.0.1".to_string(), 6881),
+            connected.clone(),
+            send_peers,
+        );
+
+        connected.insert("10.0.0.1", 6881, OUTGOING);
+        connected.insert("10.0.0.2", 6882, OUTGOING);
+        connected.insert("::1", 6883, 0);
+        let msg = de::from_bytes::<PexMsg>(&pex.on_tick()?[0])?;
+        // the remote peer itself isn't advertised
+        assert_eq!(msg.added.as_ref(), &[10, 0, 0, 2, 0x1a, 0xe2]);
+        assert_eq!(msg.added_flags.as_ref(), &[OUTGOING]);
+        assert_eq!(msg.added6.len(), 18);
+        assert_eq!(msg.added6_flags.as_ref(), &[0]);
+
+        // nothing changed
+        assert!(pex.on_tick()?.is_empty());
+
+        connected.remove("10.0.0.2", 6882);
+        let msg = de::from_bytes::<PexMsg>(&pex.on_tick()?[0])?;
+        assert!(msg.added.is_empty());
+        assert_eq!(msg.dropped.as_ref(), &[10, 0, 0, 2, 0x1a, 0xe2]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_learned_peers() -> Result<()> {
+        let (send_peers, mut receive_peers) = mpsc::unbounded_channel();
+        let mut pex = UtPex::new(
+            ("10.0.0.1".to_string(), 6881),
+            Arc::new(ConnectedPeers::default()),
+            send_peers,
+        );
+        let mut payload = b"d5:added12:".to_vec();
+        payload.extend_from_slice(&[10, 0, 0, 2, 0x1a, 0xe2, 10, 0, 0, 3, 0x1a, 0xe3]);
+        payload.extend_from_slice(b"7:added.f2:\x10\x00e");
+        assert!(pex.on_message(&payload)?.is_empty());
+
+        let peers = receive_peers.try_recv()?;
+        assert_eq!(peers.len(), 2);
+        assert_eq!((peers[1].ip.as_str(), peers[1].port), ("10.0.0.3", 6883));
+        Ok(())
+    }
+}
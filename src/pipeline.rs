@@ -0,0 +1,146 @@
+use std::collections::HashMap;
+use std::time::Duration;
+use tokio::time::Instant;
+
+use crate::extension::MAX_REQUESTS;
+
+/// requests kept outstanding before anything is measured
+const INITIAL_DEPTH: usize = 5;
+/// a single slow block shouldn't stall the peer
+const MIN_DEPTH: usize = 2;
+/// the queue holds twice the bandwidth-delay product, so it keeps growing until the peer's bandwidth is the limit
+const DEPTH_GAIN: f64 = 2.0;
+/// the download rate is sampled over this window
+const RATE_WINDOW: Duration = Duration::from_secs(1);
+/// weight of a new rate sample
+const RATE_SMOOTHING: f64 = 0.5;
+const BLOCK_LENGTH: f64 = 16384.0;
+
+/// Requests outstanding with a peer, the queue is sized from the measured bandwidth-delay product
+#[derive(Debug)]
+pub struct Pipeline {
+    /// requested blocks by piece index and offset, along with when they were requested
+    in_flight: HashMap<(u32, u32), Instant>,
+    /// requests the peer queues at once, its `reqq`
+    max_depth: usize,
+    /// quickest a block arrived, the delay without the requests queued at the peer
+    min_rtt: Option<Duration>,
+    /// bytes per second, smoothed over the windows
+    rate: f64,
+    /// bytes received in the current window
+    received: u64,
+    window_start: Instant,
+}
+
+impl Pipeline {
+    pub fn new(now: Instant) -> Self {
+        Self {
+            in_flight: HashMap::new(),
+            max_depth: MAX_REQUESTS as usize,
+            min_rtt: None,
+            rate: 0.0,
+            received: 0,
+            window_start: now,
+        }
+    }
+
+    /// Limit the queue to what the peer advertised in its extended handshake
+    pub fn set_max_depth(&mut self, reqq: u32) {
+        self.max_depth = (reqq as usize).clamp(1, MAX_REQUESTS as usize);
+    }
+
+    /// Number of requests to keep outstanding
+    pub fn depth(&self) -> usize {
+        let depth = match self.min_rtt {
+            Some(rtt) if self.rate > 0.0 => {
+                let bdp = self.rate * rtt.as_secs_f64() / BLOCK_LENGTH;
+                (bdp * DEPTH_GAIN).ceil() as usize
+            }
+            _ => INITIAL_DEPTH,
+        };
+        depth.clamp(MIN_DEPTH.min(self.max_depth), self.max_depth)
+    }
+
+    /// Number of blocks to request to fill the queue
+    pub fn free_slots(&self) -> usize {
+        self.depth().saturating_sub(self.in_flight.len())
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.len()
+    }
+
+    pub fn requested(&mut self, piece_index: u32, begin: u32, now: Instant) {
+        self.in_flight.insert((piece_index, begin), now);
+    }
+
+    /// Account for a block which arrived, blocks which weren't requested only count towards the rate
+    pub fn received(&mut self, piece_index: u32, begin: u32, length: u32, now: Instant) {
+        if let Some(requested) = self.in_flight.remove(&(piece_index, begin)) {
+            let rtt = now - requested;
+            self.min_rtt = Some(self.min_rtt.map_or(rtt, |min_rtt| min_rtt.min(rtt)));
+        }
+        self.received += length as u64;
+        let elapsed = now - self.window_start;
+        if elapsed >= RATE_WINDOW {
+            let sample = self.received as f64 / elapsed.as_secs_f64();
+            self.rate = if self.rate == 0.0 {
+                sample
+            } else {
+                self.rate * (1.0 - RATE_SMOOTHING) + sample * RATE_SMOOTHING
+            };
+            self.received = 0;
+            self.window_start = now;
+        }
+    }
+
+    /// The block won't arrive, e.g. the peer rejected it or we cancelled it
+    pub fn released(&mut self, piece_index: u32, begin: u32) {
+        self.in_flight.remove(&(piece_index, begin));
+    }
+
+    /// Forget the requests older than `timeout`, the piece picker hands them to other peers
+    pub fn expire(&mut self, now: Instant, timeout: Duration) {
+        self.in_flight
+            .retain(|_, requested| now.duration_since(*requested) < timeout);
+    }
+
+    /// The peer discards every request when it chokes us without the fast extension
+    pub fn clear(&mut self) {
+        self.in_flight.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_depth_follows_bandwidth_delay_product() {
+        let start = Instant::now();
+        let mut pipeline = Pipeline::new(start);
+        assert_eq!(pipeline.free_slots(), INITIAL_DEPTH);
+        for begin in 0..INITIAL_DEPTH as u32 {
+            pipeline.requested(0, begin, start);
+        }
+        assert_eq!(pipeline.free_slots(), 0);
+
+        // 40 blocks a second with 100ms until the first block arrives
+        let rtt = Duration::from_millis(100);
+        for begin in 0..39 {
+            pipeline.received(0, begin, 16384, start + rtt);
+        }
+        pipeline.received(0, 39, 16384, start + RATE_WINDOW);
+        // four blocks are in flight at that rate, twice that is requested
+        assert_eq!(pipeline.depth(), 8);
+        assert_eq!(pipeline.in_flight(), 0);
+
+        // the peer caps the queue
+        pipeline.set_max_depth(6);
+        assert_eq!(pipeline.free_slots(), 6);
+
+        pipeline.requested(1, 0, start);
+        pipeline.expire(start + Duration::from_secs(30), Duration::from_secs(30));
+        assert_eq!(pipeline.in_flight(), 0);
+    }
+}
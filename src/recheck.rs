@@ -0,0 +1,127 @@
+use bitvec::{order::Msb0, prelude::BitVec};
+use std::path::PathBuf;
+
+use crate::disk_pool::DiskPool;
+use crate::Result;
+
+/// Outcome of checking a piece against its hash
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PieceCheck {
+    Valid,
+    /// the data isn't on disk, e.g. a file is missing or too short
+    Missing,
+    /// the data doesn't match the hash
+    Corrupt,
+}
+
+/// A file of the torrent along with the pieces overlapping it which didn't check out
+#[derive(Debug)]
+pub struct FileCheck {
+    pub path: PathBuf,
+    pub exists: bool,
+    pub total_pieces: u32,
+    pub bad_pieces: u32,
+}
+
+/// Result of hashing all the data of a torrent which is on disk
+#[derive(Debug)]
+pub struct Recheck {
+    pub pieces: Vec<PieceCheck>,
+    pub files: Vec<FileCheck>,
+}
+
+impl Recheck {
+    /// Pieces which match their hash
+    pub fn have(&self) -> BitVec<Msb0, u8> {
+        self.pieces
+            .iter()
+            .map(|check| *check == PieceCheck::Valid)
+            .collect()
+    }
+
+    pub fn count(&self, check: PieceCheck) -> usize {
+        self.pieces.iter().filter(|piece| **piece == check).count()
+    }
+
+    /// Indexes of the pieces with the outcome
+    pub fn indexes(&self, check: PieceCheck) -> Vec<u32> {
+        (0..self.pieces.len() as u32)
+            .filter(|index| self.pieces[*index as usize] == check)
+            .collect()
+    }
+}
+
+/// Hash every piece against `piece_hashes` on the disk threads
+pub async fn recheck(pool: &DiskPool, piece_hashes: &[[u8; 20]]) -> Result<Recheck> {
+    // all the pieces are queued first, so that the threads hash them in parallel
+    let mut hashes = Vec::with_capacity(piece_hashes.len());
+    for index in 0..piece_hashes.len() as u32 {
+        hashes.push(pool.hash(index).await?);
+    }
+    let mut pieces = Vec::with_capacity(piece_hashes.len());
+    for (hash, expected) in hashes.into_iter().zip(piece_hashes) {
+        pieces.push(match hash.await? {
+            Ok(sha1) if sha1 == *expected => PieceCheck::Valid,
+            Ok(_) => PieceCheck::Corrupt,
+            // the data isn't on disk
+            Err(_) => PieceCheck::Missing,
+        });
+    }
+
+    let files = pool
+        .storage()
+        .file_pieces()
+        .into_iter()
+        .map(|(path, exists, range)| FileCheck {
+            path: path.to_path_buf(),
+            exists,
+            total_pieces: range.len() as u32,
+            bad_pieces: range
+                .filter(|index| pieces.get(*index as usize) != Some(&PieceCheck::Valid))
+                .count() as u32,
+        })
+        .collect();
+    Ok(Recheck { pieces, files })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{FileStorage, Layout};
+    use ring::digest;
+    use std::convert::TryInto;
+    use std::fs;
+
+    fn hash(data: &[u8]) -> [u8; 20] {
+        let sha1 = digest::digest(&digest::SHA1_FOR_LEGACY_USE_ONLY, data);
+        sha1.as_ref().try_into().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_recheck() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("bitr-verify-{}", std::process::id()));
+        fs::create_dir_all(&dir)?;
+        // the second piece is corrupt, the file with the third piece is missing
+        fs::write(dir.join("a"), [0, 1, 2, 3, 4, 5, 0, 7])?;
+        let files = vec![(dir.join("a"), 8), (dir.join("b"), 2)];
+        let pool = DiskPool::new(Box::new(FileStorage::open(Layout::new(files, 4))));
+        let piece_hashes = [hash(&[0, 1, 2, 3]), hash(&[4, 5, 6, 7]), hash(&[8, 9])];
+
+        let recheck = recheck(&pool, &piece_hashes).await?;
+        assert_eq!(
+            recheck.pieces,
+            vec![PieceCheck::Valid, PieceCheck::Corrupt, PieceCheck::Missing]
+        );
+        assert_eq!(recheck.indexes(PieceCheck::Corrupt), vec![1]);
+        assert_eq!(recheck.have().count_ones(), 1);
+        assert!(recheck.files[0].exists);
+        assert_eq!(recheck.files[0].bad_pieces, 1);
+        assert!(!recheck.files[1].exists);
+        assert_eq!(
+            (recheck.files[1].total_pieces, recheck.files[1].bad_pieces),
+            (1, 1)
+        );
+        fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+}
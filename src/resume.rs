@@ -0,0 +1,153 @@
+use bitvec::{order::Msb0, prelude::BitVec};
+use serde_bencode::{de, ser};
+use serde_bytes::ByteBuf;
+use std::fs;
+use std::path::PathBuf;
+use std::time::UNIX_EPOCH;
+use tokio::task;
+
+use crate::disk::DiskHandle;
+use crate::Result;
+
+/// Length and modification time of a file, the resume state is stale once they change
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct FileState {
+    pub length: u64,
+    /// nanoseconds since the unix epoch
+    pub mtime: u64,
+}
+
+/// A block of a piece which wasn't complete yet
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct PartialBlock {
+    pub begin: u32,
+    pub data: ByteBuf,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct PartialPiece {
+    pub index: u32,
+    pub blocks: Vec<PartialBlock>,
+}
+
+/// What a download needs to continue after a restart without checking the data again
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub struct ResumeData {
+    /// verified pieces, in the layout of the bitfield message
+    pub have: ByteBuf,
+    pub files: Vec<FileState>,
+    /// blocks which were downloaded but aren't written yet
+    pub partial: Vec<PartialPiece>,
+}
+
+impl ResumeData {
+    pub fn have(&self) -> BitVec<Msb0, u8> {
+        BitVec::from_vec(self.have.to_vec())
+    }
+}
+
+/// Where the resume state of a torrent is kept
+#[derive(Debug, Clone)]
+pub struct ResumeFile {
+    path: PathBuf,
+    /// path and length of every file of the torrent
+    files: Vec<(PathBuf, u64)>,
+    disk: DiskHandle,
+}
+
+impl ResumeFile {
+    pub fn new(path: PathBuf, files: Vec<(PathBuf, u64)>, disk: DiskHandle) -> Self {
+        Self { path, files, disk }
+    }
+
+    /// The saved state, unless it is missing or the files changed since it was saved
+    pub fn load(&self) -> Option<ResumeData> {
+        let data = de::from_bytes::<ResumeData>(&fs::read(&self.path).ok()?).ok()?;
+        if data.files != file_states(&self.files).ok()? {
+            return None;
+        }
+        Some(data)
+    }
+
+    pub async fn save(&self, partial: Vec<PartialPiece>) -> Result<()> {
+        // the files are stated after the pieces are taken, so a piece written in between is only downloaded again
+        let have = self.disk.have.bitfield().into_vec();
+        // the pieces may still be in the cache
+        self.disk.pool.flush().await?;
+        let (path, files) = (self.path.clone(), self.files.clone());
+        // the files are stated and written on a blocking thread, off the async runtime
+        task::spawn_blocking(move || {
+            let data = ResumeData {
+                have: ByteBuf::from(have),
+                files: file_states(&files)?,
+                partial,
+            };
+            // the directory of a multi-file torrent is only created once its files are written
+            if let Some(dir) = path.parent() {
+                fs::create_dir_all(dir)?;
+            }
+            // a crash while writing mustn't leave a half written state behind
+            let tmp = path.with_extension("tmp");
+            fs::write(&tmp, ser::to_bytes(&data)?)?;
+            fs::rename(tmp, &path)?;
+            Ok(())
+        })
+        .await?
+    }
+}
+
+/// Current length and modification time of the files, missing files have neither
+fn file_states(files: &[(PathBuf, u64)]) -> Result<Vec<FileState>> {
+    files
+        .iter()
+        .map(|(path, _)| match fs::metadata(path) {
+            Ok(metadata) => Ok(FileState {
+                length: metadata.len(),
+                mtime: metadata.modified()?.duration_since(UNIX_EPOCH)?.as_nanos() as u64,
+            }),
+            Err(_) => Ok(FileState {
+                length: 0,
+                mtime: 0,
+            }),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{Layout, MemoryStorage};
+
+    #[tokio::test]
+    async fn test_stale_state() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("bitr-resume-{}", std::process::id()));
+        fs::create_dir_all(&dir)?;
+        let file = dir.join("a");
+        fs::write(&file, [1; 4])?;
+        let storage = MemoryStorage::new(Layout::new(vec![(file.clone(), 40)], 4));
+        let disk = DiskHandle::new(Box::new(storage));
+        disk.have.insert(3);
+        let resume = ResumeFile::new(dir.join("a.bitr_resume"), vec![(file.clone(), 4)], disk);
+        assert_eq!(resume.load(), None);
+
+        let partial = vec![PartialPiece {
+            index: 4,
+            blocks: vec![PartialBlock {
+                begin: 16384,
+                data: ByteBuf::from(vec![2; 16384]),
+            }],
+        }];
+        resume.save(partial.clone()).await?;
+        let data = resume.load().ok_or("State is missing")?;
+        let have: Vec<bool> = data.have().iter().map(|bit| *bit).collect();
+        assert_eq!(have.iter().filter(|bit| **bit).count(), 1);
+        assert!(have[3]);
+        assert_eq!(data.partial, partial);
+
+        // the data changed behind our back
+        fs::write(&file, [1; 5])?;
+        assert_eq!(resume.load(), None);
+        fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,270 @@
+use ring::digest;
+use std::convert::TryInto;
+use std::fmt::Debug;
+use std::fs;
+use std::ops::Range;
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+
+use crate::Result;
+
+mod cache;
+mod file;
+mod memory;
+mod mmap;
+
+pub use cache::{CacheStats, CachedStorage};
+pub use file::FileStorage;
+pub use memory::MemoryStorage;
+pub use mmap::MmapStorage;
+
+/// A file of the torrent along with its position in the piece stream
+#[derive(Debug, Clone)]
+struct FileSpan {
+    path: PathBuf,
+    /// offset of the first byte of the file in the torrent
+    offset: u64,
+    length: u64,
+}
+
+/// How the files of a torrent map onto its pieces
+#[derive(Debug, Clone)]
+pub struct Layout {
+    files: Vec<FileSpan>,
+    total_length: u64,
+    piece_length: u64,
+}
+
+impl Layout {
+    /// `files` contains the path and length of every file, in the order they appear in the torrent
+    pub fn new(files: Vec<(PathBuf, u64)>, piece_length: u64) -> Self {
+        let mut offset = 0;
+        let mut spans = Vec::with_capacity(files.len());
+        for (path, length) in files {
+            spans.push(FileSpan {
+                path,
+                offset,
+                length,
+            });
+            offset += length;
+        }
+        Self {
+            files: spans,
+            total_length: offset,
+            piece_length,
+        }
+    }
+
+    pub fn total_pieces(&self) -> u32 {
+        self.total_length.div_ceil(self.piece_length) as u32
+    }
+
+    /// Length of the piece, the final piece may be shorter
+    pub fn piece_length(&self, index: u32) -> u64 {
+        self.piece_length
+            .min(self.total_length - index as u64 * self.piece_length)
+    }
+
+    /// Offset of a block in the torrent, the block has to lie within its piece
+    fn block_offset(&self, index: u32, begin: u32, length: u64) -> Result<u64> {
+        if index >= self.total_pieces() || begin as u64 + length > self.piece_length(index) {
+            Err("Block is out of the piece's bounds")?;
+        }
+        Ok(index as u64 * self.piece_length + begin as u64)
+    }
+
+    /// Files overlapping `length` bytes starting at `offset` in the torrent, as the index of the file,
+    /// the offset within the file and the range of the bytes which belong to it
+    fn spans(&self, offset: u64, length: u64) -> Vec<(usize, u64, Range<usize>)> {
+        let end = offset + length;
+        self.files
+            .iter()
+            .enumerate()
+            // skip files which don't overlap with the data, empty files never do
+            .filter(|(_, file)| {
+                file.length > 0 && file.offset + file.length > offset && file.offset < end
+            })
+            .map(|(index, file)| {
+                let start = offset.max(file.offset);
+                let stop = end.min(file.offset + file.length);
+                let range = (start - offset) as usize..(stop - offset) as usize;
+                (index, start - file.offset, range)
+            })
+            .collect()
+    }
+
+    /// Where every file ends up once the files are moved under `dir`, absolute paths are nested in it as well
+    fn moved_paths(&self, dir: &Path) -> Vec<PathBuf> {
+        self.files
+            .iter()
+            .map(|file| {
+                let relative = file
+                    .path
+                    .components()
+                    .filter(|component| matches!(component, Component::Normal(_)));
+                dir.join(relative.collect::<PathBuf>())
+            })
+            .collect()
+    }
+}
+
+/// Where the data of a torrent is kept. Blocks are addressed by piece, the storage maps them onto its files.
+pub trait Storage: Debug + Send + Sync {
+    fn layout(&self) -> &Layout;
+
+    /// Read `length` bytes starting at `offset` in the torrent, joining them across file boundaries
+    fn read_at(&self, offset: u64, length: u64) -> Result<Vec<u8>>;
+
+    /// Write `data` starting at `offset` in the torrent, splitting it across file boundaries
+    fn write_at(&self, data: &[u8], offset: u64) -> Result<()>;
+
+    /// Make sure everything written so far survives a crash
+    fn flush(&self) -> Result<()>;
+
+    /// Move the files under `dir`, their paths are kept relative to it
+    fn move_to(&mut self, dir: &Path) -> Result<()>;
+
+    /// Whether the file at `index` of the layout exists
+    fn file_exists(&self, _index: usize) -> bool {
+        true
+    }
+
+    /// Read a block of a piece, the block has to lie within the piece
+    fn read_block(&self, index: u32, begin: u32, length: u32) -> Result<Vec<u8>> {
+        let offset = self.layout().block_offset(index, begin, length as u64)?;
+        self.read_at(offset, length as u64)
+    }
+
+    /// Write a block of a piece, the block has to lie within the piece
+    fn write_block(&self, index: u32, begin: u32, data: &[u8]) -> Result<()> {
+        let offset = self
+            .layout()
+            .block_offset(index, begin, data.len() as u64)?;
+        self.write_at(data, offset)
+    }
+
+    /// Write a whole piece, the storage may keep the data as it is
+    fn write_piece(&self, index: u32, data: Vec<u8>) -> Result<()> {
+        self.write_block(index, 0, &data)
+    }
+
+    /// SHA-1 of the data of the piece
+    fn hash_piece(&self, index: u32) -> Result<[u8; 20]> {
+        let length = self.layout().piece_length(index);
+        let data = self.read_block(index, 0, length as u32)?;
+        Ok(sha1(&data))
+    }
+
+    /// How well the blocks are served from memory, for storages which cache them
+    fn cache_stats(&self) -> Option<CacheStats> {
+        None
+    }
+
+    /// Path of every file, whether it exists and the pieces which overlap with it
+    fn file_pieces(&self) -> Vec<(&Path, bool, Range<u32>)> {
+        let layout = self.layout();
+        layout
+            .files
+            .iter()
+            .enumerate()
+            .map(|(index, file)| {
+                let start = (file.offset / layout.piece_length) as u32;
+                let end = (file.offset + file.length).div_ceil(layout.piece_length) as u32;
+                (file.path.as_path(), self.file_exists(index), start..end)
+            })
+            .collect()
+    }
+}
+
+/// The storage backends a torrent can be downloaded to
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum StorageKind {
+    /// regular reads and writes of the files
+    #[default]
+    File,
+    /// nothing is written to disk, the data is gone once bitr exits
+    Memory,
+    /// the files are mapped into memory
+    Mmap,
+}
+
+impl StorageKind {
+    /// Create the storage for the files of a torrent
+    pub fn create(self, files: Vec<(PathBuf, u64)>, piece_length: u64) -> Result<Box<dyn Storage>> {
+        let layout = Layout::new(files, piece_length);
+        Ok(match self {
+            StorageKind::File => Box::new(FileStorage::create(layout)?),
+            StorageKind::Memory => Box::new(MemoryStorage::new(layout)),
+            StorageKind::Mmap => Box::new(MmapStorage::create(layout)?),
+        })
+    }
+
+    /// Whether the data is still there after a restart
+    pub fn is_persistent(self) -> bool {
+        self != StorageKind::Memory
+    }
+}
+
+impl FromStr for StorageKind {
+    type Err = String;
+
+    fn from_str(kind: &str) -> std::result::Result<Self, Self::Err> {
+        match kind {
+            "file" => Ok(StorageKind::File),
+            "memory" => Ok(StorageKind::Memory),
+            "mmap" => Ok(StorageKind::Mmap),
+            _ => Err(format!("Unknown storage: {}", kind)),
+        }
+    }
+}
+
+fn sha1(data: &[u8]) -> [u8; 20] {
+    let sha1 = digest::digest(&digest::SHA1_FOR_LEGACY_USE_ONLY, data);
+    // a SHA-1 digest is always 20 bytes
+    sha1.as_ref().try_into().unwrap()
+}
+
+/// Move a file, copying it if it can't be renamed, e.g. to another filesystem
+fn move_file(from: &Path, to: &Path) -> Result<()> {
+    if let Some(parent) = to.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    if fs::rename(from, to).is_err() {
+        fs::copy(from, to)?;
+        fs::remove_file(from)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_layout() -> Result<()> {
+        let layout = Layout::new(
+            vec![
+                (PathBuf::from("a"), 3),
+                (PathBuf::from("b"), 0),
+                (PathBuf::from("c"), 7),
+            ],
+            4,
+        );
+        assert_eq!(layout.total_pieces(), 3);
+        assert_eq!(layout.piece_length(2), 2);
+        assert_eq!(layout.spans(2, 4), vec![(0, 2, 0..1), (2, 0, 1..4)]);
+        assert_eq!(layout.block_offset(1, 1, 3)?, 5);
+        // the final piece is shorter than the others
+        assert!(layout.block_offset(2, 0, 3).is_err());
+        assert!(layout.block_offset(3, 0, 1).is_err());
+
+        let storage = MemoryStorage::new(layout);
+        let pieces: Vec<Range<u32>> = storage
+            .file_pieces()
+            .into_iter()
+            .map(|(_, _, pieces)| pieces)
+            .collect();
+        assert_eq!(pieces, vec![0..1, 0..1, 0..3]);
+        Ok(())
+    }
+}
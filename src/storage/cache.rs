@@ -0,0 +1,325 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Mutex;
+
+use super::{sha1, Layout, Storage};
+use crate::Result;
+
+/// How often the blocks were served from the cache
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+/// A piece kept in memory
+#[derive(Debug)]
+struct CachedPiece {
+    data: Vec<u8>,
+    /// not written to the storage yet
+    dirty: bool,
+    /// tick of the last access, the piece with the lowest one is evicted first
+    used: u64,
+}
+
+#[derive(Debug, Default)]
+struct Cache {
+    pieces: HashMap<u32, CachedPiece>,
+    /// bytes of all the cached pieces
+    size: usize,
+    tick: u64,
+    stats: CacheStats,
+}
+
+/// Keeps whole pieces in memory in front of another storage. Written pieces are held back and
+/// written together with their neighbours, pieces read by the peers stay around for the next requests.
+#[derive(Debug)]
+pub struct CachedStorage {
+    inner: Box<dyn Storage>,
+    /// bytes of pieces the cache may hold
+    capacity: usize,
+    cache: Mutex<Cache>,
+}
+
+impl CachedStorage {
+    pub fn new(inner: Box<dyn Storage>, capacity: usize) -> Self {
+        Self {
+            inner,
+            capacity,
+            cache: Mutex::new(Cache::default()),
+        }
+    }
+
+    /// Add a piece, evicting the least recently used ones to make room for it.
+    /// A piece read from the storage doesn't replace one which is already cached.
+    fn insert(&self, index: u32, data: Vec<u8>, dirty: bool) -> Result<()> {
+        let mut cache = self.cache.lock().unwrap();
+        if !dirty && cache.pieces.contains_key(&index) {
+            return Ok(());
+        }
+        if let Some(piece) = cache.pieces.remove(&index) {
+            cache.size -= piece.data.len();
+        }
+        // pieces larger than the whole cache aren't kept
+        if data.len() > self.capacity {
+            drop(cache);
+            return match dirty {
+                true => self.inner.write_block(index, 0, &data),
+                false => Ok(()),
+            };
+        }
+        while cache.size + data.len() > self.capacity {
+            let oldest = match cache.pieces.iter().min_by_key(|(_, piece)| piece.used) {
+                Some((index, _)) => *index,
+                None => break,
+            };
+            // the rest of the dirty pieces are likely next to it, they are written along with it.
+            // the pieces may change while they are written, so the oldest one is looked up again.
+            if cache.pieces[&oldest].dirty {
+                drop(cache);
+                self.write_dirty()?;
+                cache = self.cache.lock().unwrap();
+                if !dirty && cache.pieces.contains_key(&index) {
+                    return Ok(());
+                }
+                continue;
+            }
+            let piece = cache.pieces.remove(&oldest).unwrap();
+            cache.size -= piece.data.len();
+        }
+        cache.tick += 1;
+        cache.size += data.len();
+        let used = cache.tick;
+        let piece = CachedPiece { data, dirty, used };
+        if let Some(piece) = cache.pieces.insert(index, piece) {
+            cache.size -= piece.data.len();
+        }
+        Ok(())
+    }
+
+    /// Write the dirty pieces, every run of consecutive pieces goes out in a single write.
+    /// The runs are copied out, so that the cache isn't locked while they are written.
+    fn write_dirty(&self) -> Result<()> {
+        let piece_length = self.inner.layout().piece_length;
+        let runs: Vec<(u32, Vec<u8>)> = {
+            let cache = self.cache.lock().unwrap();
+            let mut dirty: Vec<u32> = cache
+                .pieces
+                .iter()
+                .filter(|(_, piece)| piece.dirty)
+                .map(|(index, _)| *index)
+                .collect();
+            dirty.sort_unstable();
+            let mut runs: Vec<(u32, u32, Vec<u8>)> = vec![];
+            for index in dirty {
+                let data = &cache.pieces[&index].data;
+                match runs.last_mut() {
+                    Some((_, last, run)) if *last + 1 == index => {
+                        *last = index;
+                        run.extend_from_slice(data);
+                    }
+                    _ => runs.push((index, index, data.clone())),
+                }
+            }
+            runs.into_iter()
+                .map(|(first, _, data)| (first, data))
+                .collect()
+        };
+        for (first, data) in &runs {
+            self.inner.write_at(data, *first as u64 * piece_length)?;
+        }
+        // pieces written again in the meantime are still dirty
+        let mut cache = self.cache.lock().unwrap();
+        for (first, data) in runs {
+            for (index, written) in (first..).zip(data.chunks(piece_length as usize)) {
+                if let Some(piece) = cache.pieces.get_mut(&index) {
+                    if piece.data == written {
+                        piece.dirty = false;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Drop the cached pieces overlapping the data, they would be stale once it is written
+    fn invalidate(&self, data: &[u8], offset: u64) {
+        let piece_length = self.inner.layout().piece_length;
+        let first = offset / piece_length;
+        let last = (offset + data.len() as u64).div_ceil(piece_length);
+        let mut cache = self.cache.lock().unwrap();
+        for index in first..last {
+            let clean = cache
+                .pieces
+                .get(&(index as u32))
+                .is_some_and(|piece| !piece.dirty);
+            if clean {
+                let piece = cache.pieces.remove(&(index as u32)).unwrap();
+                cache.size -= piece.data.len();
+            }
+        }
+    }
+}
+
+impl Storage for CachedStorage {
+    fn layout(&self) -> &Layout {
+        self.inner.layout()
+    }
+
+    fn read_at(&self, offset: u64, length: u64) -> Result<Vec<u8>> {
+        // the data on disk is only current once the dirty pieces are written
+        self.write_dirty()?;
+        self.inner.read_at(offset, length)
+    }
+
+    fn write_at(&self, data: &[u8], offset: u64) -> Result<()> {
+        self.write_dirty()?;
+        self.invalidate(data, offset);
+        self.inner.write_at(data, offset)?;
+        // the pieces might have been read while the data was written
+        self.invalidate(data, offset);
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.write_dirty()?;
+        self.inner.flush()
+    }
+
+    fn move_to(&mut self, dir: &Path) -> Result<()> {
+        self.flush()?;
+        self.inner.move_to(dir)
+    }
+
+    fn file_exists(&self, index: usize) -> bool {
+        self.inner.file_exists(index)
+    }
+
+    fn read_block(&self, index: u32, begin: u32, length: u32) -> Result<Vec<u8>> {
+        let range = begin as usize..begin as usize + length as usize;
+        {
+            let mut cache = self.cache.lock().unwrap();
+            cache.tick += 1;
+            let tick = cache.tick;
+            if let Some(piece) = cache.pieces.get_mut(&index) {
+                piece.used = tick;
+                let data = piece.data.get(range.clone()).map(|data| data.to_vec());
+                cache.stats.hits += 1;
+                return Ok(data.ok_or("Block is out of the piece's bounds")?);
+            }
+            cache.stats.misses += 1;
+        }
+        // the whole piece is read, the peers usually request the rest of its blocks next
+        let piece_length = self.layout().piece_length(index);
+        if index >= self.layout().total_pieces() || range.end as u64 > piece_length {
+            Err("Block is out of the piece's bounds")?;
+        }
+        let data = self.inner.read_block(index, 0, piece_length as u32)?;
+        let block = data[range].to_vec();
+        // the piece might have been written while it was read
+        self.insert(index, data, false)?;
+        Ok(block)
+    }
+
+    fn write_block(&self, index: u32, begin: u32, data: &[u8]) -> Result<()> {
+        let offset = self
+            .layout()
+            .block_offset(index, begin, data.len() as u64)?;
+        // only whole pieces are cached
+        if begin == 0 && data.len() as u64 == self.layout().piece_length(index) {
+            return self.insert(index, data.to_vec(), true);
+        }
+        self.write_at(data, offset)
+    }
+
+    fn write_piece(&self, index: u32, data: Vec<u8>) -> Result<()> {
+        if index >= self.layout().total_pieces()
+            || data.len() as u64 != self.layout().piece_length(index)
+        {
+            Err("Piece has the wrong length")?;
+        }
+        self.insert(index, data, true)
+    }
+
+    fn hash_piece(&self, index: u32) -> Result<[u8; 20]> {
+        // hashing every piece would push the hot ones out of the cache, only those already in it are used
+        if let Some(piece) = self.cache.lock().unwrap().pieces.get(&index) {
+            return Ok(sha1(&piece.data));
+        }
+        self.inner.hash_piece(index)
+    }
+
+    fn cache_stats(&self) -> Option<CacheStats> {
+        Some(self.cache.lock().unwrap().stats)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+    use std::path::PathBuf;
+    use std::sync::Arc;
+
+    /// Records the writes which reach the storage
+    #[derive(Debug)]
+    struct RecordingStorage {
+        inner: MemoryStorage,
+        writes: Arc<Mutex<Vec<(u64, usize)>>>,
+    }
+
+    impl Storage for RecordingStorage {
+        fn layout(&self) -> &Layout {
+            self.inner.layout()
+        }
+
+        fn read_at(&self, offset: u64, length: u64) -> Result<Vec<u8>> {
+            self.inner.read_at(offset, length)
+        }
+
+        fn write_at(&self, data: &[u8], offset: u64) -> Result<()> {
+            self.writes.lock().unwrap().push((offset, data.len()));
+            self.inner.write_at(data, offset)
+        }
+
+        fn flush(&self) -> Result<()> {
+            Ok(())
+        }
+
+        fn move_to(&mut self, dir: &Path) -> Result<()> {
+            self.inner.move_to(dir)
+        }
+    }
+
+    #[test]
+    fn test_block_cache() -> Result<()> {
+        let writes = Arc::new(Mutex::new(vec![]));
+        let inner = RecordingStorage {
+            inner: MemoryStorage::new(Layout::new(vec![(PathBuf::from("a"), 18)], 4)),
+            writes: writes.clone(),
+        };
+        // room for three pieces
+        let storage = CachedStorage::new(Box::new(inner), 12);
+
+        storage.write_block(1, 0, &[4, 5, 6, 7])?;
+        storage.write_block(0, 0, &[0, 1, 2, 3])?;
+        storage.write_block(3, 0, &[12, 13, 14, 15])?;
+        // served from memory before it reaches the storage
+        assert_eq!(storage.read_block(1, 1, 2)?, vec![5, 6]);
+        assert!(writes.lock().unwrap().is_empty());
+
+        // the final piece evicts the first one, the dirty pieces are written in two runs
+        storage.write_block(4, 0, &[16, 17])?;
+        assert_eq!(*writes.lock().unwrap(), vec![(0, 8), (12, 4)]);
+        assert_eq!(storage.read_block(0, 0, 4)?, vec![0, 1, 2, 3]);
+        assert_eq!(
+            storage.cache_stats(),
+            Some(CacheStats { hits: 1, misses: 1 })
+        );
+
+        storage.flush()?;
+        assert_eq!(writes.lock().unwrap()[2], (16, 2));
+        assert_eq!(storage.read_at(0, 18)?[16..], [16, 17]);
+        Ok(())
+    }
+}
@@ -0,0 +1,165 @@
+use std::fs::{self, File, OpenOptions};
+use std::os::unix::prelude::FileExt;
+use std::path::Path;
+
+use super::{move_file, Layout, Storage};
+use crate::Result;
+
+/// The files of a torrent, read and written in place
+#[derive(Debug)]
+pub struct FileStorage {
+    layout: Layout,
+    /// missing when the files are only opened for reading and it doesn't exist
+    files: Vec<Option<File>>,
+    writable: bool,
+}
+
+impl FileStorage {
+    /// Open the files for reading and writing, creating the missing ones. Data from an earlier run is kept.
+    pub fn create(layout: Layout) -> Result<Self> {
+        let files = layout
+            .files
+            .iter()
+            .map(|file| open_writable(&file.path).map(Some))
+            .collect::<Result<_>>()?;
+        Ok(Self {
+            layout,
+            files,
+            writable: true,
+        })
+    }
+
+    /// Open the existing files for reading only, nothing is created
+    pub fn open(layout: Layout) -> Self {
+        let files = layout
+            .files
+            .iter()
+            .map(|file| File::open(&file.path).ok())
+            .collect();
+        Self {
+            layout,
+            files,
+            writable: false,
+        }
+    }
+
+    fn file(&self, index: usize) -> Result<&File> {
+        Ok(self.files[index].as_ref().ok_or("File is missing")?)
+    }
+}
+
+fn open_writable(path: &Path) -> Result<File> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    // blocks are read back to serve them to peers
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)?;
+    Ok(file)
+}
+
+impl Storage for FileStorage {
+    fn layout(&self) -> &Layout {
+        &self.layout
+    }
+
+    fn read_at(&self, offset: u64, length: u64) -> Result<Vec<u8>> {
+        let mut data = vec![0; length as usize];
+        for (index, file_offset, range) in self.layout.spans(offset, length) {
+            self.file(index)?
+                .read_exact_at(&mut data[range], file_offset)?;
+        }
+        Ok(data)
+    }
+
+    fn write_at(&self, data: &[u8], offset: u64) -> Result<()> {
+        for (index, file_offset, range) in self.layout.spans(offset, data.len() as u64) {
+            self.file(index)?.write_all_at(&data[range], file_offset)?;
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<()> {
+        if self.writable {
+            for file in self.files.iter().flatten() {
+                file.sync_data()?;
+            }
+        }
+        Ok(())
+    }
+
+    fn move_to(&mut self, dir: &Path) -> Result<()> {
+        self.flush()?;
+        let paths = self.layout.moved_paths(dir);
+        for (index, path) in paths.into_iter().enumerate() {
+            // missing files are only moved in name
+            if self.files[index].is_some() {
+                move_file(&self.layout.files[index].path, &path)?;
+                self.files[index] = Some(match self.writable {
+                    true => open_writable(&path)?,
+                    false => File::open(&path)?,
+                });
+            }
+            self.layout.files[index].path = path;
+        }
+        Ok(())
+    }
+
+    fn file_exists(&self, index: usize) -> bool {
+        self.files[index].is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_across_files() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("bitr-storage-{}", std::process::id()));
+        let files = vec![
+            (dir.join("a"), 3),
+            (dir.join("sub").join("b"), 0),
+            (dir.join("sub").join("c"), 4),
+            (dir.join("d"), 3),
+        ];
+        let storage = FileStorage::create(Layout::new(files, 4))?;
+
+        storage.write_block(0, 0, &[0, 1, 2, 3])?;
+        storage.write_block(1, 0, &[4, 5, 6, 7])?;
+        storage.write_block(2, 0, &[8, 9])?;
+
+        assert_eq!(fs::read(dir.join("a"))?, vec![0, 1, 2]);
+        assert_eq!(fs::read(dir.join("sub").join("b"))?, Vec::<u8>::new());
+        assert_eq!(fs::read(dir.join("sub").join("c"))?, vec![3, 4, 5, 6]);
+        assert_eq!(fs::read(dir.join("d"))?, vec![7, 8, 9]);
+        assert_eq!(storage.read_block(1, 1, 3)?, vec![5, 6, 7]);
+        fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_move_to() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("bitr-move-{}", std::process::id()));
+        let files = vec![(dir.join("a"), 3), (dir.join("sub").join("b"), 2)];
+        let mut storage = FileStorage::create(Layout::new(files, 4))?;
+        storage.write_block(0, 0, &[0, 1, 2, 3])?;
+
+        let moved = dir.join("moved");
+        storage.move_to(&moved)?;
+        // the absolute paths are nested in the new directory
+        let moved = moved.join(dir.strip_prefix("/")?);
+        assert!(!dir.join("a").exists());
+        assert_eq!(fs::read(moved.join("a"))?, vec![0, 1, 2]);
+        // the files keep working from their new place
+        storage.write_block(1, 0, &[4])?;
+        assert_eq!(storage.read_block(0, 2, 2)?, vec![2, 3]);
+        assert_eq!(fs::read(moved.join("sub").join("b"))?, vec![3, 4]);
+        fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,54 @@
+use std::path::Path;
+use std::sync::Mutex;
+
+use super::{Layout, Storage};
+use crate::Result;
+
+/// Keeps the whole torrent in memory, nothing touches the disk
+#[derive(Debug)]
+pub struct MemoryStorage {
+    layout: Layout,
+    data: Mutex<Vec<u8>>,
+}
+
+impl MemoryStorage {
+    pub fn new(layout: Layout) -> Self {
+        let data = Mutex::new(vec![0; layout.total_length as usize]);
+        Self { layout, data }
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn layout(&self) -> &Layout {
+        &self.layout
+    }
+
+    fn read_at(&self, offset: u64, length: u64) -> Result<Vec<u8>> {
+        let data = self.data.lock().unwrap();
+        let range = offset as usize..(offset + length) as usize;
+        Ok(data.get(range).ok_or("Read is out of bounds")?.to_vec())
+    }
+
+    fn write_at(&self, data: &[u8], offset: u64) -> Result<()> {
+        let mut stored = self.data.lock().unwrap();
+        let range = offset as usize..offset as usize + data.len();
+        stored
+            .get_mut(range)
+            .ok_or("Write is out of bounds")?
+            .copy_from_slice(data);
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn move_to(&mut self, dir: &Path) -> Result<()> {
+        // only the names change, the data isn't kept in the files
+        let paths = self.layout.moved_paths(dir);
+        for (file, path) in self.layout.files.iter_mut().zip(paths) {
+            file.path = path;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,132 @@
+use memmap2::{MmapMut, MmapOptions};
+use std::fs::{self, OpenOptions};
+use std::path::Path;
+use std::sync::RwLock;
+
+use super::{move_file, Layout, Storage};
+use crate::Result;
+
+/// The files of a torrent mapped into memory, the kernel writes them back on its own
+#[derive(Debug)]
+pub struct MmapStorage {
+    layout: Layout,
+    /// empty files can't be mapped
+    maps: Vec<Option<RwLock<MmapMut>>>,
+}
+
+impl MmapStorage {
+    /// Map the files, they are created and extended to their full length first. Data from an earlier run is kept.
+    pub fn create(layout: Layout) -> Result<Self> {
+        let maps = map_files(&layout)?;
+        Ok(Self { layout, maps })
+    }
+
+    fn map(&self, index: usize) -> Result<&RwLock<MmapMut>> {
+        Ok(self.maps[index].as_ref().ok_or("File is empty")?)
+    }
+}
+
+fn map_files(layout: &Layout) -> Result<Vec<Option<RwLock<MmapMut>>>> {
+    layout
+        .files
+        .iter()
+        .map(|file| {
+            if let Some(parent) = file.path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            let handle = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(&file.path)?;
+            if file.length == 0 {
+                return Ok(None);
+            }
+            // accessing a mapping beyond the end of the file is a bus error
+            if handle.metadata()?.len() < file.length {
+                handle.set_len(file.length)?;
+            }
+            // safety: the file mustn't be truncated by anyone else while it is mapped
+            let map = unsafe {
+                MmapOptions::new()
+                    .len(file.length as usize)
+                    .map_mut(&handle)?
+            };
+            Ok(Some(RwLock::new(map)))
+        })
+        .collect()
+}
+
+impl Storage for MmapStorage {
+    fn layout(&self) -> &Layout {
+        &self.layout
+    }
+
+    fn read_at(&self, offset: u64, length: u64) -> Result<Vec<u8>> {
+        let mut data = vec![0; length as usize];
+        for (index, file_offset, range) in self.layout.spans(offset, length) {
+            let map = self.map(index)?.read().unwrap();
+            let start = file_offset as usize;
+            data[range.clone()].copy_from_slice(&map[start..start + range.len()]);
+        }
+        Ok(data)
+    }
+
+    fn write_at(&self, data: &[u8], offset: u64) -> Result<()> {
+        for (index, file_offset, range) in self.layout.spans(offset, data.len() as u64) {
+            let mut map = self.map(index)?.write().unwrap();
+            let start = file_offset as usize;
+            map[start..start + range.len()].copy_from_slice(&data[range]);
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<()> {
+        for map in self.maps.iter().flatten() {
+            map.read().unwrap().flush()?;
+        }
+        Ok(())
+    }
+
+    fn move_to(&mut self, dir: &Path) -> Result<()> {
+        self.flush()?;
+        // the files are mapped again once they are in place
+        self.maps.clear();
+        let paths = self.layout.moved_paths(dir);
+        for (file, path) in self.layout.files.iter_mut().zip(paths) {
+            move_file(&file.path, &path)?;
+            file.path = path;
+        }
+        self.maps = map_files(&self.layout)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mmap_storage() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("bitr-mmap-{}", std::process::id()));
+        let files = vec![(dir.join("a"), 3), (dir.join("b"), 0), (dir.join("c"), 5)];
+        let storage = MmapStorage::create(Layout::new(files.clone(), 4))?;
+        // the files take up their full length right away
+        assert_eq!(fs::read(dir.join("c"))?, vec![0; 5]);
+
+        storage.write_block(0, 0, &[0, 1, 2, 3])?;
+        storage.write_block(1, 0, &[4, 5, 6, 7])?;
+        storage.flush()?;
+        assert_eq!(fs::read(dir.join("a"))?, vec![0, 1, 2]);
+        assert_eq!(fs::read(dir.join("c"))?, vec![3, 4, 5, 6, 7]);
+        assert_eq!(storage.read_block(0, 2, 2)?, vec![2, 3]);
+        drop(storage);
+
+        // the data is still there once the files are mapped again
+        let storage = MmapStorage::create(Layout::new(files, 4))?;
+        assert_eq!(storage.read_block(1, 0, 4)?, vec![4, 5, 6, 7]);
+        fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+}
@@ -1,13 +1,21 @@
-use reqwest::Url;
 use ring::digest;
-use serde_bencode::{de, ser};
+use serde_bencode::{de, value::Value};
 use serde_bytes::ByteBuf;
+use std::convert::TryInto;
 use std::fmt;
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Component, Path, PathBuf};
 
-use crate::utils::bytes_to_string_with_encoding;
 use crate::Result;
+use crate::{bencode, utils};
+
+/// A single entry of the `files` list of a multi-file torrent
+#[derive(Debug, Deserialize, Serialize)]
+pub struct File {
+    pub length: u64,
+    /// path segments relative to the torrent's root directory
+    pub path: Vec<String>,
+}
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct Info {
@@ -15,8 +23,92 @@ pub struct Info {
     pub pieces: ByteBuf,
     #[serde(rename = "piece length")]
     pub piece_length: u64,
+    /// present only in single-file torrents
     #[serde(default)]
     pub length: Option<u64>,
+    /// present only in multi-file torrents
+    #[serde(default)]
+    pub files: Option<Vec<File>>,
+    /// peers of private torrents may only come from the trackers, see BEP 27
+    #[serde(default)]
+    pub private: Option<u8>,
+}
+
+impl Info {
+    /// Total number of bytes across all the files in the torrent
+    pub fn total_length(&self) -> u64 {
+        match &self.files {
+            Some(files) => files.iter().map(|file| file.length).sum(),
+            None => self.length.unwrap_or(0),
+        }
+    }
+
+    pub fn is_private(&self) -> bool {
+        self.private == Some(1)
+    }
+
+    pub fn total_pieces(&self) -> u32 {
+        (self.pieces.len() / 20) as u32
+    }
+
+    /// SHA-1 of every piece
+    pub fn piece_hashes(&self) -> Vec<[u8; 20]> {
+        self.pieces
+            .chunks_exact(20)
+            // try to remove this unwrap
+            .map(|chunk| chunk.try_into().unwrap())
+            .collect()
+    }
+
+    /// File the resume state is kept in, next to the data. It is hidden, inside the directory of
+    /// multi-file torrents and beside the file of single-file ones.
+    pub fn resume_path(&self) -> Result<PathBuf> {
+        let root = sanitize_segment(&self.name)?;
+        Ok(match &self.files {
+            Some(_) => PathBuf::from(root).join(".bitr_resume"),
+            None => PathBuf::from(format!(".{}.bitr_resume", root)),
+        })
+    }
+
+    /// Paths and lengths of the files in the order they appear in the piece stream.
+    /// Single-file torrents are stored as `name`, multi-file torrents under a `name` directory.
+    pub fn file_layout(&self) -> Result<Vec<(PathBuf, u64)>> {
+        let root = sanitize_segment(&self.name)?;
+        match &self.files {
+            Some(files) => files
+                .iter()
+                .map(|file| {
+                    if file.path.is_empty() {
+                        Err("File path is empty")?;
+                    }
+                    let mut path = PathBuf::from(root);
+                    for segment in &file.path {
+                        path.push(sanitize_segment(segment)?);
+                    }
+                    Ok((path, file.length))
+                })
+                .collect(),
+            None => {
+                let length = self.length.ok_or("File length is missing")?;
+                Ok(vec![(PathBuf::from(root), length)])
+            }
+        }
+    }
+}
+
+/// SHA-1 of the bencoded info dictionary
+pub fn generate_info_hash(info_bytes: &[u8]) -> Vec<u8> {
+    let digest = digest::digest(&digest::SHA1_FOR_LEGACY_USE_ONLY, info_bytes);
+    digest.as_ref().to_vec()
+}
+
+/// Make sure a path segment from the metainfo can't escape the download directory
+fn sanitize_segment(segment: &str) -> Result<&str> {
+    let mut components = Path::new(segment).components();
+    match (components.next(), components.next()) {
+        (Some(Component::Normal(_)), None) => Ok(segment),
+        _ => Err(format!("Invalid path segment in torrent: {:?}", segment))?,
+    }
 }
 
 #[derive(Debug, Deserialize)]
@@ -24,6 +116,13 @@ pub struct Torrent {
     pub info: Info,
     #[serde(default)]
     announce: Option<String>,
+    /// tiers of trackers, see BEP 12
+    #[serde(default)]
+    #[serde(rename = "announce-list")]
+    announce_list: Option<Vec<Vec<String>>>,
+    /// DHT nodes as `[host, port]` lists, see BEP 5
+    #[serde(default)]
+    nodes: Option<Vec<Vec<Value>>>,
     #[serde(default)]
     #[serde(rename = "creation date")]
     creation_date: Option<i64>,
@@ -33,14 +132,21 @@ pub struct Torrent {
     created_by: Option<String>,
     #[serde(skip)]
     pub info_hash: Vec<u8>,
+    /// raw info dictionary, served to peers which only have the magnet link
+    #[serde(skip)]
+    pub info_bytes: Vec<u8>,
 }
 
 impl fmt::Display for Torrent {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         writeln!(f, "name:\t\t{}", self.info.name)?;
-        writeln!(f, "length:\t\t{:?}", self.info.length)?;
+        writeln!(f, "length:\t\t{:?}", self.info.total_length())?;
+        if let Some(files) = &self.info.files {
+            writeln!(f, "files:\t\t{}", files.len())?;
+        }
         writeln!(f, "piece length:\t{:?}", self.info.piece_length)?;
         writeln!(f, "announce:\t{:?}", self.announce)?;
+        writeln!(f, "announce list:\t{:?}", self.announce_list)?;
         writeln!(f, "created by:\t{:?}", self.created_by)?;
         writeln!(f, "creation date:\t{:?}", self.creation_date)?;
         writeln!(f, "comment:\t{:?}", self.comment)?;
@@ -51,50 +157,166 @@ impl fmt::Display for Torrent {
 impl Torrent {
     pub fn new(path: &PathBuf) -> Result<Torrent> {
         let contents = fs::read(path)?;
+        Torrent::from_bytes(&contents)
+    }
 
+    pub fn from_bytes(contents: &[u8]) -> Result<Torrent> {
         // deserialize the file to torrent struct
-        let torrent = de::from_bytes::<Torrent>(&contents)?;
+        let torrent = de::from_bytes::<Torrent>(contents)?;
 
-        let info_hash = torrent.generate_info_hash()?;
+        // the info hash has to be computed over the original bytes, re-serializing `Info`
+        // would drop any keys which aren't modelled by it
+        let info_span =
+            bencode::dict_value_span(contents, b"info")?.ok_or("Info dictionary is missing")?;
+        let info_hash = generate_info_hash(&contents[info_span.clone()]);
         Ok(Torrent {
             info_hash,
+            info_bytes: contents[info_span].to_vec(),
             ..torrent
         })
     }
 
-    fn generate_info_hash(&self) -> Result<Vec<u8>> {
-        let ser_info = ser::to_bytes(&self.info)?;
-        let x = digest::digest(&digest::SHA1_FOR_LEGACY_USE_ONLY, &ser_info);
-        let info_hash = x.as_ref();
-        let mut vec: Vec<u8> = Vec::new();
-        vec.extend_from_slice(info_hash);
-        Ok(vec)
+    /// Build a torrent from the info dictionary fetched for a magnet link.
+    /// Every tracker of the magnet link gets its own tier.
+    pub fn from_info_bytes(info_bytes: &[u8], trackers: &[String]) -> Result<Torrent> {
+        let info = de::from_bytes::<Info>(info_bytes)?;
+        Ok(Torrent {
+            info,
+            announce: None,
+            announce_list: Some(trackers.iter().map(|url| vec![url.clone()]).collect()),
+            nodes: None,
+            creation_date: None,
+            comment: None,
+            created_by: None,
+            info_hash: generate_info_hash(info_bytes),
+            info_bytes: info_bytes.to_vec(),
+        })
+    }
+
+    /// Tiers of tracker urls, `announce` is ignored if `announce-list` is present.
+    /// Trackers are shuffled within each tier, trackerless torrents have no tiers.
+    pub fn tracker_tiers(&self) -> Result<Vec<Vec<String>>> {
+        let mut tiers: Vec<Vec<String>> = match &self.announce_list {
+            Some(announce_list) => announce_list
+                .iter()
+                .filter(|tier| !tier.is_empty())
+                .cloned()
+                .collect(),
+            None => vec![],
+        };
+        if tiers.is_empty() {
+            if let Some(announce_url) = &self.announce {
+                tiers.push(vec![announce_url.clone()]);
+            }
+        }
+        for tier in tiers.iter_mut() {
+            utils::shuffle(tier)?;
+        }
+        Ok(tiers)
     }
 
-    pub fn generate_tracker_url(&self, peer_id: &Vec<u8>) -> Result<Url> {
-        // bittorrent port
-        const PORT: i32 = 6881;
-        let length = self.info.length.ok_or("File length is missing")?;
-        let info_hash = bytes_to_string_with_encoding(&self.info_hash)?;
-        let peer_id = bytes_to_string_with_encoding(peer_id)?;
+    /// `host:port` of the DHT nodes of the torrent, invalid entries are skipped
+    pub fn dht_nodes(&self) -> Vec<String> {
+        self.nodes
+            .iter()
+            .flatten()
+            .filter_map(|node| match node.as_slice() {
+                [Value::Bytes(host), Value::Int(port)] => {
+                    let host = String::from_utf8_lossy(host);
+                    // IPv6 addresses have to be bracketed
+                    if host.contains(':') {
+                        Some(format!("[{}]:{}", host, port))
+                    } else {
+                        Some(format!("{}:{}", host, port))
+                    }
+                }
+                _ => None,
+            })
+            .collect()
+    }
+}
 
-        // parse the url using reqwest
-        let announce_url = self.announce.as_ref().ok_or("Announce url missing")?;
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        let url = format!(
-            "{url}?info_hash={info_hash}&peer_id={peer_id}",
-            url = announce_url,
-            info_hash = info_hash,
-            peer_id = peer_id
+    #[test]
+    fn test_multi_file_info() -> Result<()> {
+        let info = b"d5:filesld6:lengthi3e4:pathl1:aeed6:lengthi5e4:pathl3:sub1:beee4:name4:root12:piece lengthi4e6:pieces40:aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaae";
+        let info = de::from_bytes::<Info>(info)?;
+        assert_eq!(info.total_length(), 8);
+        assert_eq!(info.total_pieces(), 2);
+        assert_eq!(
+            info.file_layout()?,
+            vec![
+                (PathBuf::from("root/a"), 3),
+                (PathBuf::from("root/sub/b"), 5)
+            ]
         );
+        assert_eq!(info.resume_path()?, PathBuf::from("root/.bitr_resume"));
+        Ok(())
+    }
 
-        let mut url = Url::parse(&url)?;
+    #[test]
+    fn test_info_hash_uses_raw_bytes() -> Result<()> {
+        // contains a `source` key which isn't part of `Info`
+        let mut contents = b"d8:announce32:https://tracker.example/announce4:infod6:lengthi10e4:name8:file.bin12:piece lengthi16384e6:pieces20:".to_vec();
+        contents.extend_from_slice(&[1; 20]);
+        contents.extend_from_slice(b"7:privatei1e6:source4:testee");
+
+        let torrent = Torrent::from_bytes(&contents)?;
+        assert_eq!(torrent.info.name, "file.bin");
+        assert!(torrent.info.is_private());
+        assert_eq!(
+            torrent.info_hash,
+            [
+                0x14, 0x6e, 0xdf, 0xf7, 0x18, 0x5c, 0x33, 0x56, 0xc0, 0x38, 0xfc, 0x40, 0x40, 0xaa,
+                0x71, 0x7b, 0x23, 0x96, 0x58, 0xb4
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_tracker_tiers() -> Result<()> {
+        let contents = b"d8:announce3:one13:announce-listll3:twoel5:three4:fouree4:infod6:lengthi1e4:name1:a12:piece lengthi1e6:pieces0:ee";
+        let torrent = Torrent::from_bytes(contents)?;
+        let mut tiers = torrent.tracker_tiers()?;
+        tiers[1].sort();
+        assert_eq!(tiers, vec![vec!["two"], vec!["four", "three"]]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_dht_nodes() -> Result<()> {
+        let contents = b"d4:infod6:lengthi1e4:name1:a12:piece lengthi1e6:pieces0:e5:nodesll9:127.0.0.1i6881eel3:::1i6882eel7:invalideee";
+        let torrent = Torrent::from_bytes(contents)?;
+        assert_eq!(torrent.dht_nodes(), vec!["127.0.0.1:6881", "[::1]:6882"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_from_info_bytes() -> Result<()> {
+        let info = b"d6:lengthi10e4:name8:file.bin12:piece lengthi16384e6:pieces0:e";
+        let torrent = Torrent::from_info_bytes(info, &["udp://a".to_string()])?;
+        assert_eq!(torrent.info.name, "file.bin");
+        assert_eq!(torrent.info_hash, generate_info_hash(info));
+        assert_eq!(
+            torrent.info.resume_path()?,
+            PathBuf::from(".file.bin.bitr_resume")
+        );
+        assert_eq!(torrent.tracker_tiers()?, vec![vec!["udp://a"]]);
+
+        let trackerless = Torrent::from_info_bytes(info, &[])?;
+        assert!(trackerless.tracker_tiers()?.is_empty());
+        Ok(())
+    }
 
-        url.query_pairs_mut()
-            .append_pair("port", &PORT.to_string())
-            .append_pair("uploaded", "0")
-            .append_pair("downloaded", "0")
-            .append_pair("left", &length.to_string());
-        Ok(url)
+    #[test]
+    fn test_path_traversal_rejected() -> Result<()> {
+        let info = b"d5:filesld6:lengthi3e4:pathl2:..1:aeee4:name4:root12:piece lengthi4e6:pieces20:aaaaaaaaaaaaaaaaaaaae";
+        let info = de::from_bytes::<Info>(info)?;
+        assert!(info.file_layout().is_err());
+        Ok(())
     }
 }
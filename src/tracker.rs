@@ -1,29 +1,61 @@
 use reqwest::Url;
+use serde::de::{self as serde_de, Deserialize, Deserializer, SeqAccess, Visitor};
 use serde_bencode::de;
 use serde_bytes::ByteBuf;
+use std::collections::HashMap;
 use std::fmt;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+use tokio::time;
 
+use crate::utils::bytes_to_string_with_encoding;
 use crate::Result;
 
-#[derive(Debug, Deserialize)]
+mod udp;
+
+use udp::UdpTracker;
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
 pub struct TrackerPeer {
     pub ip: String,
     pub port: u16,
+    /// not present in compact peer lists
+    #[serde(default)]
     #[serde(rename = "peer id")]
-    pub peer_id: ByteBuf,
+    pub peer_id: Option<ByteBuf>,
+}
+
+impl From<SocketAddr> for TrackerPeer {
+    fn from(addr: SocketAddr) -> Self {
+        Self {
+            ip: addr.ip().to_string(),
+            port: addr.port(),
+            peer_id: None,
+        }
+    }
 }
 #[derive(Debug, Deserialize)]
 pub struct TrackerResponse {
+    #[serde(default)]
+    #[serde(deserialize_with = "deserialize_peers")]
     pub peers: Vec<TrackerPeer>,
+    /// compact IPv6 peers, see BEP 7
+    #[serde(default)]
+    #[serde(deserialize_with = "deserialize_peers6")]
+    peers6: Vec<TrackerPeer>,
+    #[serde(default)]
     complete: i64,
+    #[serde(default)]
     incomplete: i64,
-    interval: i64,
+    /// seconds to wait before the next announce
+    pub interval: i64,
     #[serde(rename = "tracker id")]
     tracker_id: Option<String>,
+    /// the announce succeeded but the tracker wants to tell us something
     #[serde(rename = "warning message")]
-    warning_message: Option<String>,
+    pub warning_message: Option<String>,
     #[serde(rename = "min interval")]
-    min_interval: Option<i64>,
+    pub min_interval: Option<i64>,
 }
 
 impl fmt::Display for TrackerResponse {
@@ -35,27 +67,578 @@ impl fmt::Display for TrackerResponse {
         writeln!(f, "incomplete:\t\t{:?}", self.incomplete)?;
         writeln!(f, "interval:\t\t{:?}", self.interval)?;
         writeln!(f, "min interval:\t\t{:?}", self.min_interval)?;
-        writeln!(f, "tracker id:\t\t{:?}", self.tracker_id)
+        writeln!(f, "tracker id:\t\t{:?}", self.tracker_id)?;
+        writeln!(f, "warning message:\t{:?}", self.warning_message)
+    }
+}
+
+/// Peers can either be a list of dictionaries or a compact string, see BEP 23
+fn deserialize_peers<'de, D>(deserializer: D) -> std::result::Result<Vec<TrackerPeer>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    struct PeersVisitor;
+
+    impl<'de> Visitor<'de> for PeersVisitor {
+        type Value = Vec<TrackerPeer>;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "a list of peers or a compact peer string")
+        }
+
+        fn visit_bytes<E: serde_de::Error>(
+            self,
+            bytes: &[u8],
+        ) -> std::result::Result<Self::Value, E> {
+            Ok(decode_compact_peers(bytes, false))
+        }
+
+        fn visit_seq<A: SeqAccess<'de>>(
+            self,
+            mut seq: A,
+        ) -> std::result::Result<Self::Value, A::Error> {
+            let mut peers = vec![];
+            while let Some(peer) = seq.next_element::<TrackerPeer>()? {
+                peers.push(peer);
+            }
+            Ok(peers)
+        }
     }
+
+    deserializer.deserialize_any(PeersVisitor)
+}
+
+fn deserialize_peers6<'de, D>(deserializer: D) -> std::result::Result<Vec<TrackerPeer>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let bytes = ByteBuf::deserialize(deserializer)?;
+    Ok(decode_compact_peers(&bytes, true))
+}
+
+/// Lifecycle events reported to the tracker
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Event {
+    /// first announce for the download
+    Started,
+    /// the download finished
+    Completed,
+    /// the client is shutting down
+    Stopped,
 }
 
-pub fn send_tracker_request(url: Url) -> Result<TrackerResponse> {
-    match url.scheme() {
-        "https" => handle_https_scheme(url),
-        "udp" => todo!("Support for UDP trackers"),
-        _ => Err("URL scheme not supported")?,
+impl Event {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Event::Started => "started",
+            Event::Completed => "completed",
+            Event::Stopped => "stopped",
+        }
+    }
+    /// event codes used by UDP trackers, 0 means none
+    fn udp_code(&self) -> u32 {
+        match self {
+            Event::Completed => 1,
+            Event::Started => 2,
+            Event::Stopped => 3,
+        }
     }
 }
 
-fn handle_https_scheme(url: Url) -> Result<TrackerResponse> {
-    // send get request to tracker
-    let mut response = reqwest::blocking::get(url)?;
-    //println!("{:?}", response.text().unwrap());
-    let mut buf: Vec<u8> = vec![];
-    response.copy_to(&mut buf).unwrap();
+/// Parameters sent to the tracker on every announce
+#[derive(Debug, Clone)]
+pub struct AnnounceRequest {
+    pub info_hash: Vec<u8>,
+    pub peer_id: Vec<u8>,
+    pub port: u16,
+    pub uploaded: u64,
+    pub downloaded: u64,
+    pub left: u64,
+    pub event: Option<Event>,
+}
+
+impl AnnounceRequest {
+    pub fn generate_tracker_url(
+        &self,
+        announce_url: &str,
+        tracker_id: Option<&str>,
+    ) -> Result<Url> {
+        let info_hash = bytes_to_string_with_encoding(&self.info_hash)?;
+        let peer_id = bytes_to_string_with_encoding(&self.peer_id)?;
+
+        let url = format!(
+            "{url}?info_hash={info_hash}&peer_id={peer_id}",
+            url = announce_url,
+            info_hash = info_hash,
+            peer_id = peer_id
+        );
+
+        let mut url = Url::parse(&url)?;
 
-    // deserialize response to TrackerResponse
-    let tracker_res = de::from_bytes::<TrackerResponse>(&buf)?;
-    println!("{}", tracker_res);
-    Ok(tracker_res)
+        url.query_pairs_mut()
+            .append_pair("port", &self.port.to_string())
+            .append_pair("uploaded", &self.uploaded.to_string())
+            .append_pair("downloaded", &self.downloaded.to_string())
+            .append_pair("left", &self.left.to_string())
+            .append_pair("compact", "1");
+        if let Some(event) = self.event {
+            url.query_pairs_mut().append_pair("event", event.as_str());
+        }
+        if let Some(tracker_id) = tracker_id {
+            url.query_pairs_mut().append_pair("trackerid", tracker_id);
+        }
+        Ok(url)
+    }
+}
+
+/// Decode a compact peer list, 6 bytes per peer for IPv4 and 18 bytes for IPv6
+pub fn decode_compact_peers(bytes: &[u8], ipv6: bool) -> Vec<TrackerPeer> {
+    let ip_length = if ipv6 { 16 } else { 4 };
+    bytes
+        .chunks_exact(ip_length + 2)
+        .map(|chunk| {
+            let (ip, port) = chunk.split_at(ip_length);
+            let ip = if ipv6 {
+                let mut octets = [0; 16];
+                octets.copy_from_slice(ip);
+                Ipv6Addr::from(octets).to_string()
+            } else {
+                Ipv4Addr::new(ip[0], ip[1], ip[2], ip[3]).to_string()
+            };
+            TrackerPeer {
+                ip,
+                port: u16::from_be_bytes([port[0], port[1]]),
+                peer_id: None,
+            }
+        })
+        .collect()
+}
+
+/// Swarm statistics for a single torrent returned by a scrape
+#[derive(Debug, Default, Deserialize, PartialEq)]
+pub struct ScrapeStats {
+    /// number of seeders
+    #[serde(default)]
+    pub complete: i64,
+    /// number of times the download was completed
+    #[serde(default)]
+    pub downloaded: i64,
+    /// number of leechers
+    #[serde(default)]
+    pub incomplete: i64,
+}
+
+#[derive(Debug, Deserialize)]
+struct ScrapeResponse {
+    files: HashMap<ByteBuf, ScrapeStats>,
+}
+
+/// Derive the scrape url from the announce url, only possible if the last path segment
+/// starts with `announce`
+pub fn scrape_url(announce_url: &str) -> Option<Url> {
+    let mut url = Url::parse(announce_url).ok()?;
+    let path = url.path();
+    let slash = path.rfind('/')?;
+    let segment = &path[slash + 1..];
+    if !segment.starts_with("announce") {
+        return None;
+    }
+    let path = format!("{}scrape{}", &path[..=slash], &segment["announce".len()..]);
+    url.set_path(&path);
+    Some(url)
+}
+
+/// Errors which can occur while talking to a tracker
+#[derive(Debug)]
+pub enum TrackerError {
+    /// the tracker refused the request, contains the `failure reason`
+    Failure(String),
+    /// the tracker didn't respond in time
+    Timeout,
+    /// the response couldn't be decoded
+    InvalidResponse(String),
+    /// the tracker couldn't be reached
+    Connection(String),
+    /// the announce url is invalid or uses an unsupported scheme
+    UnsupportedUrl(String),
+}
+
+impl fmt::Display for TrackerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TrackerError::Failure(reason) => write!(f, "tracker returned a failure: {}", reason),
+            TrackerError::Timeout => write!(f, "tracker did not respond in time"),
+            TrackerError::InvalidResponse(e) => write!(f, "invalid tracker response: {}", e),
+            TrackerError::Connection(e) => write!(f, "could not reach tracker: {}", e),
+            TrackerError::UnsupportedUrl(url) => write!(f, "unsupported tracker url: {}", url),
+        }
+    }
+}
+
+impl std::error::Error for TrackerError {}
+
+impl From<reqwest::Error> for TrackerError {
+    fn from(e: reqwest::Error) -> Self {
+        if e.is_timeout() {
+            TrackerError::Timeout
+        } else {
+            TrackerError::Connection(e.to_string())
+        }
+    }
+}
+
+impl From<std::io::Error> for TrackerError {
+    fn from(e: std::io::Error) -> Self {
+        TrackerError::Connection(e.to_string())
+    }
+}
+
+impl From<serde_bencode::Error> for TrackerError {
+    fn from(e: serde_bencode::Error) -> Self {
+        TrackerError::InvalidResponse(e.to_string())
+    }
+}
+
+/// Only used to check for `failure reason` before decoding the full response,
+/// since failed responses don't contain any of the other keys
+#[derive(Debug, Deserialize)]
+struct FailureResponse {
+    #[serde(rename = "failure reason")]
+    failure_reason: Option<String>,
+}
+
+/// A tracker along with the state which has to be kept between announces
+#[derive(Debug)]
+pub struct Tracker {
+    pub announce_url: String,
+    /// sent back on every announce after the tracker gave us one
+    tracker_id: Option<String>,
+    /// created on the first announce so that the connection id can be reused
+    udp: Option<UdpTracker>,
+    http: reqwest::Client,
+    /// maximum time to wait for a tracker to respond, UDP retransmissions included
+    timeout: Duration,
+}
+
+impl Tracker {
+    pub fn new(announce_url: String) -> Self {
+        Self {
+            announce_url,
+            tracker_id: None,
+            udp: None,
+            http: reqwest::Client::new(),
+            timeout: Duration::from_secs(30),
+        }
+    }
+
+    pub async fn announce(
+        &mut self,
+        request: &AnnounceRequest,
+    ) -> std::result::Result<TrackerResponse, TrackerError> {
+        let url = Url::parse(&self.announce_url)
+            .map_err(|_| TrackerError::UnsupportedUrl(self.announce_url.clone()))?;
+        let tracker_res = match url.scheme() {
+            "http" | "https" => {
+                let url = request
+                    .generate_tracker_url(&self.announce_url, self.tracker_id.as_deref())
+                    .map_err(|e| TrackerError::UnsupportedUrl(e.to_string()))?;
+                self.handle_http_scheme(url).await?
+            }
+            "udp" => {
+                let timeout = self.timeout;
+                let udp = self.udp(&url).await?;
+                // a dead tracker would otherwise be retried for hours
+                time::timeout(timeout, udp.announce(request))
+                    .await
+                    .map_err(|_| TrackerError::Timeout)??
+            }
+            _ => return Err(TrackerError::UnsupportedUrl(self.announce_url.clone())),
+        };
+        println!("{}", tracker_res);
+        if let Some(tracker_id) = &tracker_res.tracker_id {
+            self.tracker_id = Some(tracker_id.clone());
+        }
+        Ok(tracker_res)
+    }
+
+    /// Get swarm statistics for all the `info_hashes` in as few requests as possible
+    pub async fn scrape(
+        &mut self,
+        info_hashes: &[Vec<u8>],
+    ) -> std::result::Result<HashMap<Vec<u8>, ScrapeStats>, TrackerError> {
+        let url = Url::parse(&self.announce_url)
+            .map_err(|_| TrackerError::UnsupportedUrl(self.announce_url.clone()))?;
+        match url.scheme() {
+            "http" | "https" => {
+                let url = scrape_url(&self.announce_url)
+                    .ok_or_else(|| TrackerError::UnsupportedUrl(self.announce_url.clone()))?;
+                let mut url = url.to_string();
+                for (i, info_hash) in info_hashes.iter().enumerate() {
+                    let separator = if i == 0 && !url.contains('?') {
+                        '?'
+                    } else {
+                        '&'
+                    };
+                    let info_hash = bytes_to_string_with_encoding(info_hash)
+                        .map_err(|e| TrackerError::UnsupportedUrl(e.to_string()))?;
+                    url = format!("{}{}info_hash={}", url, separator, info_hash);
+                }
+                let response = self.http.get(&url).timeout(self.timeout).send().await?;
+                let buf = response.bytes().await?;
+
+                if let Some(reason) = de::from_bytes::<FailureResponse>(&buf)?.failure_reason {
+                    return Err(TrackerError::Failure(reason));
+                }
+                let scrape_res = de::from_bytes::<ScrapeResponse>(&buf)?;
+                Ok(scrape_res
+                    .files
+                    .into_iter()
+                    .map(|(info_hash, stats)| (info_hash.into_vec(), stats))
+                    .collect())
+            }
+            "udp" => {
+                let timeout = self.timeout;
+                let udp = self.udp(&url).await?;
+                let stats = time::timeout(timeout, udp.scrape(info_hashes))
+                    .await
+                    .map_err(|_| TrackerError::Timeout)??;
+                Ok(info_hashes.iter().cloned().zip(stats).collect())
+            }
+            _ => Err(TrackerError::UnsupportedUrl(self.announce_url.clone())),
+        }
+    }
+
+    /// The UDP tracker, created on first use so that the connection id can be reused
+    async fn udp(&mut self, url: &Url) -> std::result::Result<&mut UdpTracker, TrackerError> {
+        if self.udp.is_none() {
+            let udp = time::timeout(self.timeout, UdpTracker::new(url))
+                .await
+                .map_err(|_| TrackerError::Timeout)??;
+            self.udp = Some(udp);
+        }
+        self.udp
+            .as_mut()
+            .ok_or_else(|| TrackerError::Connection("UDP tracker is missing".into()))
+    }
+
+    async fn handle_http_scheme(
+        &self,
+        url: Url,
+    ) -> std::result::Result<TrackerResponse, TrackerError> {
+        // send get request to tracker
+        let response = self.http.get(url).timeout(self.timeout).send().await?;
+        let buf = response.bytes().await?;
+
+        if let Some(reason) = de::from_bytes::<FailureResponse>(&buf)?.failure_reason {
+            return Err(TrackerError::Failure(reason));
+        }
+        // deserialize response to TrackerResponse
+        let mut tracker_res = de::from_bytes::<TrackerResponse>(&buf)?;
+        let mut peers6 = std::mem::take(&mut tracker_res.peers6);
+        tracker_res.peers.append(&mut peers6);
+        Ok(tracker_res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dictionary_peers() -> Result<()> {
+        let res = b"d8:completei1e10:incompletei2e8:intervali1800e5:peersld2:ip9:127.0.0.17:peer id20:aaaaaaaaaaaaaaaaaaaa4:porti6881eed2:ip3:::14:porti80eeee";
+        let res = de::from_bytes::<TrackerResponse>(res)?;
+        assert_eq!(res.peers.len(), 2);
+        assert_eq!(res.peers[0].ip, "127.0.0.1");
+        assert_eq!(res.peers[0].port, 6881);
+        assert_eq!(res.peers[0].peer_id, Some(ByteBuf::from(vec![b'a'; 20])));
+        assert_eq!(res.peers[1].ip, "::1");
+        assert_eq!(res.peers[1].peer_id, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_compact_peers() -> Result<()> {
+        let mut res = b"d8:completei1e10:incompletei2e8:intervali1800e5:peers12:".to_vec();
+        res.extend_from_slice(&[10, 0, 0, 1, 0x1a, 0xe1, 192, 168, 1, 2, 0, 80]);
+        res.extend_from_slice(b"6:peers618:");
+        res.extend_from_slice(&[
+            0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 80,
+        ]);
+        res.extend_from_slice(b"e");
+
+        let res = de::from_bytes::<TrackerResponse>(&res)?;
+        assert_eq!(res.peers.len(), 2);
+        assert_eq!(res.peers[0].ip, "10.0.0.1");
+        assert_eq!(res.peers[0].port, 6881);
+        assert_eq!(res.peers[1].ip, "192.168.1.2");
+        assert_eq!(res.peers[1].port, 80);
+        assert_eq!(res.peers6.len(), 1);
+        assert_eq!(res.peers6[0].ip, "2001:db8::1");
+        assert_eq!(res.peers6[0].port, 80);
+        Ok(())
+    }
+
+    #[test]
+    fn test_compact_url() -> Result<()> {
+        let request = AnnounceRequest {
+            info_hash: vec![0xab; 20],
+            peer_id: vec![1; 20],
+            port: 6881,
+            uploaded: 0,
+            downloaded: 0,
+            left: 10,
+            event: Some(Event::Started),
+        };
+        let url = request.generate_tracker_url("http://tracker.example/announce", Some("xyz"))?;
+        assert!(url
+            .as_str()
+            .starts_with("http://tracker.example/announce?info_hash=%AB%AB"));
+        assert!(url
+            .as_str()
+            .ends_with("&left=10&compact=1&event=started&trackerid=xyz"));
+        Ok(())
+    }
+
+    /// Serve a single HTTP request with `body` on a local port and return the announce url
+    async fn fake_http_tracker(body: Option<&'static [u8]>) -> Result<String> {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let url = format!("http://{}/announce", listener.local_addr()?);
+        tokio::spawn(async move {
+            use tokio::io::{AsyncReadExt, AsyncWriteExt};
+            let (mut socket, _) = listener.accept().await?;
+            // read the request headers
+            let mut request = vec![];
+            let mut buf = [0; 1024];
+            while !request.ends_with(b"\r\n\r\n") {
+                let length = socket.read(&mut buf).await?;
+                if length == 0 {
+                    break;
+                }
+                request.extend_from_slice(&buf[..length]);
+            }
+            match body {
+                Some(body) => {
+                    let header =
+                        format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", body.len());
+                    socket.write_all(header.as_bytes()).await?;
+                    socket.write_all(body).await?;
+                }
+                // never respond
+                None => tokio::time::sleep(Duration::from_secs(5)).await,
+            }
+            Ok::<_, std::io::Error>(())
+        });
+        Ok(url)
+    }
+
+    /// Announce of a torrent with ten bytes left, shared with the UDP tracker tests
+    pub(super) fn request() -> AnnounceRequest {
+        AnnounceRequest {
+            info_hash: vec![1; 20],
+            peer_id: vec![2; 20],
+            port: 6881,
+            uploaded: 0,
+            downloaded: 0,
+            left: 10,
+            event: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_http_announce() -> Result<()> {
+        let url = fake_http_tracker(Some(
+            b"d8:intervali900e5:peers6:\x7f\x00\x00\x01\x1a\xe115:warning message4:slow10:tracker id3:abce",
+        ))
+        .await?;
+        let mut tracker = Tracker::new(url);
+        let res = tracker.announce(&request()).await?;
+        assert_eq!(res.interval, 900);
+        assert_eq!(res.peers[0].ip, "127.0.0.1");
+        assert_eq!(res.warning_message.as_deref(), Some("slow"));
+        assert_eq!(tracker.tracker_id.as_deref(), Some("abc"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_http_failure_reason() -> Result<()> {
+        let url = fake_http_tracker(Some(b"d14:failure reason17:unknown info hashe")).await?;
+        match Tracker::new(url).announce(&request()).await {
+            Err(TrackerError::Failure(reason)) => assert_eq!(reason, "unknown info hash"),
+            res => panic!("unexpected result {:?}", res),
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_http_malformed_response() -> Result<()> {
+        let url = fake_http_tracker(Some(b"d8:intervali900e5:peers")).await?;
+        match Tracker::new(url).announce(&request()).await {
+            Err(TrackerError::InvalidResponse(_)) => {}
+            res => panic!("unexpected result {:?}", res),
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_http_timeout() -> Result<()> {
+        let url = fake_http_tracker(None).await?;
+        let mut tracker = Tracker::new(url);
+        tracker.timeout = Duration::from_millis(100);
+        match tracker.announce(&request()).await {
+            Err(TrackerError::Timeout) => {}
+            res => panic!("unexpected result {:?}", res),
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_udp_timeout() -> Result<()> {
+        // never responds, so the tracker keeps retransmitting
+        let server = tokio::net::UdpSocket::bind("127.0.0.1:0").await?;
+        let mut tracker = Tracker::new(format!("udp://{}/announce", server.local_addr()?));
+        tracker.timeout = Duration::from_millis(100);
+        match tracker.announce(&request()).await {
+            Err(TrackerError::Timeout) => {}
+            res => panic!("unexpected result {:?}", res),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_scrape_url() {
+        let scrape = |url| scrape_url(url).map(|url| url.to_string());
+        assert_eq!(
+            scrape("http://example.com/announce"),
+            Some("http://example.com/scrape".to_string())
+        );
+        assert_eq!(
+            scrape("http://example.com/x/announce.php?passkey=1"),
+            Some("http://example.com/x/scrape.php?passkey=1".to_string())
+        );
+        assert_eq!(
+            scrape("udp://example.com:80/announce"),
+            Some("udp://example.com:80/scrape".to_string())
+        );
+        assert_eq!(scrape("http://example.com/a"), None);
+        assert_eq!(scrape("http://example.com/announce/x"), None);
+    }
+
+    #[tokio::test]
+    async fn test_http_scrape() -> Result<()> {
+        let url = fake_http_tracker(Some(
+            b"d5:filesd20:aaaaaaaaaaaaaaaaaaaad8:completei5e10:downloadedi50e10:incompletei10eeee",
+        ))
+        .await?;
+        let stats = Tracker::new(url).scrape(&[vec![b'a'; 20]]).await?;
+        assert_eq!(
+            stats.get(&vec![b'a'; 20]),
+            Some(&ScrapeStats {
+                complete: 5,
+                downloaded: 50,
+                incomplete: 10
+            })
+        );
+        Ok(())
+    }
 }
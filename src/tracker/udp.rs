@@ -0,0 +1,358 @@
+use reqwest::Url;
+use std::time::{Duration, Instant};
+use tokio::net::{lookup_host, UdpSocket};
+
+use super::{decode_compact_peers, AnnounceRequest, ScrapeStats, TrackerError, TrackerResponse};
+use crate::utils;
+
+type Result<T> = std::result::Result<T, TrackerError>;
+
+/// magic constant identifying the protocol in connect requests
+const PROTOCOL_ID: u64 = 0x417_2710_1980;
+/// a connection id can be used for one minute after it was received
+const CONNECTION_ID_LIFETIME: Duration = Duration::from_secs(60);
+/// n in the 15 * 2 ^ n retransmission schedule goes up to 8
+const MAX_RETRIES: u32 = 8;
+
+const ACTION_CONNECT: u32 = 0;
+const ACTION_ANNOUNCE: u32 = 1;
+const ACTION_SCRAPE: u32 = 2;
+const ACTION_ERROR: u32 = 3;
+/// maximum number of info hashes in a single scrape request
+const MAX_SCRAPE_HASHES: usize = 74;
+
+/// Client for a single UDP tracker, see BEP 15
+#[derive(Debug)]
+pub struct UdpTracker {
+    socket: UdpSocket,
+    /// connection id along with the time it was received
+    connection: Option<(u64, Instant)>,
+    /// timeout before the first retransmission, doubled after every retry
+    base_timeout: Duration,
+}
+
+impl UdpTracker {
+    pub async fn new(url: &Url) -> Result<Self> {
+        let invalid_url = || TrackerError::UnsupportedUrl(url.to_string());
+        let host = url.host_str().ok_or_else(invalid_url)?;
+        let port = url.port().ok_or_else(invalid_url)?;
+        let addr = lookup_host((host, port))
+            .await?
+            .next()
+            .ok_or_else(|| TrackerError::Connection("Could not resolve tracker address".into()))?;
+
+        let bind_addr = if addr.is_ipv4() {
+            "0.0.0.0:0"
+        } else {
+            "[::]:0"
+        };
+        let socket = UdpSocket::bind(bind_addr).await?;
+        socket.connect(addr).await?;
+        Ok(Self {
+            socket,
+            connection: None,
+            base_timeout: Duration::from_secs(15),
+        })
+    }
+
+    /// announce: <connection_id><action=1><transaction_id><info_hash><peer_id><downloaded><left>
+    /// <uploaded><event><ip><key><num_want><port>
+    pub async fn announce(&mut self, request: &AnnounceRequest) -> Result<TrackerResponse> {
+        let key = random_u32()?;
+        let mut body = Vec::with_capacity(82);
+        body.extend_from_slice(&request.info_hash);
+        body.extend_from_slice(&request.peer_id);
+        body.extend_from_slice(&request.downloaded.to_be_bytes());
+        body.extend_from_slice(&request.left.to_be_bytes());
+        body.extend_from_slice(&request.uploaded.to_be_bytes());
+        let event = request.event.map_or(0, |event| event.udp_code());
+        body.extend_from_slice(&event.to_be_bytes());
+        // ip address: default
+        body.extend_from_slice(&0u32.to_be_bytes());
+        body.extend_from_slice(&key.to_be_bytes());
+        // num_want: default
+        body.extend_from_slice(&(-1i32).to_be_bytes());
+        body.extend_from_slice(&request.port.to_be_bytes());
+
+        // response: <action=1><transaction_id><interval><leechers><seeders><peers>
+        let response = self.send_request(ACTION_ANNOUNCE, &body).await?;
+        if response.len() < 12 {
+            return Err(TrackerError::InvalidResponse(
+                "Announce response is too short".into(),
+            ));
+        }
+        let ipv6 = self.socket.local_addr()?.is_ipv6();
+        Ok(TrackerResponse {
+            peers: decode_compact_peers(&response[12..], ipv6),
+            peers6: vec![],
+            interval: read_u32(&response[0..4]) as i64,
+            incomplete: read_u32(&response[4..8]) as i64,
+            complete: read_u32(&response[8..12]) as i64,
+            tracker_id: None,
+            warning_message: None,
+            min_interval: None,
+        })
+    }
+
+    /// scrape: <connection_id><action=2><transaction_id><info_hash>...
+    pub async fn scrape(&mut self, info_hashes: &[Vec<u8>]) -> Result<Vec<ScrapeStats>> {
+        let mut stats = Vec::with_capacity(info_hashes.len());
+        for chunk in info_hashes.chunks(MAX_SCRAPE_HASHES) {
+            let body = chunk.concat();
+            // response: <action=2><transaction_id>(<seeders><completed><leechers>)...
+            let response = self.send_request(ACTION_SCRAPE, &body).await?;
+            if response.len() < chunk.len() * 12 {
+                return Err(TrackerError::InvalidResponse(
+                    "Scrape response is too short".into(),
+                ));
+            }
+            stats.extend(
+                response
+                    .chunks_exact(12)
+                    .take(chunk.len())
+                    .map(|entry| ScrapeStats {
+                        complete: read_u32(&entry[0..4]) as i64,
+                        downloaded: read_u32(&entry[4..8]) as i64,
+                        incomplete: read_u32(&entry[8..12]) as i64,
+                    }),
+            );
+        }
+        Ok(stats)
+    }
+
+    /// Send a request for `action` and return the response without the action and transaction id.
+    /// Takes care of getting a connection id and of retransmitting requests which time out.
+    async fn send_request(&mut self, action: u32, body: &[u8]) -> Result<Vec<u8>> {
+        let mut retry = 0;
+        loop {
+            let connection_id = match self.connection {
+                Some((id, received)) if received.elapsed() < CONNECTION_ID_LIFETIME => id,
+                _ => {
+                    // connect: <protocol_id><action=0><transaction_id>
+                    match self
+                        .transact(PROTOCOL_ID, ACTION_CONNECT, &[], retry)
+                        .await?
+                    {
+                        // response: <action=0><transaction_id><connection_id>
+                        Some(response) if response.len() >= 8 => {
+                            let mut id = [0; 8];
+                            id.copy_from_slice(&response[0..8]);
+                            let id = u64::from_be_bytes(id);
+                            self.connection = Some((id, Instant::now()));
+                            continue;
+                        }
+                        Some(_) => {
+                            return Err(TrackerError::InvalidResponse(
+                                "Connect response is too short".into(),
+                            ))
+                        }
+                        None => {
+                            retry += 1;
+                            continue;
+                        }
+                    }
+                }
+            };
+            match self.transact(connection_id, action, body, retry).await? {
+                Some(response) => return Ok(response),
+                None => retry += 1,
+            }
+        }
+    }
+
+    /// Send a single packet and wait for the matching response.
+    /// Returns `None` if the tracker didn't respond within the timeout for this retry.
+    async fn transact(
+        &self,
+        connection_id: u64,
+        action: u32,
+        body: &[u8],
+        retry: u32,
+    ) -> Result<Option<Vec<u8>>> {
+        if retry > MAX_RETRIES {
+            return Err(TrackerError::Timeout);
+        }
+        let transaction_id = random_u32()?;
+        let mut packet = Vec::with_capacity(16 + body.len());
+        packet.extend_from_slice(&connection_id.to_be_bytes());
+        packet.extend_from_slice(&action.to_be_bytes());
+        packet.extend_from_slice(&transaction_id.to_be_bytes());
+        packet.extend_from_slice(body);
+        self.socket.send(&packet).await?;
+
+        let deadline = tokio::time::Instant::now() + self.base_timeout * 2u32.pow(retry);
+        let mut buf = vec![0; 2048];
+        loop {
+            let length = match tokio::time::timeout_at(deadline, self.socket.recv(&mut buf)).await {
+                Ok(length) => length?,
+                Err(_) => return Ok(None),
+            };
+            if length < 8 {
+                continue;
+            }
+            // ignore responses to requests which we've given up on
+            if read_u32(&buf[4..8]) != transaction_id {
+                continue;
+            }
+            let response_action = read_u32(&buf[0..4]);
+            let payload = buf[8..length].to_vec();
+            if response_action == ACTION_ERROR {
+                // error: <action=3><transaction_id><message>
+                let message = String::from_utf8_lossy(&payload);
+                return Err(TrackerError::Failure(message.into_owned()));
+            }
+            if response_action != action {
+                return Err(TrackerError::InvalidResponse(
+                    "Tracker responded with an unexpected action".into(),
+                ));
+            }
+            return Ok(Some(payload));
+        }
+    }
+}
+
+fn random_u32() -> Result<u32> {
+    utils::random_u32().map_err(|e| TrackerError::Connection(e.to_string()))
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tracker::{self, Event};
+    use crate::Result;
+
+    /// Bind a local socket acting as the tracker and a client pointing at it
+    async fn setup() -> Result<(UdpSocket, UdpTracker)> {
+        let server = UdpSocket::bind("127.0.0.1:0").await?;
+        let url = Url::parse(&format!("udp://{}/announce", server.local_addr()?))?;
+        let mut tracker = UdpTracker::new(&url).await?;
+        tracker.base_timeout = Duration::from_millis(50);
+        Ok((server, tracker))
+    }
+
+    fn request() -> AnnounceRequest {
+        AnnounceRequest {
+            event: Some(Event::Started),
+            ..tracker::tests::request()
+        }
+    }
+
+    async fn respond_to_connect(server: &UdpSocket, connection_id: u64) -> Result<()> {
+        let mut buf = [0; 2048];
+        let (length, addr) = server.recv_from(&mut buf).await?;
+        assert_eq!(length, 16);
+        assert_eq!(&buf[0..8], &PROTOCOL_ID.to_be_bytes());
+        assert_eq!(read_u32(&buf[8..12]), ACTION_CONNECT);
+
+        let mut response = vec![0, 0, 0, 0];
+        response.extend_from_slice(&buf[12..16]);
+        response.extend_from_slice(&connection_id.to_be_bytes());
+        server.send_to(&response, addr).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_announce() -> Result<()> {
+        let (server, mut tracker) = setup().await?;
+        let server_task = tokio::spawn(async move {
+            // drop the first connect request to exercise retransmission
+            let mut buf = [0; 2048];
+            server.recv_from(&mut buf).await?;
+            respond_to_connect(&server, 42).await?;
+
+            for _ in 0..2 {
+                let (length, addr) = server.recv_from(&mut buf).await?;
+                assert_eq!(length, 98);
+                assert_eq!(&buf[0..8], &42u64.to_be_bytes());
+                assert_eq!(read_u32(&buf[8..12]), ACTION_ANNOUNCE);
+                assert_eq!(&buf[16..36], &[1; 20]);
+                assert_eq!(&buf[36..56], &[2; 20]);
+                assert_eq!(read_u32(&buf[80..84]), 2);
+
+                let mut response = vec![0, 0, 0, 1];
+                response.extend_from_slice(&buf[12..16]);
+                response.extend_from_slice(&[0, 0, 7, 8, 0, 0, 0, 3, 0, 0, 0, 5]);
+                response.extend_from_slice(&[127, 0, 0, 1, 0x1a, 0xe1]);
+                server.send_to(&response, addr).await?;
+            }
+            Ok::<_, Box<dyn std::error::Error + Send + Sync>>(())
+        });
+
+        let response = tracker.announce(&request()).await?;
+        assert_eq!(response.interval, 1800);
+        assert_eq!(response.incomplete, 3);
+        assert_eq!(response.complete, 5);
+        assert_eq!(response.peers.len(), 1);
+        assert_eq!(response.peers[0].ip, "127.0.0.1");
+        assert_eq!(response.peers[0].port, 6881);
+
+        // the second announce reuses the cached connection id
+        tracker.announce(&request()).await?;
+        server_task.await??;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_error_response() -> Result<()> {
+        let (server, mut tracker) = setup().await?;
+        let server_task = tokio::spawn(async move {
+            respond_to_connect(&server, 7).await?;
+            let mut buf = [0; 2048];
+            let (_, addr) = server.recv_from(&mut buf).await?;
+            let mut response = vec![0, 0, 0, 3];
+            response.extend_from_slice(&buf[12..16]);
+            response.extend_from_slice(b"torrent not registered");
+            server.send_to(&response, addr).await?;
+            Ok::<_, Box<dyn std::error::Error + Send + Sync>>(())
+        });
+
+        match tracker.announce(&request()).await {
+            Err(TrackerError::Failure(message)) => assert_eq!(message, "torrent not registered"),
+            res => panic!("unexpected result {:?}", res),
+        }
+        server_task.await??;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_scrape() -> Result<()> {
+        let (server, mut tracker) = setup().await?;
+        let server_task = tokio::spawn(async move {
+            respond_to_connect(&server, 9).await?;
+            let mut buf = [0; 2048];
+            let (length, addr) = server.recv_from(&mut buf).await?;
+            assert_eq!(length, 16 + 40);
+            assert_eq!(read_u32(&buf[8..12]), ACTION_SCRAPE);
+
+            let mut response = vec![0, 0, 0, 2];
+            response.extend_from_slice(&buf[12..16]);
+            response.extend_from_slice(&[0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0, 3]);
+            response.extend_from_slice(&[0, 0, 0, 4, 0, 0, 0, 5, 0, 0, 0, 6]);
+            server.send_to(&response, addr).await?;
+            Ok::<_, Box<dyn std::error::Error + Send + Sync>>(())
+        });
+
+        let stats = tracker.scrape(&[vec![1; 20], vec![2; 20]]).await?;
+        assert_eq!(
+            stats,
+            vec![
+                ScrapeStats {
+                    complete: 1,
+                    downloaded: 2,
+                    incomplete: 3
+                },
+                ScrapeStats {
+                    complete: 4,
+                    downloaded: 5,
+                    incomplete: 6
+                }
+            ]
+        );
+        server_task.await??;
+        Ok(())
+    }
+}
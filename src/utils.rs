@@ -18,6 +18,22 @@ pub fn generate_peer_id() -> Result<Vec<u8>> {
     Ok(bytes)
 }
 
+pub fn random_u32() -> Result<u32> {
+    let generator = SystemRandom::new();
+    let mut bytes = [0; 4];
+    generator.fill(&mut bytes)?;
+    Ok(u32::from_be_bytes(bytes))
+}
+
+/// Fisher-Yates shuffle
+pub fn shuffle<T>(items: &mut [T]) -> Result<()> {
+    for i in (1..items.len()).rev() {
+        let j = random_u32()? as usize % (i + 1);
+        items.swap(i, j);
+    }
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;